@@ -0,0 +1,19 @@
+// The Markdown parser must return for every input without panicking. Run it from the
+// repository root with `cargo +nightly fuzz run markdown`
+#![no_main]
+
+use flux::parsers::md_parser::{self, MDOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(md) = std::str::from_utf8(data) {
+        let mut options = MDOptions {
+            toc: true,
+            sourcepos: true,
+            ..MDOptions::default()
+        };
+        md_parser::md_string_to_html_with_warnings(md, &options);
+        options.safe = true;
+        md_parser::md_string_to_html_with_warnings(md, &options);
+    }
+});
@@ -0,0 +1,464 @@
+// Batch conversion of a directory tree or glob pattern into an output directory. The relative
+// layout is mirrored, convertible files get the target format's extension, and everything else
+// is copied across unchanged so links to images and other assets keep working
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::convert::{ConvertOptions, FileType, Registry};
+use crate::diag;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub converted: usize,
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} converted, {} copied, {} skipped, {} failed",
+            self.converted, self.copied, self.skipped, self.failed
+        )
+    }
+}
+
+/// Whether `input` names more than one file, so it needs an output directory
+pub fn is_batch_input(input: &str) -> bool {
+    is_glob(input) || Path::new(input).is_dir()
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+/// The files `input` selects, each paired with its path relative to the root of the selection.
+/// Hidden files and directories, and symlinks back into a directory being walked, are left out
+/// and counted separately
+pub fn collect_files(input: &str) -> io::Result<(Vec<(PathBuf, PathBuf)>, usize)> {
+    let (root, pattern) = if is_glob(input) {
+        split_glob(input)
+    } else if Path::new(input).is_dir() {
+        (PathBuf::from(input), None)
+    } else {
+        // A single file is a batch of one, placed directly in the output directory
+        let path = PathBuf::from(input);
+        let name = PathBuf::from(path.file_name().unwrap_or_default());
+        return Ok((vec![(path, name)], 0));
+    };
+    let mut files = Vec::new();
+    let mut skipped = 0;
+    let mut ancestors = Vec::new();
+    walk(&root, &root, &mut files, &mut skipped, &mut ancestors)?;
+    if let Some(pattern) = pattern {
+        files.retain(|(_, relative)| glob_match(&pattern, relative));
+    }
+    Ok((files, skipped))
+}
+
+// The directories before the first wildcard are the root that output paths are relative to
+fn split_glob(input: &str) -> (PathBuf, Option<Vec<String>>) {
+    let segments: Vec<&str> = input.split('/').collect();
+    let literal = segments.iter().take_while(|s| !is_glob(s)).count();
+    let root = match segments[..literal].join("/") {
+        root if root.is_empty() && input.starts_with('/') => "/".to_string(),
+        root if root.is_empty() => ".".to_string(),
+        root => root,
+    };
+    let pattern = segments[literal..].iter().map(|s| s.to_string()).collect();
+    (PathBuf::from(root), Some(pattern))
+}
+
+// `ancestors` holds the canonical paths of the directories being walked, so a symlink back to
+// one of them is skipped instead of followed forever
+fn walk(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+    skipped: &mut usize,
+    ancestors: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    ancestors.push(dir.canonicalize()?);
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            *skipped += 1;
+            continue;
+        }
+        // Symlinked directories are followed, and a broken symlink is left to fail as a file
+        let file_type = entry.file_type()?;
+        let is_dir = if file_type.is_symlink() {
+            fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir())
+        } else {
+            file_type.is_dir()
+        };
+        if is_dir {
+            if path
+                .canonicalize()
+                .is_ok_and(|target| ancestors.contains(&target))
+            {
+                *skipped += 1;
+                continue;
+            }
+            walk(root, &path, files, skipped, ancestors)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            files.push((path, relative));
+        }
+    }
+    ancestors.pop();
+    Ok(())
+}
+
+/// Whether a relative path matches a glob like `blog/**/*.md`
+pub fn glob_matches(glob: &str, path: &Path) -> bool {
+    let pattern: Vec<String> = glob.split('/').map(String::from).collect();
+    glob_match(&pattern, path)
+}
+
+// Match a relative path against glob segments, where `**` spans any number of directories
+fn glob_match(pattern: &[String], path: &Path) -> bool {
+    let segments: Vec<String> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    match_segments(pattern, &segments)
+}
+
+fn match_segments(pattern: &[String], segments: &[String]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=segments.len()).any(|skip| match_segments(rest, &segments[skip..]))
+        }
+        Some((first, rest)) => match segments.split_first() {
+            Some((segment, remaining)) => {
+                wildcard_match(first.as_bytes(), segment.as_bytes())
+                    && match_segments(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+// Only the latest `*` ever needs to match more text, so this backtracks to it alone and runs in
+// linear time for typical patterns
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The latest `*` and how far into the text it reaches so far
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Where a converted file goes: the same relative path under `out_dir`, with the target
+/// format's extension
+pub fn output_path(out_dir: &Path, relative: &Path, to: FileType) -> PathBuf {
+    out_dir.join(relative).with_extension(to.extension())
+}
+
+/// Convert one file and write the result, returning any warnings
+pub fn convert_file(
+    registry: &Registry,
+    input: &Path,
+    output: &Path,
+    from: FileType,
+    to: FileType,
+    options: &ConvertOptions,
+) -> Result<Vec<String>, String> {
+    let options = &options.for_file(input);
+    let contents = fs::read_to_string(input).map_err(|e| e.to_string())?;
+    let mut warnings = Vec::new();
+    let converted = registry.convert(&contents, from, to, options, &mut warnings)?;
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(output, converted).map_err(|e| e.to_string())?;
+    Ok(warnings)
+}
+
+/// What happened to one file of a batch
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Converted(PathBuf),
+    Copied(PathBuf),
+    Failed(String),
+}
+
+// The format `path` converts from, if it can be converted to `to` at all
+fn convertible_from(registry: &Registry, path: &Path, to: FileType) -> Option<FileType> {
+    let from = FileType::from_file_name(&path.to_string_lossy()).ok();
+    from.filter(|&from| registry.path(from, to).is_some())
+}
+
+/// Where `process_file` puts `path`: converted under the target format's extension, or copied
+/// under its own name
+pub fn destination(
+    registry: &Registry,
+    path: &Path,
+    relative: &Path,
+    out_dir: &Path,
+    to: FileType,
+) -> PathBuf {
+    match convertible_from(registry, path, to) {
+        Some(_) => output_path(out_dir, relative, to),
+        None => out_dir.join(relative),
+    }
+}
+
+/// Convert `path` into `out_dir` if a conversion to `to` exists, or copy it there unchanged
+pub fn process_file(
+    registry: &Registry,
+    path: &Path,
+    relative: &Path,
+    out_dir: &Path,
+    to: FileType,
+    options: &ConvertOptions,
+    warnings: &mut Vec<String>,
+) -> Outcome {
+    match convertible_from(registry, path, to) {
+        Some(from) => {
+            let output = output_path(out_dir, relative, to);
+            match convert_file(registry, path, &output, from, to, options) {
+                Ok(file_warnings) => {
+                    let checked = options.check_warnings(&file_warnings);
+                    warnings.extend(file_warnings);
+                    match checked {
+                        Ok(()) => Outcome::Converted(output),
+                        Err(e) => Outcome::Failed(e),
+                    }
+                }
+                Err(e) => Outcome::Failed(e),
+            }
+        }
+        None => {
+            let output = out_dir.join(relative);
+            let copied = output
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::copy(path, &output));
+            match copied {
+                Ok(_) => Outcome::Copied(output),
+                Err(e) => Outcome::Failed(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Whether `path` lies inside `out_dir`, so it is earlier output rather than input
+pub fn in_out_dir(path: &Path, out_dir: &Path) -> bool {
+    match (out_dir.canonicalize(), path.canonicalize()) {
+        (Ok(out_dir), Ok(path)) => path.starts_with(out_dir),
+        _ => false,
+    }
+}
+
+/// Convert every file `input` selects into `out_dir`, reporting each file on stderr as it is
+/// handled
+pub fn run(
+    registry: &Registry,
+    input: &str,
+    out_dir: &Path,
+    to: FileType,
+    options: &ConvertOptions,
+) -> io::Result<Summary> {
+    let (files, skipped) = collect_files(input)?;
+    let mut summary = Summary {
+        skipped,
+        ..Summary::default()
+    };
+    // Which input each output came from, so two inputs never write the same file
+    let mut destinations: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for (path, relative) in files {
+        // Never feed earlier output back in when the output directory sits inside the input
+        if in_out_dir(&path, out_dir) {
+            summary.skipped += 1;
+            continue;
+        }
+        let output = destination(registry, &path, &relative, out_dir, to);
+        if let Some(earlier) = destinations.get(&output) {
+            diag::error!(
+                "{}: {} is already written from {}",
+                path.display(),
+                output.display(),
+                earlier.display()
+            );
+            summary.failed += 1;
+            continue;
+        }
+        destinations.insert(output, path.clone());
+        let mut warnings = Vec::new();
+        let outcome = process_file(
+            registry,
+            &path,
+            &relative,
+            out_dir,
+            to,
+            options,
+            &mut warnings,
+        );
+        for warning in warnings {
+            diag::warning!("{}: {}", path.display(), warning);
+        }
+        match outcome {
+            Outcome::Converted(output) => {
+                diag::verbose!("{} -> {}", path.display(), output.display());
+                summary.converted += 1;
+            }
+            Outcome::Copied(output) => {
+                diag::verbose!("{} copied to {}", path.display(), output.display());
+                summary.copied += 1;
+            }
+            Outcome::Failed(e) => {
+                diag::error!("{}: {}", path.display(), e);
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_by_segment() {
+        let pattern = |p: &str| p.split('/').map(String::from).collect::<Vec<String>>();
+        assert!(glob_match(&pattern("**/*.md"), Path::new("a/b/c.md")));
+        assert!(glob_match(&pattern("**/*.md"), Path::new("c.md")));
+        assert!(glob_match(&pattern("*/?.md"), Path::new("a/c.md")));
+        assert!(!glob_match(&pattern("*.md"), Path::new("a/c.md")));
+        assert!(!glob_match(&pattern("*.md"), Path::new("c.mdx")));
+        assert!(wildcard_match(b"*a*b?", b"xxaxxbc"));
+        assert!(!wildcard_match(b"a*", b"ba"));
+        // Backtracking only to the latest star keeps this from taking exponential time
+        let name = "a".repeat(10_000);
+        assert!(!wildcard_match(b"*a*a*a*a*a*a*a*a*b", name.as_bytes()));
+        assert_eq!(
+            split_glob("docs/**/*.md"),
+            (
+                PathBuf::from("docs"),
+                Some(vec!["**".to_string(), "*.md".to_string()])
+            )
+        );
+    }
+
+    #[test]
+    fn mirrors_the_tree_and_copies_assets() -> io::Result<()> {
+        let root = std::env::temp_dir().join("flux_batch_test");
+        let _ = fs::remove_dir_all(&root);
+        let docs = root.join("docs");
+        fs::create_dir_all(docs.join("guide"))?;
+        fs::create_dir_all(docs.join(".git"))?;
+        fs::write(docs.join("index.md"), "# Home\n")?;
+        fs::write(docs.join("guide/setup.v2.md"), "Setup\n")?;
+        fs::write(docs.join("guide/logo.png"), [0u8, 1, 2])?;
+        fs::write(docs.join("settings.toml"), "port = 80\n")?;
+        fs::write(docs.join("bad.json"), "{")?;
+
+        let registry = Registry::with_builtins();
+        let options = ConvertOptions::default();
+        let input = docs.to_str().unwrap();
+        let html = root.join("html");
+        let summary = run(&registry, input, &html, FileType::HTML, &options)?;
+        assert_eq!(
+            summary,
+            Summary {
+                converted: 2,
+                copied: 3,
+                skipped: 1,
+                failed: 0,
+            }
+        );
+        assert!(html.join("index.html").is_file());
+        assert!(html.join("guide/setup.v2.html").is_file());
+        assert_eq!(fs::read(html.join("guide/logo.png"))?, [0u8, 1, 2]);
+
+        let yaml = root.join("yaml");
+        let summary = run(&registry, input, &yaml, FileType::YAML, &options)?;
+        assert_eq!((summary.converted, summary.failed), (1, 1));
+        assert_eq!(
+            fs::read_to_string(yaml.join("settings.yaml"))?,
+            "port: 80\n"
+        );
+
+        let pattern = format!("{}/**/*.md", docs.display());
+        let (files, _) = collect_files(&pattern)?;
+        let relative: Vec<_> = files.into_iter().map(|(_, r)| r).collect();
+        assert_eq!(
+            relative,
+            [
+                PathBuf::from("guide/setup.v2.md"),
+                PathBuf::from("index.md")
+            ]
+        );
+        fs::remove_dir_all(&root)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinked_directories_and_rejects_collisions() -> io::Result<()> {
+        let root = std::env::temp_dir().join("flux_batch_links");
+        let _ = fs::remove_dir_all(&root);
+        let docs = root.join("docs");
+        fs::create_dir_all(root.join("shared"))?;
+        fs::create_dir_all(&docs)?;
+        fs::write(root.join("shared/guide.md"), "Guide\n")?;
+        std::os::unix::fs::symlink(root.join("shared"), docs.join("shared"))?;
+        // A link back up the tree would otherwise be walked forever
+        std::os::unix::fs::symlink(&docs, docs.join("loop"))?;
+        fs::write(docs.join("a.html"), "<p>kept</p>")?;
+        fs::write(docs.join("a.md"), "Replaced\n")?;
+
+        let html = root.join("html");
+        let input = docs.to_str().unwrap();
+        let registry = Registry::with_builtins();
+        let summary = run(
+            &registry,
+            input,
+            &html,
+            FileType::HTML,
+            &ConvertOptions::default(),
+        )?;
+        assert_eq!(
+            summary,
+            Summary {
+                converted: 1,
+                copied: 1,
+                skipped: 1,
+                failed: 1,
+            }
+        );
+        assert!(html.join("shared/guide.html").is_file());
+        assert_eq!(fs::read_to_string(html.join("a.html"))?, "<p>kept</p>");
+        fs::remove_dir_all(&root)
+    }
+}
@@ -0,0 +1,363 @@
+// Project configuration. Settings start from the built-in defaults, then the nearest `flux.toml`
+// in the working directory or one of its parents, then command-line flags. Each `[[overrides]]`
+// entry applies its `markdown` settings to the files matching its glob, which is relative to the
+// directory holding flux.toml. Flags still win over overrides
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::batch;
+use crate::convert::ConvertOptions;
+use crate::highlight::Theme;
+use crate::html::Format;
+use crate::math::MathOutput;
+use crate::parsers::md_parser::{Extensions, HeadingIds, MDOptions};
+use crate::parsers::toml;
+use crate::value::Value;
+
+pub const FILE_NAME: &str = "flux.toml";
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// The flux.toml these settings were read from, if one was found
+    pub file: Option<PathBuf>,
+    /// Where batch conversion, watch and site write their output
+    pub out_dir: Option<PathBuf>,
+    /// The HTML layout for site pages
+    pub template: Option<PathBuf>,
+    /// Stylesheets linked from site and preview pages
+    pub css: Vec<String>,
+    pub minify: bool,
+    pub warnings_as_errors: bool,
+    pub md: MDOptions,
+    pub overrides: Vec<Override>,
+}
+
+/// Markdown settings for the files matching a glob
+#[derive(Clone, Debug)]
+pub struct Override {
+    pub glob: String,
+    // The directory the glob is relative to
+    root: PathBuf,
+    markdown: Vec<(Value, Value)>,
+}
+
+impl Override {
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        path.strip_prefix(&self.root)
+            .is_ok_and(|relative| batch::glob_matches(&self.glob, relative))
+    }
+
+    pub fn apply(&self, md: &mut MDOptions) {
+        // Overrides are checked when they are loaded, so this cannot fail
+        let _ = apply_markdown(md, &self.markdown, "markdown");
+    }
+}
+
+/// The nearest flux.toml in `dir` or one of its parents
+pub fn discover(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let settings = toml::parse(&text).map_err(|e| e.to_string())?;
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    let base = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let mut config = Config {
+        file: Some(path),
+        ..Config::default()
+    };
+    config.apply(&settings, &base)?;
+    Ok(config)
+}
+
+impl Config {
+    /// Layer a table of settings over these, resolving relative paths against `base`
+    pub fn apply(&mut self, settings: &Value, base: &Path) -> Result<(), String> {
+        for (key, value) in entries(settings, "settings")? {
+            match key_name(key)? {
+                "out_dir" => self.out_dir = Some(base.join(string(value, "out_dir")?)),
+                "template" => self.template = Some(base.join(string(value, "template")?)),
+                "css" => self.css = strings(value, "css")?,
+                "minify" => {
+                    self.minify = boolean(value, "minify")?;
+                    self.md.format = if self.minify {
+                        Format::Minify
+                    } else {
+                        Format::Pretty
+                    };
+                }
+                "warnings_as_errors" => {
+                    self.warnings_as_errors = boolean(value, "warnings_as_errors")?
+                }
+                "markdown" => {
+                    apply_markdown(&mut self.md, entries(value, "markdown")?, "markdown")?
+                }
+                "overrides" => {
+                    let Value::Array(items) = value else {
+                        return Err("overrides: expected an array of tables".to_string());
+                    };
+                    for item in items {
+                        self.overrides.push(parse_override(item, base)?);
+                    }
+                }
+                other => return Err(format!("unknown setting '{}'", other)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Layer settings given as flags. A Markdown setting given this way also replaces whatever
+    /// the overrides set for it
+    pub fn apply_flags(&mut self, settings: &Value) -> Result<(), String> {
+        self.apply(settings, Path::new(""))?;
+        let markdown = entries(settings, "settings")?
+            .iter()
+            .find(|(key, _)| matches!(key, Value::String(key) if key == "markdown"));
+        if let Some((_, Value::Map(markdown))) = markdown {
+            for each in &mut self.overrides {
+                each.markdown
+                    .retain(|(key, _)| !markdown.iter().any(|(flag, _)| flag == key));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn convert_options(&self) -> ConvertOptions {
+        ConvertOptions {
+            md: self.md.clone(),
+            minify: self.minify,
+            overrides: self.overrides.clone(),
+            warnings_as_errors: self.warnings_as_errors,
+        }
+    }
+
+    /// The settings as a TOML table, in the layout flux.toml uses
+    pub fn to_value(&self) -> Value {
+        let mut settings = Vec::new();
+        if let Some(out_dir) = &self.out_dir {
+            settings.push(entry("out_dir", path_value(out_dir)));
+        }
+        if let Some(template) = &self.template {
+            settings.push(entry("template", path_value(template)));
+        }
+        let css = self.css.iter().cloned().map(Value::String).collect();
+        settings.push(entry("css", Value::Array(css)));
+        settings.push(entry("minify", Value::Bool(self.minify)));
+        settings.push(entry(
+            "warnings_as_errors",
+            Value::Bool(self.warnings_as_errors),
+        ));
+        settings.push(entry("markdown", markdown_value(&self.md)));
+        let overrides = self
+            .overrides
+            .iter()
+            .map(|each| {
+                Value::Map(vec![
+                    entry("glob", Value::String(each.glob.clone())),
+                    entry("markdown", Value::Map(each.markdown.clone())),
+                ])
+            })
+            .collect();
+        settings.push(entry("overrides", Value::Array(overrides)));
+        Value::Map(settings)
+    }
+}
+
+fn parse_override(item: &Value, base: &Path) -> Result<Override, String> {
+    let mut glob = None;
+    let mut markdown = Vec::new();
+    for (key, value) in entries(item, "overrides")? {
+        match key_name(key)? {
+            "glob" => glob = Some(string(value, "overrides.glob")?.to_string()),
+            "markdown" => {
+                let settings = entries(value, "overrides.markdown")?;
+                apply_markdown(&mut MDOptions::default(), settings, "overrides.markdown")?;
+                markdown = settings.to_vec();
+            }
+            other => return Err(format!("unknown setting 'overrides.{}'", other)),
+        }
+    }
+    let glob = glob.ok_or("overrides: every override needs a glob")?;
+    Ok(Override {
+        glob,
+        root: base.to_path_buf(),
+        markdown,
+    })
+}
+
+// `table` names the table the settings came from, for error messages
+fn apply_markdown(
+    md: &mut MDOptions,
+    settings: &[(Value, Value)],
+    table: &str,
+) -> Result<(), String> {
+    for (key, value) in settings {
+        let key = key_name(key)?;
+        let path = format!("{}.{}", table, key);
+        match key {
+            "toc" => md.toc = boolean(value, &path)?,
+            "toc_depth" => match value {
+                Value::Integer(depth @ 1..=6) => md.toc_depth = *depth as usize,
+                _ => return Err(format!("{}: expected an integer from 1 to 6", path)),
+            },
+            "highlight_theme" => {
+                let name = string(value, &path)?;
+                md.highlight_theme = match name {
+                    "none" => None,
+                    _ => Some(Theme::from_name(name).map_err(|e| format!("{}: {}", path, e))?),
+                }
+            }
+            "math" => {
+                let name = string(value, &path)?;
+                md.math = MathOutput::from_name(name).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            "sourcepos" => md.sourcepos = boolean(value, &path)?,
+            "safe" => md.safe = boolean(value, &path)?,
+            "heading_ids" => {
+                let name = string(value, &path)?;
+                md.heading_ids =
+                    HeadingIds::from_name(name).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            "extensions" => {
+                md.extensions = Extensions::from_names(&strings(value, &path)?)
+                    .map_err(|e| format!("{}: {}", path, e))?
+            }
+            _ => return Err(format!("unknown setting '{}'", path)),
+        }
+    }
+    Ok(())
+}
+
+fn markdown_value(md: &MDOptions) -> Value {
+    let theme = md.highlight_theme.map_or("none", |theme| theme.name());
+    let extensions = md
+        .extensions
+        .names()
+        .into_iter()
+        .map(|name| Value::String(name.to_string()))
+        .collect();
+    Value::Map(vec![
+        entry("toc", Value::Bool(md.toc)),
+        entry("toc_depth", Value::Integer(md.toc_depth as i64)),
+        entry("highlight_theme", Value::String(theme.to_string())),
+        entry("math", Value::String(md.math.name().to_string())),
+        entry("sourcepos", Value::Bool(md.sourcepos)),
+        entry("safe", Value::Bool(md.safe)),
+        entry(
+            "heading_ids",
+            Value::String(md.heading_ids.name().to_string()),
+        ),
+        entry("extensions", Value::Array(extensions)),
+    ])
+}
+
+pub fn entry(key: &str, value: Value) -> (Value, Value) {
+    (Value::String(key.to_string()), value)
+}
+
+fn path_value(path: &Path) -> Value {
+    Value::String(path.to_string_lossy().into_owned())
+}
+
+fn entries<'a>(value: &'a Value, path: &str) -> Result<&'a [(Value, Value)], String> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        _ => Err(format!("{}: expected a table", path)),
+    }
+}
+
+fn key_name(key: &Value) -> Result<&str, String> {
+    match key {
+        Value::String(key) => Ok(key),
+        _ => Err("setting names must be strings".to_string()),
+    }
+}
+
+fn string<'a>(value: &'a Value, path: &str) -> Result<&'a str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(format!("{}: expected a string", path)),
+    }
+}
+
+fn strings(value: &Value, path: &str) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| string(item, path).map(str::to_string))
+            .collect(),
+        _ => Err(format!("{}: expected an array of strings", path)),
+    }
+}
+
+fn boolean(value: &Value, path: &str) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        _ => Err(format!("{}: expected true or false", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_win_over_the_file_and_its_overrides() -> std::io::Result<()> {
+        let root = std::env::temp_dir().join("flux_config_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs/blog"))?;
+        fs::write(
+            root.join(FILE_NAME),
+            "out_dir = \"public\"\ncss = [\"site.css\"]\n\n[markdown]\nsafe = true\n\
+             extensions = [\"math\"]\n\n[[overrides]]\nglob = \"docs/blog/**/*.md\"\n\
+             markdown = { safe = false, toc = true }\n",
+        )?;
+        fs::write(root.join("docs/blog/post.md"), "")?;
+        fs::write(root.join("docs/about.md"), "")?;
+
+        let found = discover(&root.join("docs/blog")).unwrap();
+        let mut config = load(&found).unwrap();
+        assert_eq!(config.out_dir, Some(root.canonicalize()?.join("public")));
+        assert!(config.md.safe && !config.md.extensions.alerts);
+
+        let options = config.convert_options();
+        assert!(!options.for_file(&root.join("docs/blog/post.md")).md.safe);
+        assert!(options.for_file(&root.join("docs/about.md")).md.safe);
+
+        let flags = Value::Map(vec![entry(
+            "markdown",
+            Value::Map(vec![entry("toc", Value::Bool(false))]),
+        )]);
+        config.apply_flags(&flags).unwrap();
+        let post = config
+            .convert_options()
+            .for_file(&root.join("docs/blog/post.md"));
+        assert!(!post.md.toc && !post.md.safe);
+        fs::remove_dir_all(&root)
+    }
+
+    #[test]
+    fn rejects_unknown_and_mistyped_settings() {
+        let parse = |text: &str| {
+            let settings = toml::parse(text).unwrap();
+            Config::default().apply(&settings, Path::new(""))
+        };
+        assert_eq!(
+            parse("outdir = \"x\"").unwrap_err(),
+            "unknown setting 'outdir'"
+        );
+        assert_eq!(
+            parse("[markdown]\ntoc_depth = 9").unwrap_err(),
+            "markdown.toc_depth: expected an integer from 1 to 6"
+        );
+        assert_eq!(
+            parse("[[overrides]]\nglob = \"*.md\"\nmarkdown = { tables = true }").unwrap_err(),
+            "unknown setting 'overrides.markdown.tables'"
+        );
+    }
+}
@@ -0,0 +1,485 @@
+// Conversions between file types. Each `Converter` handles one (from, to) pair, and the
+// `Registry` chains converters when no single one covers a pair, so md -> text runs through
+// HTML
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+
+use crate::config::Override;
+use crate::html;
+use crate::parsers::md_parser::{self, MDOptions};
+use crate::parsers::{json, toml, yaml};
+use crate::value::{self, ConfigFormat, Value};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileType {
+    MD,
+    HTML,
+    TEXT,
+    JSON,
+    YAML,
+    TOML,
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file_type_str = match self {
+            FileType::MD => "MD",
+            FileType::HTML => "HTML",
+            FileType::TEXT => "TEXT",
+            FileType::JSON => "JSON",
+            FileType::YAML => "YAML",
+            FileType::TOML => "TOML",
+        };
+        write!(f, "{}", file_type_str)
+    }
+}
+
+impl FileType {
+    pub const ALL: [FileType; 6] = [
+        FileType::MD,
+        FileType::HTML,
+        FileType::TEXT,
+        FileType::JSON,
+        FileType::YAML,
+        FileType::TOML,
+    ];
+
+    /// Parse a format name as given to `--from` and `--to`. File extensions work too
+    pub fn from_name(name: &str) -> Result<FileType, &'static str> {
+        match name.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Ok(FileType::MD),
+            "html" | "htm" => Ok(FileType::HTML),
+            "text" | "txt" => Ok(FileType::TEXT),
+            "json" => Ok(FileType::JSON),
+            "yaml" | "yml" => Ok(FileType::YAML),
+            "toml" => Ok(FileType::TOML),
+            _ => Err("expected one of md, html, text, json, yaml or toml"),
+        }
+    }
+
+    /// The type named by a path's final extension, so `notes.v2.md` is Markdown
+    pub fn from_file_name(file_name: &str) -> Result<FileType, &'static str> {
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .ok_or("no file extension")?;
+        FileType::from_name(extension).map_err(|_| "unknown file extension")
+    }
+
+    /// Guess the type from a file's contents, for files whose extension says nothing
+    pub fn sniff(contents: &str) -> Option<FileType> {
+        let trimmed = contents.trim_start_matches('\u{feff}').trim_start();
+        let lowercase_start = trimmed
+            .chars()
+            .take(16)
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if lowercase_start.starts_with("<!doctype html") || lowercase_start.starts_with("<html") {
+            return Some(FileType::HTML);
+        }
+        if trimmed.starts_with(['{', '[']) && json::parse(trimmed).is_ok() {
+            return Some(FileType::JSON);
+        }
+        // Judge the rest by the first line that is not a comment
+        let first_line = trimmed
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+        if first_line == "---" || first_line.starts_with("--- ") || first_line.starts_with("%YAML")
+        {
+            return Some(FileType::YAML);
+        }
+        let is_key = |key: &str| {
+            let key = key.trim().trim_matches(['"', '\'']);
+            !key.is_empty() && !key.contains(' ')
+        };
+        if first_line.starts_with('[') && first_line.ends_with(']') && !first_line.contains(", ") {
+            return Some(FileType::TOML);
+        }
+        if first_line
+            .split_once(" = ")
+            .is_some_and(|(key, _)| is_key(key))
+        {
+            return Some(FileType::TOML);
+        }
+        if first_line.starts_with("- ")
+            || first_line
+                .split_once(": ")
+                .is_some_and(|(key, _)| is_key(key))
+            || (first_line.ends_with(':') && is_key(&first_line[..first_line.len() - 1]))
+        {
+            return Some(FileType::YAML);
+        }
+        None
+    }
+
+    /// The short lowercase name used in listings
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileType::MD => "md",
+            FileType::HTML => "html",
+            FileType::TEXT => "text",
+            FileType::JSON => "json",
+            FileType::YAML => "yaml",
+            FileType::TOML => "toml",
+        }
+    }
+
+    /// The extension given to files converted to this type
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileType::TEXT => "txt",
+            _ => self.name(),
+        }
+    }
+
+    fn config_format(&self) -> Option<ConfigFormat> {
+        match self {
+            FileType::JSON => Some(ConfigFormat::Json),
+            FileType::YAML => Some(ConfigFormat::Yaml),
+            FileType::TOML => Some(ConfigFormat::Toml),
+            FileType::MD | FileType::HTML | FileType::TEXT => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptions {
+    pub md: MDOptions,
+    /// Drop insignificant whitespace from JSON output
+    pub minify: bool,
+    /// Markdown settings for files matching a glob, applied in order
+    pub overrides: Vec<Override>,
+    /// Fail a conversion that produced any warnings
+    pub warnings_as_errors: bool,
+}
+
+impl ConvertOptions {
+    /// These options with the overrides that match `path` applied
+    pub fn for_file(&self, path: &Path) -> ConvertOptions {
+        let mut options = self.clone();
+        for matching in self.overrides.iter().filter(|o| o.matches(path)) {
+            matching.apply(&mut options.md);
+        }
+        options
+    }
+
+    /// The error for a conversion that produced `warnings`, under --warnings-as-errors
+    pub fn check_warnings(&self, warnings: &[String]) -> Result<(), String> {
+        match warnings.len() {
+            0 => Ok(()),
+            _ if !self.warnings_as_errors => Ok(()),
+            1 => Err("1 warning treated as an error".to_string()),
+            count => Err(format!("{} warnings treated as errors", count)),
+        }
+    }
+}
+
+pub trait Converter {
+    /// The (from, to) pair this converter handles
+    fn pair(&self) -> (FileType, FileType);
+
+    /// Convert `input`, pushing anything lost along the way onto `warnings`
+    fn convert(
+        &self,
+        input: &str,
+        options: &ConvertOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<String, String>;
+}
+
+struct MarkdownToHtml;
+
+impl Converter for MarkdownToHtml {
+    fn pair(&self) -> (FileType, FileType) {
+        (FileType::MD, FileType::HTML)
+    }
+
+    fn convert(
+        &self,
+        input: &str,
+        options: &ConvertOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<String, String> {
+        let (html, found) = md_parser::md_string_to_html_with_warnings(input, &options.md);
+        warnings.extend(found.iter().map(|warning| warning.to_string()));
+        Ok(html)
+    }
+}
+
+struct HtmlToText;
+
+impl Converter for HtmlToText {
+    fn pair(&self) -> (FileType, FileType) {
+        (FileType::HTML, FileType::TEXT)
+    }
+
+    fn convert(
+        &self,
+        input: &str,
+        _options: &ConvertOptions,
+        _warnings: &mut Vec<String>,
+    ) -> Result<String, String> {
+        Ok(html::to_text(input))
+    }
+}
+
+// Every config format parses into a `Value` and serializes from one, so a single converter
+// type covers every pair. Whatever the output cannot represent is checked before writing
+struct ConfigConverter {
+    from: FileType,
+    to: FileType,
+}
+
+impl Converter for ConfigConverter {
+    fn pair(&self) -> (FileType, FileType) {
+        (self.from, self.to)
+    }
+
+    fn convert(
+        &self,
+        input: &str,
+        options: &ConvertOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<String, String> {
+        let from = self.from.config_format().unwrap();
+        let to = self.to.config_format().unwrap();
+        let parsed = match from {
+            ConfigFormat::Json => json::parse(input)
+                .map(|v| vec![v])
+                .map_err(|e| e.to_string()),
+            // Only YAML output can hold a stream of several documents
+            ConfigFormat::Yaml if to == ConfigFormat::Yaml => {
+                yaml::parse_documents(input).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Yaml => yaml::parse(input)
+                .map(|v| vec![v])
+                .map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::parse(input)
+                .map(|v| vec![v])
+                .map_err(|e| e.to_string()),
+        };
+        let mut documents = parsed?;
+        if documents.is_empty() {
+            documents.push(Value::Null);
+        }
+        for document in &documents {
+            let losses = value::check(document, to)
+                .map_err(|e| format!("cannot convert to {}: {}", to, e))?;
+            warnings.extend(losses.iter().map(|loss| loss.to_string()));
+        }
+        let converted = match to {
+            ConfigFormat::Json if options.minify => json::to_string_compact(&documents[0]),
+            ConfigFormat::Json => json::to_string_pretty(&documents[0]),
+            ConfigFormat::Yaml => yaml::to_string_documents(&documents),
+            ConfigFormat::Toml => toml::to_string(&documents[0])?,
+        };
+        Ok(converted)
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    converters: HashMap<(FileType, FileType), Box<dyn Converter>>,
+}
+
+impl Registry {
+    /// A registry holding every converter flux ships with
+    pub fn with_builtins() -> Self {
+        let mut registry = Registry::default();
+        registry.register(Box::new(MarkdownToHtml));
+        registry.register(Box::new(HtmlToText));
+        let configs = FileType::ALL
+            .into_iter()
+            .filter(|t| t.config_format().is_some());
+        for from in configs.clone() {
+            for to in configs.clone() {
+                registry.register(Box::new(ConfigConverter { from, to }));
+            }
+        }
+        registry
+    }
+
+    /// Add a converter, replacing any earlier one for the same pair
+    pub fn register(&mut self, converter: Box<dyn Converter>) {
+        self.converters.insert(converter.pair(), converter);
+    }
+
+    /// The shortest chain of converters from `from` to `to`, if there is one
+    pub fn path(&self, from: FileType, to: FileType) -> Option<Vec<&dyn Converter>> {
+        if let Some(direct) = self.converters.get(&(from, to)) {
+            return Some(vec![direct.as_ref()]);
+        }
+        // Breadth-first search, visiting types in `FileType::ALL` order so ties resolve the
+        // same way every run
+        let mut previous: HashMap<FileType, FileType> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(current) = queue.pop_front() {
+            for next in FileType::ALL {
+                if next == from
+                    || previous.contains_key(&next)
+                    || !self.converters.contains_key(&(current, next))
+                {
+                    continue;
+                }
+                previous.insert(next, current);
+                if next == to {
+                    let mut chain = vec![next];
+                    while let Some(&step) = previous.get(chain.last().unwrap()) {
+                        chain.push(step);
+                    }
+                    chain.reverse();
+                    return Some(
+                        chain
+                            .windows(2)
+                            .map(|pair| self.converters[&(pair[0], pair[1])].as_ref())
+                            .collect(),
+                    );
+                }
+                queue.push_back(next);
+            }
+        }
+        None
+    }
+
+    pub fn convert(
+        &self,
+        input: &str,
+        from: FileType,
+        to: FileType,
+        options: &ConvertOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<String, String> {
+        let path = self
+            .path(from, to)
+            .ok_or_else(|| format!("no conversion from {} to {}", from.name(), to.name()))?;
+        let mut converted = input.to_string();
+        for converter in path {
+            converted = converter.convert(&converted, options, warnings)?;
+        }
+        Ok(converted)
+    }
+
+    /// Every supported pair with the types it passes through on the way
+    pub fn supported_pairs(&self) -> Vec<(FileType, FileType, Vec<FileType>)> {
+        let mut pairs = Vec::new();
+        for from in FileType::ALL {
+            for to in FileType::ALL {
+                if let Some(path) = self.path(from, to) {
+                    let via = path[1..].iter().map(|c| c.pair().0).collect();
+                    pairs.push((from, to, via));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_converters_when_no_direct_one_exists() {
+        let registry = Registry::with_builtins();
+        let path = registry.path(FileType::MD, FileType::TEXT).unwrap();
+        let pairs: Vec<_> = path.iter().map(|c| c.pair()).collect();
+        assert_eq!(
+            pairs,
+            [
+                (FileType::MD, FileType::HTML),
+                (FileType::HTML, FileType::TEXT)
+            ]
+        );
+        let mut warnings = Vec::new();
+        let text = registry
+            .convert(
+                "# Hi\nSome *text*\n",
+                FileType::MD,
+                FileType::TEXT,
+                &ConvertOptions::default(),
+                &mut warnings,
+            )
+            .unwrap();
+        assert_eq!(text, "Hi\n\nSome text\n");
+    }
+
+    #[test]
+    fn unsupported_pairs_are_errors() {
+        let registry = Registry::with_builtins();
+        let result = registry.convert(
+            "{}",
+            FileType::JSON,
+            FileType::MD,
+            &ConvertOptions::default(),
+            &mut Vec::new(),
+        );
+        assert_eq!(result, Err("no conversion from json to md".to_string()));
+        assert!(registry.path(FileType::MD, FileType::MD).is_none());
+        let pairs = registry.supported_pairs();
+        assert!(pairs.contains(&(FileType::MD, FileType::TEXT, vec![FileType::HTML])));
+        assert!(pairs.contains(&(FileType::TOML, FileType::YAML, vec![])));
+    }
+
+    #[test]
+    fn registered_converters_replace_builtins() {
+        struct Shout;
+        impl Converter for Shout {
+            fn pair(&self) -> (FileType, FileType) {
+                (FileType::MD, FileType::TEXT)
+            }
+            fn convert(
+                &self,
+                input: &str,
+                _options: &ConvertOptions,
+                _warnings: &mut Vec<String>,
+            ) -> Result<String, String> {
+                Ok(input.to_uppercase())
+            }
+        }
+        let mut registry = Registry::with_builtins();
+        registry.register(Box::new(Shout));
+        let text = registry.convert(
+            "hi",
+            FileType::MD,
+            FileType::TEXT,
+            &ConvertOptions::default(),
+            &mut Vec::new(),
+        );
+        assert_eq!(text, Ok("HI".to_string()));
+    }
+
+    #[test]
+    fn file_types_come_from_the_final_extension() {
+        assert_eq!(FileType::from_file_name("notes.v2.md"), Ok(FileType::MD));
+        assert_eq!(
+            FileType::from_file_name("./docs/readme.md"),
+            Ok(FileType::MD)
+        );
+        assert_eq!(FileType::from_file_name("README.MD"), Ok(FileType::MD));
+        assert_eq!(FileType::from_file_name("config.yml"), Ok(FileType::YAML));
+        assert!(FileType::from_file_name("Makefile").is_err());
+        assert!(FileType::from_file_name("archive.tar.gz").is_err());
+        assert_eq!(FileType::from_name("Markdown"), Ok(FileType::MD));
+    }
+
+    #[test]
+    fn sniffs_formats_from_content() {
+        let cases = [
+            ("<!DOCTYPE html>\n<html></html>", Some(FileType::HTML)),
+            ("  {\"a\": [1, 2]}", Some(FileType::JSON)),
+            ("[1, 2]", Some(FileType::JSON)),
+            ("# settings\n[server]\nport = 80", Some(FileType::TOML)),
+            ("title = \"flux\"", Some(FileType::TOML)),
+            ("---\na: 1", Some(FileType::YAML)),
+            ("name: flux\nitems:\n  - a", Some(FileType::YAML)),
+            ("Just some prose.", None),
+        ];
+        for (contents, expected) in cases {
+            assert_eq!(FileType::sniff(contents), expected, "{}", contents);
+        }
+    }
+}
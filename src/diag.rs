@@ -0,0 +1,100 @@
+// Diagnostics on stderr, filtered by one process-wide level. Errors always print, warnings and
+// results print unless `--quiet`, per-file progress needs `--verbose`, and `--debug` adds traces
+// of the Markdown parser's state stack and tokens for bug reports
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages needing `level` are shown
+pub fn enabled(level: Level) -> bool {
+    shows(LEVEL.load(Ordering::Relaxed), level)
+}
+
+// Whether the process-wide level, stored as `current`, lets messages needing `level` through
+fn shows(current: u8, level: Level) -> bool {
+    current >= level as u8
+}
+
+#[doc(hidden)]
+pub fn emit(level: Level, prefix: &str, args: fmt::Arguments) {
+    if enabled(level) {
+        eprintln!("flux: {}{}", prefix, args);
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! diag_error {
+    ($($arg:tt)*) => {
+        $crate::diag::emit($crate::diag::Level::Quiet, "", format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! diag_warning {
+    ($($arg:tt)*) => {
+        $crate::diag::emit($crate::diag::Level::Normal, "warning: ", format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! diag_info {
+    ($($arg:tt)*) => {
+        $crate::diag::emit($crate::diag::Level::Normal, "", format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! diag_verbose {
+    ($($arg:tt)*) => {
+        $crate::diag::emit($crate::diag::Level::Verbose, "", format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! diag_debug {
+    ($($arg:tt)*) => {
+        $crate::diag::emit($crate::diag::Level::Debug, "debug: ", format_args!($($arg)*))
+    };
+}
+
+// Exported at the crate root under longer names, so the binary can reach them as `diag::error!`
+pub use crate::{
+    diag_debug as debug, diag_error as error, diag_info as info, diag_verbose as verbose,
+    diag_warning as warning,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the filtering without the process-wide level, which tests run in parallel share
+    #[test]
+    fn levels_are_ordered() {
+        assert!(Level::Debug > Level::Verbose && Level::Verbose > Level::Normal);
+        let normal = Level::Normal as u8;
+        assert!(shows(normal, Level::Quiet) && shows(normal, Level::Normal));
+        assert!(!shows(normal, Level::Verbose) && !shows(normal, Level::Debug));
+        assert!(shows(Level::Quiet as u8, Level::Quiet));
+        assert!(!shows(Level::Quiet as u8, Level::Normal));
+        assert!(shows(Level::Debug as u8, Level::Debug));
+    }
+}
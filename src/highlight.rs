@@ -0,0 +1,699 @@
+// Built-in syntax highlighting for fenced code blocks. Every supported language is described by
+// a `Language` table that drives one shared tokenizer, which wraps tokens in `<span class="hl-*">`
+
+use crate::html::escape_html;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub fn from_name(name: &str) -> Result<Theme, &'static str> {
+        match name {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err("Unknown highlight theme, expected 'light' or 'dark'"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+
+    pub fn css(&self) -> &'static str {
+        match self {
+            Theme::Light => include_str!("highlight/light.css"),
+            Theme::Dark => include_str!("highlight/dark.css"),
+        }
+    }
+}
+
+struct Language {
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    literals: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    // Letters that can lead a string, like Python's f"..." or Rust's b"...", in any case
+    string_prefixes: &'static [&'static str],
+    // Rust's r"..." and r#"..."#, which end at a quote followed by as many #s as they began with
+    raw_strings: bool,
+    triple_quotes: bool,
+    single_line_strings: bool,
+    // Rust uses ' for both char literals and lifetimes
+    char_literals: bool,
+    // A string or identifier directly followed by one of these is highlighted as a key
+    key_separators: &'static [char],
+    // YAML keys need whitespace after the colon so URLs like http://x stay plain
+    key_needs_space: bool,
+    capitalized_types: bool,
+    macro_bang: bool,
+    variable_prefix: Option<char>,
+    dashed_identifiers: bool,
+    section_headers: bool,
+    markup: bool,
+}
+
+const PLAIN: Language = Language {
+    keywords: &[],
+    types: &[],
+    literals: &[],
+    line_comments: &[],
+    block_comment: None,
+    quotes: &[],
+    string_prefixes: &[],
+    raw_strings: false,
+    triple_quotes: false,
+    single_line_strings: false,
+    char_literals: false,
+    key_separators: &[],
+    key_needs_space: false,
+    capitalized_types: false,
+    macro_bang: false,
+    variable_prefix: None,
+    dashed_identifiers: false,
+    section_headers: false,
+    markup: false,
+};
+
+const RUST: Language = Language {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type",
+        "unsafe", "use", "where", "while",
+    ],
+    types: &[
+        "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+        "i64", "i128", "isize", "f32", "f64",
+    ],
+    literals: &["true", "false"],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"'],
+    string_prefixes: &["b", "c", "r", "br", "cr"],
+    raw_strings: true,
+    char_literals: true,
+    capitalized_types: true,
+    macro_bang: true,
+    ..PLAIN
+};
+
+const PYTHON: Language = Language {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+        "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+        "yield",
+    ],
+    types: &[
+        "int", "float", "str", "bytes", "bool", "list", "dict", "set", "tuple", "object",
+    ],
+    literals: &["True", "False", "None"],
+    line_comments: &["#"],
+    quotes: &['"', '\''],
+    string_prefixes: &["b", "f", "r", "u", "br", "rb", "fr", "rf"],
+    triple_quotes: true,
+    capitalized_types: true,
+    ..PLAIN
+};
+
+const JAVASCRIPT: Language = Language {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "debugger",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "export",
+        "extends",
+        "finally",
+        "for",
+        "from",
+        "function",
+        "if",
+        "import",
+        "in",
+        "instanceof",
+        "let",
+        "new",
+        "of",
+        "return",
+        "static",
+        "super",
+        "switch",
+        "this",
+        "throw",
+        "try",
+        "typeof",
+        "var",
+        "void",
+        "while",
+        "with",
+        "yield",
+    ],
+    literals: &["true", "false", "null", "undefined", "NaN", "Infinity"],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+    capitalized_types: true,
+    ..PLAIN
+};
+
+const TYPESCRIPT: Language = Language {
+    keywords: &[
+        "abstract",
+        "as",
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "declare",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "enum",
+        "export",
+        "extends",
+        "finally",
+        "for",
+        "from",
+        "function",
+        "if",
+        "implements",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "keyof",
+        "let",
+        "namespace",
+        "new",
+        "of",
+        "private",
+        "protected",
+        "public",
+        "readonly",
+        "return",
+        "static",
+        "super",
+        "switch",
+        "this",
+        "throw",
+        "try",
+        "type",
+        "typeof",
+        "var",
+        "void",
+        "while",
+        "yield",
+    ],
+    types: &[
+        "any", "boolean", "never", "number", "object", "string", "symbol", "unknown", "bigint",
+    ],
+    ..JAVASCRIPT
+};
+
+const SHELL: Language = Language {
+    keywords: &[
+        "if", "then", "else", "elif", "fi", "case", "esac", "for", "while", "until", "do", "done",
+        "in", "function", "return", "local", "export", "readonly", "set", "unset", "shift",
+    ],
+    types: &[
+        "echo", "cd", "exit", "source", "test", "printf", "read", "eval", "exec", "trap",
+    ],
+    literals: &["true", "false"],
+    line_comments: &["#"],
+    quotes: &['"', '\''],
+    variable_prefix: Some('$'),
+    dashed_identifiers: true,
+    ..PLAIN
+};
+
+const JSON: Language = Language {
+    literals: &["true", "false", "null"],
+    quotes: &['"'],
+    single_line_strings: true,
+    key_separators: &[':'],
+    ..PLAIN
+};
+
+const YAML: Language = Language {
+    literals: &[
+        "true", "false", "null", "True", "False", "Null", "TRUE", "FALSE", "NULL", "yes", "no",
+        "on", "off", "~",
+    ],
+    line_comments: &["#"],
+    quotes: &['"', '\''],
+    single_line_strings: true,
+    key_separators: &[':'],
+    key_needs_space: true,
+    dashed_identifiers: true,
+    ..PLAIN
+};
+
+const TOML: Language = Language {
+    literals: &["true", "false", "inf", "nan"],
+    line_comments: &["#"],
+    quotes: &['"', '\''],
+    triple_quotes: true,
+    single_line_strings: true,
+    key_separators: &['='],
+    dashed_identifiers: true,
+    section_headers: true,
+    ..PLAIN
+};
+
+const HTML: Language = Language {
+    markup: true,
+    ..PLAIN
+};
+
+fn language_for(name: &str) -> Option<&'static Language> {
+    match name.to_lowercase().as_str() {
+        "rust" | "rs" => Some(&RUST),
+        "python" | "py" | "python3" => Some(&PYTHON),
+        "javascript" | "js" | "jsx" | "mjs" | "cjs" => Some(&JAVASCRIPT),
+        "typescript" | "ts" | "tsx" => Some(&TYPESCRIPT),
+        "shell" | "sh" | "bash" | "zsh" => Some(&SHELL),
+        "json" => Some(&JSON),
+        "yaml" | "yml" => Some(&YAML),
+        "toml" => Some(&TOML),
+        "html" | "htm" | "xml" | "svg" => Some(&HTML),
+        _ => None,
+    }
+}
+
+/// Highlight `code` written in `language`, falling back to escaped plain text when the
+/// language is missing or not supported
+pub fn highlight(code: &str, language: Option<&str>) -> String {
+    match language.and_then(language_for) {
+        Some(lang) => {
+            let mut highlighter = Highlighter {
+                lang,
+                chars: code.chars().collect(),
+                index: 0,
+                html: String::with_capacity(code.len() * 2),
+            };
+            if lang.markup {
+                highlighter.highlight_markup();
+            } else {
+                highlighter.highlight_code();
+            }
+            highlighter.html
+        }
+        None => escape_html(code),
+    }
+}
+
+struct Highlighter {
+    lang: &'static Language,
+    chars: Vec<char>,
+    index: usize,
+    html: String,
+}
+
+impl Highlighter {
+    fn highlight_code(&mut self) {
+        while self.index < self.chars.len() {
+            let c = self.chars[self.index];
+            if let Some((open, close)) = self.lang.block_comment.filter(|(o, _)| self.at(o)) {
+                let end = self.find(close, self.index + open.len());
+                self.push_token("comment", end);
+            } else if self.at_line_comment() {
+                let end = self.find_char('\n', self.index);
+                self.push_token("comment", end);
+            } else if self.lang.section_headers && c == '[' && self.at_line_start() {
+                let end = self.find_char('\n', self.index);
+                let header_end = self.chars[self.index..end]
+                    .iter()
+                    .rposition(|&c| c == ']')
+                    .map_or(end, |i| self.index + i + 1);
+                self.push_token("section", header_end);
+            } else if self.lang.char_literals && c == '\'' {
+                self.handle_char_literal();
+            } else if self.lang.quotes.contains(&c) && !self.prev_is_word() {
+                // A quote inside a word, like YAML's `it's`, is text. Prefixed strings are
+                // taken whole by `handle_identifier`, so they never get here
+                let end = self.string_end(self.index, c);
+                let class = if self.followed_by_key_separator(end) {
+                    "key"
+                } else {
+                    "string"
+                };
+                self.push_token(class, end);
+            } else if c.is_ascii_digit() && !self.prev_is_word() {
+                let end = self.number_end();
+                self.push_token("number", end);
+            } else if Some(c) == self.lang.variable_prefix && self.variable_follows() {
+                let end = match self.chars[self.index + 1] {
+                    '{' => self.find_char('}', self.index) + 1,
+                    _ => self.identifier_end(self.index + 1),
+                };
+                self.push_token("variable", end.min(self.chars.len()));
+            } else if c.is_alphabetic() || c == '_' {
+                self.handle_identifier();
+            } else {
+                self.push_plain(self.index + 1);
+            }
+        }
+    }
+
+    fn handle_identifier(&mut self) {
+        let end = self.identifier_end(self.index);
+        let word: String = self.chars[self.index..end].iter().collect();
+        if let Some(string_end) = self.prefixed_string_end(end, &word) {
+            self.push_token("string", string_end);
+            return;
+        }
+        let next = self.chars.get(end).copied();
+        let class = if self.lang.keywords.contains(&word.as_str()) {
+            Some("keyword")
+        } else if self.lang.literals.contains(&word.as_str()) {
+            Some("literal")
+        } else if self.followed_by_key_separator(end) {
+            Some("key")
+        } else if self.lang.types.contains(&word.as_str())
+            || (self.lang.capitalized_types && word.starts_with(char::is_uppercase))
+        {
+            Some("type")
+        } else if next == Some('(') || (self.lang.macro_bang && next == Some('!')) {
+            Some("function")
+        } else {
+            None
+        };
+        match class {
+            Some(class) => self.push_token(class, end),
+            None => self.push_plain(end),
+        }
+    }
+
+    fn handle_char_literal(&mut self) {
+        // 'a' and '\n' are chars, while 'a on its own is a lifetime or label
+        let end = match (
+            self.chars.get(self.index + 1),
+            self.chars.get(self.index + 2),
+        ) {
+            (Some('\\'), _) => Some(self.find_char('\'', self.index + 2) + 1),
+            (Some(_), Some('\'')) => Some(self.index + 3),
+            _ => None,
+        };
+        match end {
+            Some(end) => self.push_token("string", end.min(self.chars.len())),
+            None => {
+                let end = self.identifier_end(self.index + 1);
+                self.push_token("type", end.max(self.index + 1));
+            }
+        }
+    }
+
+    fn highlight_markup(&mut self) {
+        while self.index < self.chars.len() {
+            let next = self.chars.get(self.index + 1).copied().unwrap_or(' ');
+            if self.at("<!--") {
+                let end = self.find("-->", self.index + 4);
+                self.push_token("comment", end);
+            } else if self.chars[self.index] == '<'
+                && (next.is_alphabetic() || next == '/' || next == '!')
+            {
+                self.highlight_tag();
+            } else {
+                self.push_plain(self.index + 1);
+            }
+        }
+    }
+
+    fn highlight_tag(&mut self) {
+        let mut name_start = self.index + 1;
+        if self.chars[name_start] == '/' {
+            name_start += 1;
+        }
+        self.push_plain(name_start);
+        let name_end = self.scan_while(self.index, |c| !c.is_whitespace() && c != '>' && c != '/');
+        self.push_token("tag", name_end);
+        while self.index < self.chars.len() {
+            match self.chars[self.index] {
+                '>' => {
+                    self.push_plain(self.index + 1);
+                    return;
+                }
+                '"' | '\'' => {
+                    let end = self.find_char(self.chars[self.index], self.index + 1) + 1;
+                    self.push_token("string", end.min(self.chars.len()));
+                }
+                c if c.is_whitespace() || c == '=' || c == '/' => self.push_plain(self.index + 1),
+                _ => {
+                    let end = self
+                        .scan_while(self.index, |c| !c.is_whitespace() && !"=>/\"'".contains(c));
+                    self.push_token("attr", end);
+                }
+            }
+        }
+    }
+
+    fn at(&self, pattern: &str) -> bool {
+        pattern
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.index + i) == Some(&c))
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.chars[..self.index]
+            .iter()
+            .rev()
+            .take_while(|&&c| c != '\n')
+            .all(|c| c.is_whitespace())
+    }
+
+    fn at_line_comment(&self) -> bool {
+        self.lang.line_comments.iter().any(|marker| {
+            // A shell or YAML '#' in the middle of a word is not a comment
+            self.at(marker) && (*marker != "#" || self.index == 0 || !self.prev_is_word())
+        })
+    }
+
+    fn prev_is_word(&self) -> bool {
+        self.index > 0 && {
+            let prev = self.chars[self.index - 1];
+            prev.is_alphanumeric() || prev == '_' || prev == '$'
+        }
+    }
+
+    fn variable_follows(&self) -> bool {
+        matches!(self.chars.get(self.index + 1), Some(&c) if c.is_alphanumeric() || c == '_' || c == '{')
+    }
+
+    fn followed_by_key_separator(&self, end: usize) -> bool {
+        let mut i = end;
+        while i < self.chars.len() && (self.chars[i] == ' ' || self.chars[i] == '\t') {
+            i += 1;
+        }
+        match self.chars.get(i) {
+            Some(&c) if self.lang.key_separators.contains(&c) => {
+                !self.lang.key_needs_space
+                    || matches!(self.chars.get(i + 1), None | Some(' ' | '\n' | '\t'))
+            }
+            _ => false,
+        }
+    }
+
+    // The end of a string whose prefix, like the f of f"...", ends at the quote at `start`
+    fn prefixed_string_end(&self, start: usize, prefix: &str) -> Option<usize> {
+        let prefix = prefix.to_lowercase();
+        if !self.lang.string_prefixes.contains(&prefix.as_str()) {
+            return None;
+        }
+        if self.lang.raw_strings && prefix.ends_with('r') {
+            let hashes = self.scan_while(start, |c| c == '#') - start;
+            if self.chars.get(start + hashes) != Some(&'"') {
+                return None;
+            }
+            let close: String = std::iter::once('"')
+                .chain(std::iter::repeat_n('#', hashes))
+                .collect();
+            return Some(self.find(&close, start + hashes + 1));
+        }
+        let quote = *self.chars.get(start)?;
+        self.lang
+            .quotes
+            .contains(&quote)
+            .then(|| self.string_end(start, quote))
+    }
+
+    // The end of the string opened by the quote at `start`
+    fn string_end(&self, start: usize, quote: char) -> usize {
+        let triple: Vec<char> = std::iter::repeat_n(quote, 3).collect();
+        if self.lang.triple_quotes && self.chars[start..].starts_with(&triple) {
+            return self.find(&triple.iter().collect::<String>(), start + 3);
+        }
+        let mut i = start + 1;
+        while i < self.chars.len() {
+            match self.chars[i] {
+                // Single-quoted YAML and TOML strings are literal and have no escapes
+                '\\' if quote != '\'' || !self.lang.single_line_strings => i += 1,
+                '\n' if self.lang.single_line_strings => return i,
+                c if c == quote => return i + 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        self.chars.len()
+    }
+
+    fn number_end(&self) -> usize {
+        let mut i = self.index;
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            let exponent_sign = (c == '+' || c == '-')
+                && matches!(self.chars[i - 1], 'e' | 'E')
+                && !self.chars[self.index..i].contains(&'x');
+            let decimal_point = c == '.' && self.chars.get(i + 1).is_some_and(char::is_ascii_digit);
+            if c.is_ascii_alphanumeric() || c == '_' || decimal_point || exponent_sign {
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        i
+    }
+
+    fn identifier_end(&self, start: usize) -> usize {
+        let dashed = self.lang.dashed_identifiers;
+        self.scan_while(start, |c| {
+            c.is_alphanumeric() || c == '_' || (dashed && c == '-')
+        })
+    }
+
+    fn scan_while(&self, start: usize, predicate: impl Fn(char) -> bool) -> usize {
+        let mut i = start;
+        while i < self.chars.len() && predicate(self.chars[i]) {
+            i += 1;
+        }
+        i
+    }
+
+    // Index just past the next occurrence of `pattern`, or the end of the code
+    fn find(&self, pattern: &str, start: usize) -> usize {
+        let pattern: Vec<char> = pattern.chars().collect();
+        (start..self.chars.len())
+            .find(|&i| self.chars[i..].starts_with(&pattern))
+            .map_or(self.chars.len(), |i| i + pattern.len())
+    }
+
+    // Index of the next `target` char, or the end of the code
+    fn find_char(&self, target: char, start: usize) -> usize {
+        (start..self.chars.len())
+            .find(|&i| self.chars[i] == target)
+            .unwrap_or(self.chars.len())
+    }
+
+    fn push_token(&mut self, class: &str, end: usize) {
+        let token: String = self.chars[self.index..end].iter().collect();
+        self.html.push_str(&format!(
+            "<span class=\"hl-{}\">{}</span>",
+            class,
+            escape_html(&token)
+        ));
+        self.index = end;
+    }
+
+    fn push_plain(&mut self, end: usize) {
+        let text: String = self.chars[self.index..end].iter().collect();
+        self.html.push_str(&escape_html(&text));
+        self.index = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_rust() {
+        let html = highlight("fn main() { let x: u8 = 5; } // done", Some("rust"));
+        assert!(html.starts_with("<span class=\"hl-keyword\">fn</span> "));
+        assert!(html.contains("<span class=\"hl-function\">main</span>"));
+        assert!(html.contains("<span class=\"hl-type\">u8</span>"));
+        assert!(html.contains("<span class=\"hl-number\">5</span>"));
+        assert!(html.ends_with("<span class=\"hl-comment\">// done</span>"));
+    }
+
+    #[test]
+    fn highlights_config_keys() {
+        let json = highlight("{\"name\": \"flux\", \"ok\": true}", Some("json"));
+        assert!(json.contains("<span class=\"hl-key\">&quot;name&quot;</span>"));
+        assert!(json.contains("<span class=\"hl-string\">&quot;flux&quot;</span>"));
+        assert!(json.contains("<span class=\"hl-literal\">true</span>"));
+
+        let yaml = highlight("url: http://x # link", Some("yaml"));
+        assert!(yaml.starts_with("<span class=\"hl-key\">url</span>: http://x "));
+    }
+
+    #[test]
+    fn prefixed_strings() {
+        let string = |s: &str| format!("<span class=\"hl-string\">{}</span>", escape_html(s));
+        let comment = "<span class=\"hl-comment\"># c</span>";
+        for code in [
+            "f\"{x}\"",
+            "r\"\\d\"",
+            "b\"x\"",
+            "Rb'y'",
+            "f\"\"\"a\"b\"\"\"",
+        ] {
+            let html = highlight(&format!("x = {} # c", code), Some("python"));
+            assert_eq!(
+                html,
+                format!("x = {} {}", string(code), comment),
+                "{}",
+                code
+            );
+        }
+        for code in ["b\"x\"", "br\"\\d\"", "r#\"a \"q\" b\"#", "r##\"x\"#y\"##"] {
+            let html = highlight(&format!("let s = {}; // c", code), Some("rust"));
+            assert_eq!(
+                html,
+                format!(
+                    "<span class=\"hl-keyword\">let</span> s = {}; \
+                     <span class=\"hl-comment\">// c</span>",
+                    string(code)
+                ),
+                "{}",
+                code
+            );
+        }
+        // Raw identifiers and byte chars are not strings
+        assert_eq!(
+            highlight("r#type", Some("rust")),
+            "r#<span class=\"hl-keyword\">type</span>"
+        );
+        assert_eq!(
+            highlight("b'a'", Some("rust")),
+            format!("b{}", string("'a'"))
+        );
+    }
+
+    #[test]
+    fn unknown_language_is_escaped() {
+        assert_eq!(highlight("a < b", Some("cobol")), "a &lt; b");
+        assert_eq!(highlight("<b>", None), "&lt;b&gt;");
+    }
+}
@@ -0,0 +1,574 @@
+// An HTML node tree and its serializer. Renderers build the tree through `HtmlBuilder`, and
+// `serialize` lays it out either pretty-printed with nesting-based indentation or minified.
+// Both layouts are a pure function of the tree, so output is byte-for-byte deterministic
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Pretty,
+    Minify,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Element(Element),
+    /// Text that is escaped when serialized
+    Text(String),
+    /// Markup that is written out verbatim, like inline HTML from Markdown or highlighted code
+    Raw(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element {
+    pub tag: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+// Documents nest as deeply as their source does, so rather than dropping a child per stack frame,
+// descendants are moved onto a list and dropped from there with no children left
+impl Drop for Element {
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.children);
+        while let Some(node) = pending.pop() {
+            if let Node::Element(mut element) = node {
+                pending.append(&mut element.children);
+            }
+        }
+    }
+}
+
+impl Element {
+    pub fn new(tag: &str) -> Self {
+        Element {
+            tag: tag.to_string(),
+            attrs: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_attr(mut self, name: &str, value: &str) -> Self {
+        self.set_attr(name, value);
+        self
+    }
+
+    pub fn with_child(mut self, child: Node) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    pub fn set_attr(&mut self, name: &str, value: &str) {
+        match self.attrs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.attrs.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The text of this element with all markup removed
+    pub fn text_content(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                Node::Element(element) => text.push_str(&element.text_content()),
+                Node::Text(t) => text.push_str(t),
+                Node::Raw(raw) => text.push_str(&unescape_html(&strip_tags(raw))),
+            }
+        }
+        text
+    }
+
+    fn is_block(&self) -> bool {
+        BLOCK_TAGS.contains(&self.tag.as_str())
+    }
+}
+
+impl From<Element> for Node {
+    fn from(element: Element) -> Self {
+        Node::Element(element)
+    }
+}
+
+/// Builds a node tree from a stream of open/close/text events
+pub struct HtmlBuilder {
+    // The innermost open element is last; the bottom entry is a tagless document root
+    stack: Vec<Element>,
+}
+
+impl Default for HtmlBuilder {
+    fn default() -> Self {
+        HtmlBuilder {
+            stack: vec![Element::new("")],
+        }
+    }
+}
+
+impl HtmlBuilder {
+    pub fn open(&mut self, tag: &str) {
+        self.stack.push(Element::new(tag));
+    }
+
+    pub fn close(&mut self) {
+        self.close_with(Node::Element);
+    }
+
+    /// Close the innermost open element, putting whatever `render` makes of it in its place
+    pub fn close_with(&mut self, render: impl FnOnce(Element) -> Node) {
+        if self.stack.len() > 1 {
+            let element = self.stack.pop().unwrap();
+            self.push(render(element));
+        }
+    }
+
+    /// Set an attribute on the innermost open element
+    pub fn attr(&mut self, name: &str, value: &str) {
+        self.current_mut().set_attr(name, value);
+    }
+
+    pub fn text(&mut self, text: &str) {
+        match self.current_mut().children.last_mut() {
+            Some(Node::Text(last)) => last.push_str(text),
+            _ => self.push(Node::Text(text.to_string())),
+        }
+    }
+
+    pub fn raw(&mut self, html: &str) {
+        match self.current_mut().children.last_mut() {
+            Some(Node::Raw(last)) => last.push_str(html),
+            _ => self.push(Node::Raw(html.to_string())),
+        }
+    }
+
+    pub fn push(&mut self, node: Node) {
+        self.current_mut().children.push(node);
+    }
+
+    pub fn current(&self) -> &Element {
+        self.stack.last().unwrap()
+    }
+
+    pub fn current_mut(&mut self) -> &mut Element {
+        self.stack.last_mut().unwrap()
+    }
+
+    /// Close every open element and return the document's top-level nodes
+    pub fn finish(mut self) -> Vec<Node> {
+        while self.stack.len() > 1 {
+            self.close();
+        }
+        std::mem::take(&mut self.stack.pop().unwrap().children)
+    }
+}
+
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hr",
+    "html",
+    "li",
+    "link",
+    "main",
+    "meta",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "script",
+    "section",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "ul",
+];
+
+const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+// Whitespace inside these is significant, so their contents are never re-laid out
+const PREFORMATTED_TAGS: &[&str] = &["pre", "script", "style", "textarea"];
+
+const INDENT: &str = "    ";
+
+pub fn serialize(nodes: &[Node], format: Format) -> String {
+    let mut serializer = Serializer {
+        format,
+        out: String::new(),
+    };
+    serializer.write_children(nodes, 0);
+    serializer.out
+}
+
+struct Serializer {
+    format: Format,
+    out: String,
+}
+
+// Work left for the serializer, innermost last
+enum Pending<'a> {
+    Children(&'a [Node], usize),
+    Close(&'a str, usize),
+}
+
+impl Serializer {
+    // Block children each get their own line, and every run of inline nodes between them is
+    // written as a single line with the whitespace at its edges trimmed. Blocks nest as deeply
+    // as the document does, so the work left is kept on a stack of its own
+    fn write_children(&mut self, children: &[Node], depth: usize) {
+        let mut pending = vec![Pending::Children(children, depth)];
+        while let Some(next) = pending.pop() {
+            let (children, depth) = match next {
+                Pending::Children(children, depth) => (children, depth),
+                Pending::Close(tag, depth) => {
+                    self.write_line(&format!("</{}>", tag), depth);
+                    continue;
+                }
+            };
+            let mut run_start = None;
+            for (i, child) in children.iter().enumerate() {
+                match child {
+                    Node::Element(element) if element.is_block() => {
+                        if let Some(start) = run_start.take() {
+                            self.write_inline_run(&children[start..i], depth);
+                        }
+                        if self.write_block(element, depth) {
+                            // Its children come next, then its end tag, then its siblings
+                            pending.push(Pending::Children(&children[i + 1..], depth));
+                            pending.push(Pending::Close(&element.tag, depth));
+                            pending.push(Pending::Children(&element.children, depth + 1));
+                            break;
+                        }
+                    }
+                    _ => {
+                        run_start.get_or_insert(i);
+                    }
+                }
+            }
+            if let Some(start) = run_start {
+                self.write_inline_run(&children[start..], depth);
+            }
+        }
+    }
+
+    fn write_inline_run(&mut self, nodes: &[Node], depth: usize) {
+        let mut content = String::new();
+        for node in nodes {
+            write_inline(node, &mut content);
+        }
+        let content = content.trim();
+        if !content.is_empty() {
+            self.write_line(content, depth);
+        }
+    }
+
+    // Write a block element, or just its start tag when it holds blocks of its own, returning
+    // whether its children and end tag are still to be written
+    fn write_block(&mut self, element: &Element, depth: usize) -> bool {
+        let open = open_tag(element);
+        let tag = element.tag.as_str();
+        if VOID_TAGS.contains(&tag) {
+            self.write_line(&open, depth);
+        } else if PREFORMATTED_TAGS.contains(&tag) {
+            let mut content = String::new();
+            for child in &element.children {
+                write_inline(child, &mut content);
+            }
+            self.write_line(&format!("{}{}</{}>", open, content, tag), depth);
+        } else if element.children.iter().any(is_block_node) {
+            self.write_line(&open, depth);
+            return true;
+        } else {
+            let mut content = String::new();
+            for child in &element.children {
+                write_inline(child, &mut content);
+            }
+            self.write_line(&format!("{}{}</{}>", open, content.trim(), tag), depth);
+        }
+        false
+    }
+
+    fn write_line(&mut self, content: &str, depth: usize) {
+        match self.format {
+            Format::Pretty => {
+                for _ in 0..depth {
+                    self.out.push_str(INDENT);
+                }
+                self.out.push_str(content);
+                self.out.push('\n');
+            }
+            Format::Minify => self.out.push_str(content),
+        }
+    }
+}
+
+fn is_block_node(node: &Node) -> bool {
+    matches!(node, Node::Element(element) if element.is_block())
+}
+
+fn write_inline(node: &Node, out: &mut String) {
+    match node {
+        Node::Text(text) => out.push_str(&escape_html(text)),
+        Node::Raw(raw) => out.push_str(raw),
+        Node::Element(element) => {
+            out.push_str(&open_tag(element));
+            if !VOID_TAGS.contains(&element.tag.as_str()) {
+                for child in &element.children {
+                    write_inline(child, out);
+                }
+                out.push_str(&format!("</{}>", element.tag));
+            }
+        }
+    }
+}
+
+fn open_tag(element: &Element) -> String {
+    let mut tag = format!("<{}", element.tag);
+    for (name, value) in &element.attrs {
+        tag.push_str(&format!(" {}=\"{}\"", name, escape_html(value)));
+    }
+    tag.push('>');
+    tag
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The text of an HTML fragment without its tags. A `<` that can't start a tag, as in `a < b`,
+/// stays in the text
+pub fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let starts_tag =
+            rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || "/!?".contains(c));
+        match rest.find('>').filter(|_| starts_tag) {
+            Some(end) => rest = &rest[end + 1..],
+            None => {
+                text.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+// Elements that end a line in plain text rather than a paragraph
+const LINE_TAGS: &[&str] = &["br", "dd", "dt", "li", "tr"];
+
+/// Render HTML as plain text. Tags are dropped, block elements start new paragraphs, list items
+/// get a bullet and entities are decoded. Scripts and styles are left out entirely
+pub fn to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    // While inside a script or style, the tag whose closing ends the skipped content
+    let mut skip_until: Option<String> = None;
+    let mut pre_depth = 0usize;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            let tag = &rest[..end];
+            rest = &rest[end..];
+            let closing = tag.starts_with("</");
+            let name = tag
+                .trim_start_matches(['<', '/'])
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if let Some(until) = &skip_until {
+                if closing && name == *until {
+                    skip_until = None;
+                }
+                continue;
+            }
+            match name.as_str() {
+                "script" | "style" if !closing => skip_until = Some(name),
+                "li" if !closing => {
+                    push_break(&mut text, 1);
+                    text.push_str("- ");
+                }
+                "pre" if closing => {
+                    pre_depth = pre_depth.saturating_sub(1);
+                    push_break(&mut text, 2);
+                }
+                "pre" => {
+                    pre_depth += 1;
+                    push_break(&mut text, 2);
+                }
+                _ if LINE_TAGS.contains(&name.as_str()) => push_break(&mut text, 1),
+                _ if BLOCK_TAGS.contains(&name.as_str()) => push_break(&mut text, 2),
+                _ => {}
+            }
+            continue;
+        }
+        let end = rest.find('<').unwrap_or(rest.len());
+        let chunk = unescape_html(&rest[..end]);
+        rest = &rest[end..];
+        if skip_until.is_some() {
+            continue;
+        }
+        if pre_depth > 0 {
+            text.push_str(&chunk);
+            continue;
+        }
+        for c in chunk.chars() {
+            if !c.is_whitespace() {
+                text.push(c);
+            } else if !text.is_empty() && !text.ends_with([' ', '\n']) {
+                text.push(' ');
+            }
+        }
+    }
+    let mut result = text.trim().to_string();
+    result.push('\n');
+    result
+}
+
+// End the current line, leaving `newlines` breaks (2 for a blank line) before the next text
+fn push_break(text: &mut String, newlines: usize) {
+    let trimmed = text.trim_end_matches(' ').len();
+    text.truncate(trimmed);
+    if text.is_empty() {
+        return;
+    }
+    let existing = text.len() - text.trim_end_matches('\n').len();
+    for _ in existing..newlines {
+        text.push('\n');
+    }
+}
+
+/// Decode the character references `escape_html` and common HTML produce
+pub fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let decoded = entity.and_then(|(name, _)| match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let code = match name.strip_prefix(['#']) {
+                    Some(hex) if hex.starts_with(['x', 'X']) => {
+                        u32::from_str_radix(&hex[1..], 16).ok()
+                    }
+                    Some(decimal) => decimal.parse().ok(),
+                    None => None,
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+        match (decoded, entity) {
+            (Some(c), Some((_, end))) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<Node> {
+        let mut builder = HtmlBuilder::default();
+        builder.open("ul");
+        builder.open("li");
+        builder.text(" a < b ");
+        builder.open("ul");
+        builder.open("li");
+        builder.raw("<em>c</em>");
+        builder.close();
+        builder.close();
+        builder.close();
+        builder.close();
+        builder.open("pre");
+        builder.text("  keep\n  this\n");
+        builder.finish()
+    }
+
+    #[test]
+    fn pretty_output_is_indented_by_nesting() {
+        assert_eq!(
+            serialize(&sample(), Format::Pretty),
+            "<ul>\n    <li>\n        a &lt; b\n        <ul>\n            <li><em>c</em></li>\n\
+             \x20       </ul>\n    </li>\n</ul>\n<pre>  keep\n  this\n</pre>\n"
+        );
+    }
+
+    #[test]
+    fn minified_output_has_no_layout_whitespace() {
+        assert_eq!(
+            serialize(&sample(), Format::Minify),
+            "<ul><li>a &lt; b<ul><li><em>c</em></li></ul></li></ul><pre>  keep\n  this\n</pre>"
+        );
+    }
+
+    #[test]
+    fn text_output_keeps_paragraphs_and_lists() {
+        let html = "<style>p {}</style><h1>Title &amp; more</h1>\n<p>Some\n    <em>text</em></p>\
+                    <ul><li>one</li><li>two &#x263A;</li></ul><pre>  keep\n  this</pre>";
+        assert_eq!(
+            to_text(html),
+            "Title & more\n\nSome text\n\n- one\n- two \u{263a}\n\n  keep\n  this\n"
+        );
+    }
+}
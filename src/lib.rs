@@ -0,0 +1,16 @@
+// Flux converts between Markdown, HTML and config formats. The `flux` binary is a thin CLI over
+// these modules, and other crates can use them directly, for instance to render Markdown with
+// their own `SyntaxExtension`s and `Renderer`
+
+pub mod batch;
+pub mod config;
+pub mod convert;
+pub mod diag;
+pub mod highlight;
+pub mod html;
+pub mod math;
+pub mod parsers;
+pub mod serve;
+pub mod site;
+pub mod value;
+pub mod watch;
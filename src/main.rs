@@ -1,62 +1,557 @@
-use crate::parsers::md_parser;
-use clap::Parser;
-use core::fmt;
+use clap::{Parser, Subcommand};
+use flux::config::{self, entry, Config};
+use flux::convert::{ConvertOptions, FileType, Registry};
+use flux::diag::{self, Level};
+use flux::highlight::Theme;
+use flux::math::MathOutput;
+use flux::parsers;
+use flux::parsers::md_parser::{self, HeadingIds};
+use flux::site::{self, SiteOptions};
+use flux::value::Value;
+use flux::watch::{self, Destination, Source, Watcher};
+use flux::{batch, serve};
 use std::fs::File;
-use std::io::{self, Write};
-
-pub mod parsers;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version = "0.1", about = "A tool to convert between file types" , long_about = None)]
 struct Args {
-    /// The file to read from
-    #[arg(short, long, default_value = "test_files/baby.md")]
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The file to read from; `-` or leaving it out reads stdin. Markdown files are rendered to
+    /// HTML a block at a time, while stdin is always read whole before converting
+    #[arg(short, long)]
+    input_file: Option<String>,
+
+    /// The file to write to; `-` or leaving it out writes to stdout
+    #[arg(short, long, global = true)]
+    output_file: Option<String>,
+
+    /// Convert a directory tree or glob pattern into this directory, mirroring its layout
+    #[arg(long, global = true)]
+    out_dir: Option<String>,
+
+    /// The input format, when the file extension is missing or misleading
+    #[arg(long, value_parser = FileType::from_name)]
+    from: Option<FileType>,
+
+    /// The output format, when the file extension is missing or misleading
+    #[arg(long, global = true, value_parser = FileType::from_name)]
+    to: Option<FileType>,
+
+    /// Generate a table of contents from the document's headings
+    #[arg(long, global = true)]
+    toc: bool,
+
+    /// The deepest heading level to include in the table of contents (6 by default)
+    #[arg(long, global = true)]
+    toc_depth: Option<usize>,
+
+    /// Embed a syntax highlighting stylesheet (light or dark) in the HTML output
+    #[arg(long, global = true, value_parser = Theme::from_name)]
+    highlight_theme: Option<Theme>,
+
+    /// Render math for a client-side renderer (the default) or convert it to MathML
+    #[arg(long, global = true, value_parser = MathOutput::from_name)]
+    math: Option<MathOutput>,
+
+    /// Annotate block elements with data-sourcepos attributes for editor scroll sync
+    #[arg(long, global = true)]
+    sourcepos: bool,
+
+    /// Pretty-print the output with nesting-based indentation (the default)
+    #[arg(long, global = true, conflicts_with = "minify")]
+    pretty: bool,
 
-    /// The file to write to
-    #[arg(short, long, default_value = "test_files/test.html")]
-    output_file: String,
+    /// Minify the output, dropping all insignificant whitespace
+    #[arg(long, global = true)]
+    minify: bool,
+
+    /// Fail any conversion that produces warnings, for CI checks
+    #[arg(long, global = true)]
+    warnings_as_errors: bool,
+
+    /// Escape inline HTML and drop script links, for rendering untrusted Markdown
+    #[arg(long, global = true)]
+    safe: bool,
+
+    /// How heading ids are generated: github (the default), ascii or none
+    #[arg(long, global = true, value_parser = HeadingIds::from_name)]
+    heading_ids: Option<HeadingIds>,
+
+    /// The Markdown extensions to enable, separated by commas (all of them by default)
+    #[arg(long, global = true, value_delimiter = ',')]
+    extensions: Option<Vec<String>>,
+
+    /// A stylesheet to link from site and preview pages; repeat it for several
+    #[arg(long, global = true)]
+    css: Vec<String>,
+
+    /// Report every file converted and every request served
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Report errors only
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Also trace the Markdown parser's state stack and tokens, for bug reports
+    #[arg(long, global = true, conflicts_with = "quiet")]
+    debug: bool,
 }
 
-enum FileType {
-    HTML,
-    MD,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the supported conversions
+    Formats,
+    /// Inspect the project configuration in flux.toml
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Re-convert files whenever they change, into --out-dir or, for a single file, -o
+    Watch {
+        /// The files, directories or glob patterns to watch
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// How often to check for changes, in milliseconds
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+
+        /// How long a file must stay unchanged before it is converted, in milliseconds
+        #[arg(long, default_value_t = 200)]
+        debounce: u64,
+    },
+    /// Preview a Markdown file or directory in the browser, reloading it on changes
+    Serve {
+        /// The directory of pages and assets to serve, or a single Markdown file, served
+        /// together with only the files it links to or embeds
+        path: String,
+
+        /// The port to listen on at 127.0.0.1
+        #[arg(long, default_value_t = 4000)]
+        port: u16,
+    },
+    /// Render a directory of Markdown into a static site in --out-dir
+    Site {
+        /// The directory holding the Markdown pages and their assets
+        source: String,
+
+        /// An HTML layout with {{title}}, {{site_title}}, {{nav}}, {{css}}, {{content}} and
+        /// {{root}} placeholders
+        #[arg(long)]
+        template: Option<String>,
+
+        /// The site's title; defaults to the source directory's name
+        #[arg(long)]
+        title: Option<String>,
+    },
 }
 
-impl std::fmt::Display for FileType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let file_type_str = match self {
-            FileType::HTML => "HTML",
-            FileType::MD => "MD",
-        };
-        write!(f, "{}", file_type_str)
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the effective settings, merged from flux.toml and the flags
+    Show,
+}
+
+// Print a conversion's warnings, returning an error if --warnings-as-errors makes them errors
+fn report_warnings(
+    input_name: &str,
+    warnings: &[String],
+    options: &ConvertOptions,
+) -> Result<(), String> {
+    for warning in warnings {
+        diag::warning!("{}: {}", input_name, warning);
     }
+    options
+        .check_warnings(warnings)
+        .map_err(|e| format!("{}: {}", input_name, e))
 }
 
-impl FileType {
-    fn from_file_name(file_name: &str) -> Result<FileType, &'static str> {
-        match file_name.split_once('.').unwrap().1 {
-            "html" => Ok(FileType::HTML),
-            "md" => Ok(FileType::MD),
-            _ => Err("Invalid File Type"),
+// Render a Markdown file into `output` a block at a time, after a first pass for the link
+// definitions, returning its warnings
+fn stream_md(
+    path: &str,
+    output: &mut impl Write,
+    options: &ConvertOptions,
+) -> Result<Vec<String>, String> {
+    let open = || File::open(path).map(BufReader::new);
+    let warnings = open()
+        .and_then(md_parser::md_reference_definitions)
+        .and_then(|references| {
+            md_parser::md_stream_to_html(open()?, output, &options.md, references)
+        })
+        .and_then(|warnings| output.flush().map(|_| warnings))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(warnings.iter().map(|w| w.to_string()).collect())
+}
+
+// Write into a temporary file beside `path` and only move it into place once `write` succeeds,
+// so a failed conversion never leaves partial output behind
+fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<T, String>,
+) -> Result<T, String> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.tmp", name));
+    let file = File::create(&temp).map_err(|e| format!("{}: {}", temp.display(), e))?;
+    let mut file = BufWriter::new(file);
+    let written = write(&mut file).and_then(|value| {
+        file.flush()
+            .map(|_| value)
+            .map_err(|e| format!("{}: {}", temp.display(), e))
+    });
+    drop(file);
+    match written {
+        Ok(value) => std::fs::rename(&temp, path)
+            .map(|_| value)
+            .map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(e)
         }
     }
 }
 
+fn fail(message: &str) -> ! {
+    diag::error!("{}", message);
+    std::process::exit(1);
+}
+
+// `-` or a missing path means stdin or stdout
+fn file_path(path: &Option<String>) -> Option<&str> {
+    path.as_deref().filter(|path| *path != "-")
+}
+
+// The settings given as flags, in flux.toml's layout so they can be layered over it
+fn flag_settings(args: &Args) -> Value {
+    let string = |s: &str| Value::String(s.to_string());
+    let mut settings = Vec::new();
+    if let Some(out_dir) = &args.out_dir {
+        settings.push(entry("out_dir", string(out_dir)));
+    }
+    if !args.css.is_empty() {
+        let css = args.css.iter().map(|css| string(css)).collect();
+        settings.push(entry("css", Value::Array(css)));
+    }
+    if args.minify || args.pretty {
+        settings.push(entry("minify", Value::Bool(args.minify)));
+    }
+    if args.warnings_as_errors {
+        settings.push(entry("warnings_as_errors", Value::Bool(true)));
+    }
+    let mut markdown = Vec::new();
+    if args.toc {
+        markdown.push(entry("toc", Value::Bool(true)));
+    }
+    if let Some(depth) = args.toc_depth {
+        markdown.push(entry("toc_depth", Value::Integer(depth as i64)));
+    }
+    if let Some(theme) = args.highlight_theme {
+        markdown.push(entry("highlight_theme", string(theme.name())));
+    }
+    if let Some(math) = args.math {
+        markdown.push(entry("math", string(math.name())));
+    }
+    if args.sourcepos {
+        markdown.push(entry("sourcepos", Value::Bool(true)));
+    }
+    if args.safe {
+        markdown.push(entry("safe", Value::Bool(true)));
+    }
+    if let Some(heading_ids) = args.heading_ids {
+        markdown.push(entry("heading_ids", string(heading_ids.name())));
+    }
+    if let Some(extensions) = &args.extensions {
+        let names = extensions.iter().map(|name| string(name)).collect();
+        markdown.push(entry("extensions", Value::Array(names)));
+    }
+    if !markdown.is_empty() {
+        settings.push(entry("markdown", Value::Map(markdown)));
+    }
+    Value::Map(settings)
+}
+
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    let input_file_type: FileType =
-        FileType::from_file_name(&args.input_file).expect("Unsupported File Type");
-    let output_file_type: FileType =
-        FileType::from_file_name(&args.output_file).expect("Unsupported File Type");
-
-    let converted_data = match (input_file_type, output_file_type) {
-        (FileType::MD, FileType::HTML) => md_parser::md_to_html(&args.input_file).expect("error"),
-        (_, _) => "Unsupported Conversion".to_string(),
+    diag::set_level(if args.debug {
+        Level::Debug
+    } else if args.verbose {
+        Level::Verbose
+    } else if args.quiet {
+        Level::Quiet
+    } else {
+        Level::Normal
+    });
+    let registry = Registry::with_builtins();
+
+    if let Some(Command::Formats) = args.command {
+        for (from, to, via) in registry.supported_pairs() {
+            let via: Vec<&str> = via.iter().map(FileType::name).collect();
+            if via.is_empty() {
+                println!("{} -> {}", from.name(), to.name());
+            } else {
+                println!("{} -> {} (via {})", from.name(), to.name(), via.join(", "));
+            }
+        }
+        return Ok(());
+    }
+
+    let mut config = match config::discover(&std::env::current_dir()?) {
+        Some(path) => {
+            config::load(&path).unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)))
+        }
+        None => Config::default(),
     };
+    config
+        .apply_flags(&flag_settings(&args))
+        .unwrap_or_else(|e| fail(&e));
+    let options = config.convert_options();
+
+    if let Some(Command::Config {
+        action: ConfigAction::Show,
+    }) = &args.command
+    {
+        match &config.file {
+            Some(path) => println!("# Settings from {} and the flags", path.display()),
+            None => println!("# No {} found; defaults and the flags", config::FILE_NAME),
+        }
+        let settings = parsers::toml::to_string(&config.to_value()).unwrap_or_else(|e| fail(&e));
+        print!("{}", settings);
+        return Ok(());
+    }
 
-    let mut output_file = File::create(&args.output_file)?;
-    output_file.write_all(converted_data.as_bytes())?;
-    output_file.flush()?;
+    if let Some(Command::Watch {
+        paths,
+        interval,
+        debounce,
+    }) = &args.command
+    {
+        let single_file = paths.len() == 1 && !batch::is_batch_input(&paths[0]);
+        // -o names a single file's output, unless --out-dir is given too
+        let sources: Vec<Source> = match (file_path(&args.output_file), &config.out_dir) {
+            (Some(output), _) if single_file && args.out_dir.is_none() => vec![Source {
+                input: paths[0].clone(),
+                destination: Destination::File(PathBuf::from(output)),
+            }],
+            (_, Some(out_dir)) => paths
+                .iter()
+                .map(|input| Source {
+                    input: input.clone(),
+                    destination: Destination::Dir(out_dir.clone()),
+                })
+                .collect(),
+            _ => fail("watch writes into --out-dir, or for a single file to -o"),
+        };
+        let to = args.to.unwrap_or_else(|| match &sources[0].destination {
+            Destination::File(path) => FileType::from_file_name(&path.to_string_lossy())
+                .unwrap_or_else(|e| fail(&format!("{}: {}; pass --to", path.display(), e))),
+            Destination::Dir(_) => fail("pass --to to choose the format to convert to"),
+        });
+        let mut watcher = Watcher::new(
+            &registry,
+            sources,
+            to,
+            &options,
+            Duration::from_millis(*debounce),
+        );
+        watch::run(&mut watcher, Duration::from_millis(*interval));
+    }
+
+    if let Some(Command::Serve { path, port }) = &args.command {
+        let server = serve::Server::new(Path::new(path), options, config.css.clone())
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        serve::run(server, *port).unwrap_or_else(|e| fail(&format!("port {}: {}", port, e)));
+        return Ok(());
+    }
+
+    if let Some(Command::Site {
+        source,
+        template,
+        title,
+    }) = &args.command
+    {
+        let out_dir = config
+            .out_dir
+            .as_deref()
+            .unwrap_or_else(|| fail("pass --out-dir to choose where the site is written"));
+        let source = Path::new(source);
+        let template = template.as_ref().map(PathBuf::from);
+        let layout = match template.or_else(|| config.template.clone()) {
+            Some(path) => std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e))),
+            None => site::DEFAULT_LAYOUT.to_string(),
+        };
+        let title = title.clone().unwrap_or_else(|| {
+            let dir = source
+                .canonicalize()
+                .unwrap_or_else(|_| source.to_path_buf());
+            dir.file_name().map_or("Site".to_string(), |name| {
+                name.to_string_lossy().into_owned()
+            })
+        });
+        let site_options = SiteOptions {
+            title,
+            layout,
+            css: config.css.clone(),
+            options,
+        };
+        let summary = site::build(source, out_dir, &site_options)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", source.display(), e)));
+        diag::info!("{}", summary);
+        if summary.failed > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let input_path = file_path(&args.input_file);
+    // --out-dir asks for batch conversion, and several files need an output directory from
+    // the flag or flux.toml
+    if args.out_dir.is_some() || input_path.is_some_and(batch::is_batch_input) {
+        let input = input_path
+            .unwrap_or_else(|| fail("batch conversion reads a directory, glob or file, not stdin"));
+        let out_dir = config.out_dir.as_deref().unwrap_or_else(|| {
+            fail(&format!(
+                "{}: converting several files needs --out-dir",
+                input
+            ))
+        });
+        let to = args
+            .to
+            .unwrap_or_else(|| fail("pass --to to choose the format for batch conversion"));
+        let summary = batch::run(&registry, input, out_dir, to, &options)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", input, e)));
+        diag::info!("{}", summary);
+        if summary.failed > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let output_path = file_path(&args.output_file);
+    let input_name = input_path.unwrap_or("stdin");
+    let output_file_type = args.to.unwrap_or_else(|| match output_path {
+        Some(path) => FileType::from_file_name(path)
+            .unwrap_or_else(|e| fail(&format!("{}: {}; pass --to", path, e))),
+        None => fail("pass --to to choose the format written to stdout"),
+    });
+    let options = match input_path {
+        Some(path) => options.for_file(Path::new(path)),
+        None => options,
+    };
+
+    // Markdown files are rendered a block at a time, so a huge document never sits in memory
+    // whole. Stdin can't be read twice for the link definitions, so it is converted whole below
+    let from_name = input_path.and_then(|path| FileType::from_file_name(path).ok());
+    let streamed =
+        args.from.or(from_name) == Some(FileType::MD) && output_file_type == FileType::HTML;
+    if let Some(path) = input_path.filter(|_| streamed) {
+        match output_path {
+            Some(output) => write_atomically(Path::new(output), |file| {
+                let warnings = stream_md(path, file, &options)?;
+                report_warnings(path, &warnings, &options)
+            })
+            .unwrap_or_else(|e| fail(&e)),
+            // Nothing may reach stdout before the warnings are checked
+            None if options.warnings_as_errors => {
+                let mut html = Vec::new();
+                let warnings = stream_md(path, &mut html, &options).unwrap_or_else(|e| fail(&e));
+                report_warnings(path, &warnings, &options).unwrap_or_else(|e| fail(&e));
+                io::stdout().lock().write_all(&html)?;
+            }
+            None => {
+                let mut stdout = BufWriter::new(io::stdout().lock());
+                let warnings = stream_md(path, &mut stdout, &options).unwrap_or_else(|e| fail(&e));
+                report_warnings(path, &warnings, &options).unwrap_or_else(|e| fail(&e));
+            }
+        }
+        return Ok(());
+    }
+
+    let input = match input_path {
+        Some(path) => std::fs::read_to_string(path),
+        None => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input).map(|_| input)
+        }
+    }
+    .unwrap_or_else(|e| fail(&format!("{}: {}", input_name, e)));
+
+    // An explicit flag wins, then the extension, and for input the contents as a last resort
+    let input_file_type = args
+        .from
+        .or_else(|| input_path.and_then(|path| FileType::from_file_name(path).ok()))
+        .or_else(|| FileType::sniff(&input))
+        .unwrap_or_else(|| {
+            fail(&format!(
+                "{}: cannot tell the input format; pass --from",
+                input_name
+            ))
+        });
+    if registry.path(input_file_type, output_file_type).is_none() {
+        fail(&format!(
+            "no conversion from {} to {} (run `flux formats` to list the supported ones)",
+            input_file_type.name(),
+            output_file_type.name()
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let converted_data = registry
+        .convert(
+            &input,
+            input_file_type,
+            output_file_type,
+            &options,
+            &mut warnings,
+        )
+        .unwrap_or_else(|e| fail(&format!("{}: {}", input_name, e)));
+    report_warnings(input_name, &warnings, &options).unwrap_or_else(|e| fail(&e));
+
+    match output_path {
+        Some(path) => {
+            let mut output_file = File::create(path)?;
+            output_file.write_all(converted_data.as_bytes())?;
+            output_file.flush()?;
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(converted_data.as_bytes())?;
+            stdout.flush()?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_writes_leave_no_output() {
+        let dir = std::env::temp_dir().join("flux_write_atomically");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.html");
+
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"<p>partial").map_err(|e| e.to_string())?;
+            Err::<(), _>("1 warning treated as an error".to_string())
+        });
+        assert_eq!(failed, Err("1 warning treated as an error".to_string()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        write_atomically(&path, |file| {
+            file.write_all(b"<p>done</p>").map_err(|e| e.to_string())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "<p>done</p>");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+// Math rendering for `$...$` and `$$...$$`. By default the TeX source is passed through for a
+// client-side renderer such as KaTeX or MathJax, while `MathOutput::MathML` converts a LaTeX
+// subset to MathML so pages render without JavaScript
+
+use crate::html::{escape_html, Element, Node};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MathOutput {
+    Client,
+    MathML,
+}
+
+impl MathOutput {
+    pub fn from_name(name: &str) -> Result<MathOutput, &'static str> {
+        match name {
+            "client" => Ok(MathOutput::Client),
+            "mathml" => Ok(MathOutput::MathML),
+            _ => Err("Unknown math output, expected 'client' or 'mathml'"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MathOutput::Client => "client",
+            MathOutput::MathML => "mathml",
+        }
+    }
+}
+
+pub fn render_math(tex: &str, display: bool, output: MathOutput) -> Element {
+    let (tag, class) = if display {
+        ("div", "math display")
+    } else {
+        ("span", "math inline")
+    };
+    let body = match output {
+        MathOutput::Client if display => format!("\\[{}\\]", escape_html(tex)),
+        MathOutput::Client => format!("\\({}\\)", escape_html(tex)),
+        MathOutput::MathML => latex_to_mathml(tex, display),
+    };
+    Element::new(tag)
+        .with_attr("class", class)
+        .with_child(Node::Raw(body))
+}
+
+pub fn latex_to_mathml(tex: &str, display: bool) -> String {
+    let mut converter = MathConverter {
+        chars: tex.chars().collect(),
+        index: 0,
+        depth: 0,
+    };
+    let row = converter.parse_row(None);
+    let display = if display { "block" } else { "inline" };
+    format!(
+        "<math display=\"{}\"><mrow>{}</mrow></math>",
+        display,
+        row.concat()
+    )
+}
+
+fn greek_letter(name: &str) -> Option<char> {
+    let letter = match name {
+        "alpha" => 'α',
+        "beta" => 'β',
+        "gamma" => 'γ',
+        "delta" => 'δ',
+        "epsilon" => 'ϵ',
+        "varepsilon" => 'ε',
+        "zeta" => 'ζ',
+        "eta" => 'η',
+        "theta" => 'θ',
+        "iota" => 'ι',
+        "kappa" => 'κ',
+        "lambda" => 'λ',
+        "mu" => 'μ',
+        "nu" => 'ν',
+        "xi" => 'ξ',
+        "pi" => 'π',
+        "rho" => 'ρ',
+        "sigma" => 'σ',
+        "tau" => 'τ',
+        "upsilon" => 'υ',
+        "phi" => 'ϕ',
+        "varphi" => 'φ',
+        "chi" => 'χ',
+        "psi" => 'ψ',
+        "omega" => 'ω',
+        "Gamma" => 'Γ',
+        "Delta" => 'Δ',
+        "Theta" => 'Θ',
+        "Lambda" => 'Λ',
+        "Xi" => 'Ξ',
+        "Pi" => 'Π',
+        "Sigma" => 'Σ',
+        "Phi" => 'Φ',
+        "Psi" => 'Ψ',
+        "Omega" => 'Ω',
+        _ => return None,
+    };
+    Some(letter)
+}
+
+fn operator_symbol(name: &str) -> Option<&'static str> {
+    let symbol = match name {
+        "sum" => "∑",
+        "prod" => "∏",
+        "int" => "∫",
+        "oint" => "∮",
+        "pm" => "±",
+        "mp" => "∓",
+        "times" => "×",
+        "div" => "÷",
+        "cdot" => "⋅",
+        "ast" => "∗",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "propto" => "∝",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftrightarrow" | "iff" => "⇔",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "cup" => "∪",
+        "cap" => "∩",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "infty" => "∞",
+        "emptyset" => "∅",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "{" => "{",
+        "}" => "}",
+        "|" => "‖",
+        "," | ";" | " " | "quad" | "qquad" => "\u{2009}",
+        _ => return None,
+    };
+    Some(symbol)
+}
+
+const FUNCTION_NAMES: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "max", "min", "sup", "inf", "det", "gcd", "deg", "dim",
+];
+
+// Groups and arguments nested deeper than this are left as literal text, so hostile input
+// can't exhaust the stack
+const MAX_DEPTH: usize = 64;
+
+struct MathConverter {
+    chars: Vec<char>,
+    index: usize,
+    // How many atoms are being parsed, each inside the one before
+    depth: usize,
+}
+
+impl MathConverter {
+    // Parse nodes until `end` (or the end of input), consuming the terminator
+    fn parse_row(&mut self, end: Option<char>) -> Vec<String> {
+        let mut row = Vec::new();
+        while let Some(c) = self.peek() {
+            if Some(c) == end {
+                self.index += 1;
+                break;
+            }
+            if let Some(atom) = self.parse_atom() {
+                let atom = self.parse_scripts(atom);
+                row.push(atom);
+            }
+        }
+        row
+    }
+
+    fn parse_scripts(&mut self, base: String) -> String {
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('_') if sub.is_none() => {
+                    self.index += 1;
+                    sub = Some(self.parse_argument());
+                }
+                Some('^') if sup.is_none() => {
+                    self.index += 1;
+                    sup = Some(self.parse_argument());
+                }
+                _ => break,
+            }
+        }
+        match (sub, sup) {
+            (Some(sub), Some(sup)) => format!("<msubsup>{}{}{}</msubsup>", base, sub, sup),
+            (Some(sub), None) => format!("<msub>{}{}</msub>", base, sub),
+            (None, Some(sup)) => format!("<msup>{}{}</msup>", base, sup),
+            (None, None) => base,
+        }
+    }
+
+    // A single argument: either a `{...}` group or the next atom
+    fn parse_argument(&mut self) -> String {
+        self.skip_whitespace();
+        self.parse_atom()
+            .unwrap_or_else(|| "<mrow></mrow>".to_string())
+    }
+
+    fn parse_atom(&mut self) -> Option<String> {
+        self.skip_whitespace();
+        let c = self.peek()?;
+        if c == '}' {
+            self.index += 1;
+            return None;
+        }
+        if self.depth == MAX_DEPTH {
+            let rest: String = self.chars[self.index..].iter().collect();
+            self.index = self.chars.len();
+            return Some(format!(
+                "<merror><mtext>{}</mtext></merror>",
+                escape_html(&rest)
+            ));
+        }
+        self.index += 1;
+        self.depth += 1;
+        let atom = match c {
+            '{' => format!("<mrow>{}</mrow>", self.parse_row(Some('}')).concat()),
+            '\\' => self.parse_command(),
+            '0'..='9' | '.' => {
+                let mut number = c.to_string();
+                while let Some(d) = self.peek().filter(|d| d.is_ascii_digit() || *d == '.') {
+                    number.push(d);
+                    self.index += 1;
+                }
+                format!("<mn>{}</mn>", number)
+            }
+            _ if c.is_alphabetic() => format!("<mi>{}</mi>", c),
+            _ => format!("<mo>{}</mo>", escape_html(&c.to_string())),
+        };
+        self.depth -= 1;
+        Some(atom)
+    }
+
+    fn parse_command(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphabetic()) {
+            name.push(c);
+            self.index += 1;
+        }
+        // Control symbols like \{ or \, are a single non-letter character
+        if name.is_empty() {
+            if let Some(c) = self.peek() {
+                name.push(c);
+                self.index += 1;
+            }
+        }
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.parse_argument();
+                let denominator = self.parse_argument();
+                format!("<mfrac>{}{}</mfrac>", numerator, denominator)
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                if self.peek() == Some('[') {
+                    self.index += 1;
+                    let index = self.parse_row(Some(']')).concat();
+                    let radicand = self.parse_argument();
+                    format!("<mroot>{}<mrow>{}</mrow></mroot>", radicand, index)
+                } else {
+                    format!("<msqrt>{}</msqrt>", self.parse_argument())
+                }
+            }
+            "text" | "mathrm" | "operatorname" => {
+                format!("<mtext>{}</mtext>", escape_html(&self.raw_group()))
+            }
+            "mathbf" => self.styled("bold"),
+            "mathit" => self.styled("italic"),
+            "mathbb" => self.styled("double-struck"),
+            "mathcal" => self.styled("script"),
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" => {
+                // Sizing commands only wrap the delimiter that follows them
+                self.skip_whitespace();
+                match self.peek() {
+                    Some('.') => {
+                        self.index += 1;
+                        "".to_string()
+                    }
+                    Some(_) => self.parse_atom().unwrap_or_default(),
+                    None => "".to_string(),
+                }
+            }
+            _ if FUNCTION_NAMES.contains(&name.as_str()) => format!("<mi>{}</mi>", name),
+            _ => match (greek_letter(&name), operator_symbol(&name)) {
+                (Some(letter), _) => format!("<mi>{}</mi>", letter),
+                (None, Some(symbol)) => format!("<mo>{}</mo>", symbol),
+                (None, None) => format!("<merror><mtext>\\{}</mtext></merror>", escape_html(&name)),
+            },
+        }
+    }
+
+    fn styled(&mut self, variant: &str) -> String {
+        format!(
+            "<mstyle mathvariant=\"{}\">{}</mstyle>",
+            variant,
+            self.parse_argument()
+        )
+    }
+
+    // The literal contents of a `{...}` group, used for text that is not math
+    fn raw_group(&mut self) -> String {
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return "".to_string();
+        }
+        self.index += 1;
+        let mut depth = 1;
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            self.index += 1;
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.index += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_latex_subset() {
+        assert_eq!(
+            latex_to_mathml("x^2 + \\frac{a}{b}", false),
+            "<math display=\"inline\"><mrow><msup><mi>x</mi><mn>2</mn></msup><mo>+</mo>\
+             <mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac></mrow></math>"
+        );
+        assert_eq!(
+            latex_to_mathml("\\sum_{i=1}^n \\alpha_i", true),
+            "<math display=\"block\"><mrow><msubsup><mo>∑</mo><mrow><mi>i</mi><mo>=</mo>\
+             <mn>1</mn></mrow><mi>n</mi></msubsup><msub><mi>α</mi><mi>i</mi></msub></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn client_output_keeps_tex() {
+        let element = render_math("a < b", false, MathOutput::Client);
+        assert_eq!(element.attr("class"), Some("math inline"));
+        assert_eq!(
+            element.children,
+            vec![Node::Raw("\\(a &lt; b\\)".to_string())]
+        );
+        assert!(latex_to_mathml("\\unknown", false).contains("<merror>"));
+    }
+
+    #[test]
+    fn deep_nesting_is_capped() {
+        let braces = "{".repeat(100_000);
+        let mathml = latex_to_mathml(&braces, false);
+        assert_eq!(mathml.matches("<mrow>").count(), MAX_DEPTH + 1);
+        assert!(mathml.contains(&format!(
+            "<merror><mtext>{}</mtext></merror>",
+            "{".repeat(99_936)
+        )));
+        for tex in [
+            "\\sqrt".repeat(100_000),
+            "x^{".repeat(100_000),
+            "\\frac{".repeat(100_000),
+        ] {
+            assert!(latex_to_mathml(&tex, true).contains("<merror>"));
+        }
+        // Ordinary nesting is untouched
+        assert!(
+            !latex_to_mathml(&format!("{}x{}", "{".repeat(60), "}".repeat(60)), false)
+                .contains("<merror>")
+        );
+    }
+}
@@ -1 +1,6 @@
+pub mod json;
+pub mod md_extension;
 pub mod md_parser;
+pub mod md_renderer;
+pub mod toml;
+pub mod yaml;
@@ -0,0 +1,486 @@
+use std::fmt;
+
+use crate::value::{key_to_string, Value, MAX_DEPTH};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+struct JsonParser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    column: usize,
+    // How many arrays and objects enclose the current value
+    depth: usize,
+}
+
+impl JsonParser {
+    fn parse_document(&mut self) -> Result<Value, JsonError> {
+        self.skip_whitespace();
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(value),
+            Some(_) => Err(self.error("Unexpected trailing characters")),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, JsonError> {
+        match self.peek() {
+            Some('{' | '[') if self.depth == MAX_DEPTH => Err(self.error(&format!(
+                "Arrays and objects nested more than {} deep",
+                MAX_DEPTH
+            ))),
+            Some('{') => self.parse_nested(JsonParser::parse_object),
+            Some('[') => self.parse_nested(JsonParser::parse_array),
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('-' | '0'..='9') => self.parse_number(),
+            Some('t') => self.parse_literal("true", Value::Bool(true)),
+            Some('f') => self.parse_literal("false", Value::Bool(false)),
+            Some('n') => self.parse_literal("null", Value::Null),
+            Some(c) => Err(self.error(&format!("Unexpected character '{}'", c))),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    fn parse_nested(
+        &mut self,
+        parse: fn(&mut JsonParser) -> Result<Value, JsonError>,
+    ) -> Result<Value, JsonError> {
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_object(&mut self) -> Result<Value, JsonError> {
+        self.advance();
+        let mut members: Vec<(Value, Value)> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.advance();
+            return Ok(Value::Map(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("Expected a string key"));
+            }
+            let key = Value::String(self.parse_string()?);
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            let value = self.parse_value()?;
+            // A repeated key keeps its first position but takes the last value
+            match members.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, existing)) => *existing = value,
+                None => members.push((key, value)),
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some('}') => {
+                    self.advance();
+                    return Ok(Value::Map(members));
+                }
+                _ => return Err(self.error("Expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, JsonError> {
+        self.advance();
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.advance();
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            self.skip_whitespace();
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some(']') => {
+                    self.advance();
+                    return Ok(Value::Array(elements));
+                }
+                _ => return Err(self.error("Expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.advance();
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("Unterminated string")),
+                Some('"') => {
+                    self.advance();
+                    return Ok(string);
+                }
+                Some('\\') => {
+                    self.advance();
+                    string.push(self.parse_escape()?);
+                }
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(self.error("Unescaped control character in string"));
+                }
+                Some(c) => {
+                    string.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, JsonError> {
+        let escaped = match self.peek() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                self.advance();
+                return self.parse_unicode_escape();
+            }
+            _ => return Err(self.error("Invalid escape sequence")),
+        };
+        self.advance();
+        Ok(escaped)
+    }
+
+    // \uXXXX, where a high surrogate must be followed by an escaped low surrogate
+    fn parse_unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.parse_hex4()?;
+        let code_point = match high {
+            0xD800..=0xDBFF => {
+                if self.peek() != Some('\\') || self.chars.get(self.index + 1) != Some(&'u') {
+                    return Err(self.error("Unpaired high surrogate"));
+                }
+                self.advance();
+                self.advance();
+                let low = self.parse_hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(self.error("Invalid low surrogate"));
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(self.error("Unpaired low surrogate")),
+            _ => high,
+        };
+        char::from_u32(code_point).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self
+                .peek()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("Expected four hex digits"))?;
+            value = value * 16 + digit;
+            self.advance();
+        }
+        Ok(value)
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn parse_number(&mut self) -> Result<Value, JsonError> {
+        let start = self.index;
+        let mut is_float = false;
+        if self.peek() == Some('-') {
+            self.advance();
+        }
+        match self.peek() {
+            Some('0') => self.advance(),
+            Some('1'..='9') => self.skip_digits(),
+            _ => return Err(self.error("Expected a digit")),
+        }
+        if self.peek() == Some('.') {
+            is_float = true;
+            self.advance();
+            if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(self.error("Expected a digit after the decimal point"));
+            }
+            self.skip_digits();
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            is_float = true;
+            self.advance();
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.advance();
+            }
+            if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(self.error("Expected a digit in the exponent"));
+            }
+            self.skip_digits();
+        }
+        let literal: String = self.chars[start..self.index].iter().collect();
+        if !is_float {
+            // Integers too large for i64 fall back to a float
+            if let Ok(integer) = literal.parse::<i64>() {
+                return Ok(Value::Integer(integer));
+            }
+        }
+        literal
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| self.error("Invalid number"))
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Value) -> Result<Value, JsonError> {
+        for expected in literal.chars() {
+            if self.peek() != Some(expected) {
+                return Err(self.error(&format!("Expected '{}'", literal)));
+            }
+            self.advance();
+        }
+        Ok(value)
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.advance();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{}'", expected)));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.peek() == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        self.index += 1;
+    }
+
+    fn error(&self, message: &str) -> JsonError {
+        JsonError {
+            message: message.to_string(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Value, JsonError> {
+    let mut parser = JsonParser {
+        chars: input.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 1,
+        depth: 0,
+    };
+    parser.parse_document()
+}
+
+const INDENT: &str = "    ";
+
+pub fn to_string_pretty(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(value, &mut out, Some(0));
+    out.push('\n');
+    out
+}
+
+pub fn to_string_compact(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(value, &mut out, None);
+    out
+}
+
+// `depth` is None for compact output
+fn write_value(value: &Value, out: &mut String, depth: Option<usize>) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Integer(i) => out.push_str(&i.to_string()),
+        Value::Float(f) => out.push_str(&format_float(*f)),
+        Value::String(s) => write_string(s, out),
+        Value::Datetime(datetime) => write_string(&datetime.to_string(), out),
+        Value::Array(elements) if elements.is_empty() => out.push_str("[]"),
+        Value::Array(elements) => {
+            out.push('[');
+            for (i, element) in elements.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_newline(out, depth.map(|d| d + 1));
+                write_value(element, out, depth.map(|d| d + 1));
+            }
+            write_newline(out, depth);
+            out.push(']');
+        }
+        Value::Map(members) if members.is_empty() => out.push_str("{}"),
+        Value::Map(members) => {
+            out.push('{');
+            for (i, (key, member)) in members.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_newline(out, depth.map(|d| d + 1));
+                write_string(&key_to_string(key).unwrap_or_default(), out);
+                out.push(':');
+                if depth.is_some() {
+                    out.push(' ');
+                }
+                write_value(member, out, depth.map(|d| d + 1));
+            }
+            write_newline(out, depth);
+            out.push('}');
+        }
+    }
+}
+
+fn write_newline(out: &mut String, depth: Option<usize>) {
+    if let Some(depth) = depth {
+        out.push('\n');
+        for _ in 0..depth {
+            out.push_str(INDENT);
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Floats keep a fractional part or exponent so they stay floats when read back. JSON has no
+// NaN or infinity, so those are written as null
+fn format_float(f: f64) -> String {
+    if !f.is_finite() {
+        return "null".to_string();
+    }
+    let formatted = format!("{:?}", f);
+    if formatted.contains(['.', 'e', 'E']) {
+        formatted
+    } else {
+        format!("{}.0", formatted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escapes_and_numbers() {
+        let value = parse(r#"{"s": "a\"\\\/\n\u00e9\ud83d\ude00", "n": [-0, 12, 1.5e3, 1E-2]}"#);
+        assert_eq!(
+            value,
+            Ok(Value::Map(vec![
+                (
+                    Value::String("s".to_string()),
+                    Value::String("a\"\\/\né😀".to_string())
+                ),
+                (
+                    Value::String("n".to_string()),
+                    Value::Array(vec![
+                        Value::Integer(0),
+                        Value::Integer(12),
+                        Value::Float(1500.0),
+                        Value::Float(0.01),
+                    ])
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let error = parse("{\n  \"a\": 01\n}").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+        for invalid in [
+            "[1,]",
+            "{\"a\" 1}",
+            "\"\\ud800\"",
+            "tru",
+            "1 2",
+            "\"a\nb\"",
+            "-",
+        ] {
+            assert!(parse(invalid).is_err(), "{} should not parse", invalid);
+        }
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let error = parse(&"[".repeat(200_000)).unwrap_err();
+        assert_eq!((error.line, error.column), (1, MAX_DEPTH + 1));
+        let error = parse(&"{\"a\":".repeat(1000)).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 5 * MAX_DEPTH + 1));
+        let deepest = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&deepest).is_ok());
+    }
+
+    #[test]
+    fn round_trips_pretty_and_compact() {
+        let input = r#"{"a":[1,2.0,{"b":null}],"c":{},"d":"\u0001"}"#;
+        let value = parse(input).unwrap();
+        assert_eq!(
+            to_string_compact(&value),
+            r#"{"a":[1,2.0,{"b":null}],"c":{},"d":"\u0001"}"#
+        );
+        let pretty = to_string_pretty(&value);
+        assert_eq!(
+            pretty,
+            "{\n    \"a\": [\n        1,\n        2.0,\n        {\n            \"b\": null\n        }\n    ],\n    \"c\": {},\n    \"d\": \"\\u0001\"\n}\n"
+        );
+        assert_eq!(parse(&pretty).unwrap(), value);
+    }
+}
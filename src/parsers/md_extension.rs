@@ -0,0 +1,75 @@
+// Custom Markdown syntax. An extension claims trigger characters, and whenever the parser reaches
+// one it asks the extension to parse before trying the built-in rules: at the start of a block
+// for block triggers, or anywhere in inline text for inline triggers. Parsing only measures how
+// much source the syntax covers; rendering then turns that source into HTML nodes. Extensions
+// are registered on `MDOptions::register`
+
+use std::fmt;
+
+use crate::html::Node;
+
+/// Syntax a program using flux as a library adds to its Markdown, like `:smile:` emoji here
+///
+/// ```
+/// use flux::html::{Format, Node};
+/// use flux::parsers::md_extension::SyntaxExtension;
+/// use flux::parsers::md_parser::{md_string_to_html, MDOptions};
+///
+/// #[derive(Debug)]
+/// struct Emoji;
+///
+/// impl SyntaxExtension for Emoji {
+///     fn inline_triggers(&self) -> &[char] {
+///         &[':']
+///     }
+///
+///     fn parse_inline(&self, text: &str) -> Option<usize> {
+///         text.starts_with(":smile:").then_some(7)
+///     }
+///
+///     fn render_inline(&self, _source: &str) -> Vec<Node> {
+///         vec![Node::Text("😄".to_string())]
+///     }
+/// }
+///
+/// let mut options = MDOptions {
+///     format: Format::Minify,
+///     ..MDOptions::default()
+/// };
+/// options.register(Emoji);
+/// assert_eq!(md_string_to_html("Hi :smile:\n", &options), "<p>Hi 😄</p>");
+/// ```
+pub trait SyntaxExtension: fmt::Debug + Send + Sync {
+    /// Characters that can start this extension's inline syntax, like `@` for mentions
+    fn inline_triggers(&self) -> &[char] {
+        &[]
+    }
+
+    /// Match inline syntax at the start of `text`, the rest of the current line. Returns how
+    /// many chars it covers, or `None` to leave the text to the other rules
+    fn parse_inline(&self, _text: &str) -> Option<usize> {
+        None
+    }
+
+    /// Render the chars matched by `parse_inline`
+    fn render_inline(&self, source: &str) -> Vec<Node> {
+        vec![Node::Text(source.to_string())]
+    }
+
+    /// Characters that can start a block of this extension's syntax
+    fn block_triggers(&self) -> &[char] {
+        &[]
+    }
+
+    /// Match a block at the start of `text`, the rest of the document with blank lines left
+    /// out. Returns how many lines it covers, or `None` to leave them to the other rules.
+    /// Splitting `text` lazily, as `str::lines` does, keeps a match to the lines it looks at
+    fn parse_block(&self, _text: &str) -> Option<usize> {
+        None
+    }
+
+    /// Render the lines matched by `parse_block`
+    fn render_block(&self, lines: &[&str]) -> Vec<Node> {
+        vec![Node::Text(lines.join("\n"))]
+    }
+}
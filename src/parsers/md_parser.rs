@@ -1,9 +1,17 @@
-use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
-use std::io::Read;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
 
-use clap::Parser;
+use crate::diag::{self, Level};
+use crate::highlight::{self, Theme};
+use crate::html::{self, Element, Format, HtmlBuilder, Node};
+use crate::math::{self, MathOutput};
+use crate::parsers::md_extension::SyntaxExtension;
+use crate::parsers::md_renderer::{HtmlRenderer, Renderer};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 enum MarkdownState {
     BOLD,
@@ -19,86 +27,516 @@ enum MarkdownState {
     PARAGRAPH,
     STRIKETHROUGH,
     QUOTE,
+    MATH,
+    CONTAINER,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum ListType {
     ORDERED,
     UNORDERED,
-    NOTHING,
 }
 
-struct MDParser {
+#[derive(Clone, Debug)]
+pub struct MDOptions {
+    /// Emit a table of contents, either at a `[TOC]` marker or at the top of the document. A
+    /// document with no headings to list gets none, and its marker is dropped
+    pub toc: bool,
+    /// The deepest heading level included in the table of contents
+    pub toc_depth: usize,
+    /// Embed the CSS for this syntax highlighting theme in the output
+    pub highlight_theme: Option<Theme>,
+    /// How `$...$` and `$$...$$` math is rendered
+    pub math: MathOutput,
+    /// Annotate block elements with `data-sourcepos="line:col-line:col"`
+    pub sourcepos: bool,
+    /// Pretty-print or minify the HTML output
+    pub format: Format,
+    /// The optional syntax that is recognized
+    pub extensions: Extensions,
+    /// How heading ids are derived from heading text
+    pub heading_ids: HeadingIds,
+    /// Escape inline HTML and drop script links, for rendering untrusted input
+    pub safe: bool,
+    /// Custom syntax, tried in registration order before the built-in rules
+    pub syntax: Vec<Arc<dyn SyntaxExtension>>,
+    /// Turns each parsed node into its final HTML
+    pub renderer: Arc<dyn Renderer>,
+}
+
+impl MDOptions {
+    pub fn register(&mut self, extension: impl SyntaxExtension + 'static) {
+        self.syntax.push(Arc::new(extension));
+    }
+}
+
+impl Default for MDOptions {
+    fn default() -> Self {
+        MDOptions {
+            toc: false,
+            toc_depth: 6,
+            highlight_theme: None,
+            math: MathOutput::Client,
+            sourcepos: false,
+            format: Format::Pretty,
+            extensions: Extensions::default(),
+            heading_ids: HeadingIds::Github,
+            safe: false,
+            syntax: Vec::new(),
+            renderer: Arc::new(HtmlRenderer),
+        }
+    }
+}
+
+/// Syntax beyond the Markdown core, each of which can be switched off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extensions {
+    /// `> [!NOTE]` style alert quotes
+    pub alerts: bool,
+    /// `::: name` fenced containers
+    pub containers: bool,
+    /// `$...$` and `$$...$$` math
+    pub math: bool,
+    /// `~~struck~~` text
+    pub strikethrough: bool,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            alerts: true,
+            containers: true,
+            math: true,
+            strikethrough: true,
+        }
+    }
+}
+
+impl Extensions {
+    pub const NAMES: &'static [&'static str] = &["alerts", "containers", "math", "strikethrough"];
+
+    /// Enable exactly the named extensions
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Extensions, String> {
+        let mut extensions = Extensions {
+            alerts: false,
+            containers: false,
+            math: false,
+            strikethrough: false,
+        };
+        for name in names {
+            match name.as_ref() {
+                "alerts" => extensions.alerts = true,
+                "containers" => extensions.containers = true,
+                "math" => extensions.math = true,
+                "strikethrough" => extensions.strikethrough = true,
+                other => {
+                    return Err(format!(
+                        "Unknown extension '{}', expected one of {}",
+                        other,
+                        Extensions::NAMES.join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(extensions)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let enabled = [self.alerts, self.containers, self.math, self.strikethrough];
+        Extensions::NAMES
+            .iter()
+            .zip(enabled)
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeadingIds {
+    /// GitHub's anchors, which keep non-ASCII letters
+    Github,
+    /// GitHub's anchors folded to ASCII, with accented Latin letters reduced to their base
+    /// letter and other characters dropped
+    Ascii,
+    /// No ids, unless a table of contents needs them to link to
+    None,
+}
+
+impl HeadingIds {
+    pub fn from_name(name: &str) -> Result<HeadingIds, &'static str> {
+        match name {
+            "github" => Ok(HeadingIds::Github),
+            "ascii" => Ok(HeadingIds::Ascii),
+            "none" => Ok(HeadingIds::None),
+            _ => Err("Unknown heading id style, expected 'github', 'ascii' or 'none'"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeadingIds::Github => "github",
+            HeadingIds::Ascii => "ascii",
+            HeadingIds::None => "none",
+        }
+    }
+}
+
+/// Something in the source that is probably a mistake, at a 1-based line and column
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+struct Heading {
+    level: usize,
+    id: String,
     text: String,
-    html: String,
+}
+
+// A list containing the line being parsed, whose last item is still open
+struct OpenList {
+    list_type: ListType,
+    indent: usize,
+    // Where its first marker is, for data-sourcepos
+    start: usize,
+}
+
+// Alerts nested deeper than this render as plain quotes, since each level copies its body
+const MAX_ALERT_DEPTH: usize = 32;
+
+// A container or alert whose body is still being parsed
+enum OpenBlock {
+    // Parsed in place up to its closing fence, with the states around it saved
+    Container {
+        name: String,
+        start: usize,
+        states: Vec<MarkdownState>,
+    },
+    // Parsed as a text of its own, with the text around it saved
+    Alert {
+        title: &'static str,
+        outer: Box<SavedText>,
+    },
+}
+
+// The text being parsed and where the parser is in it
+struct SavedText {
+    text: Vec<char>,
+    source: String,
+    line_bytes: Vec<usize>,
+    length: usize,
+    index: usize,
+    states: Vec<MarkdownState>,
+    line_starts: Vec<usize>,
+    line_origins: Vec<(usize, usize)>,
+}
+
+struct MDParser {
+    options: MDOptions,
+    // Indexed by char rather than byte, so multi-byte input never splits a char
+    text: Vec<char>,
+    // The same text as a string, which block extensions borrow, and the byte offset of each
+    // line in it
+    source: String,
+    line_bytes: Vec<usize>,
+    out: HtmlBuilder,
     length: usize,
     index: usize,
-    list_level: Option<usize>,
-    max_list_level: usize,
-    curr_list_type: ListType,
-    indent_to_list_level: BTreeMap<usize, usize>,
-    indentation_level: usize,
     states: Vec<MarkdownState>,
+    headings: Vec<Heading>,
+    heading_ids: HashSet<String>,
+    toc_marker: bool,
+    // Char offset where each line of `text` starts, and the (line, column offset) in the
+    // original file it came from, since preprocessing drops blank lines
+    line_starts: Vec<usize>,
+    line_origins: Vec<(usize, usize)>,
+    last_item_end: usize,
+    // Targets of `[label]: url` definitions, keyed by lowercased label
+    references: BTreeMap<String, String>,
+    warnings: Vec<Warning>,
 }
 
 impl MDParser {
-    fn md_init_parser(mut md_file: File) -> Self {
-        let mut text = String::new();
-        let html = String::new();
-        md_file.read_to_string(&mut text).expect("Read Error");
-        text = MDParser::preprocess_md(text);
-        let length = text.len();
-        let mut list_map = BTreeMap::new();
-        list_map.insert(0, 1);
-        MDParser {
-            text,
-            html,
-            length,
+    fn md_init_parser(contents: &str, options: MDOptions) -> Self {
+        let mut parser = MDParser {
+            options,
+            text: Vec::new(),
+            source: String::new(),
+            line_bytes: Vec::new(),
+            out: HtmlBuilder::default(),
+            length: 0,
             index: 0,
-            list_level: Some(1),
-            max_list_level: 1,
-            curr_list_type: ListType::NOTHING,
-            indent_to_list_level: list_map,
-            indentation_level: 0,
             states: vec![MarkdownState::TEXT],
+            headings: Vec::new(),
+            heading_ids: HashSet::new(),
+            toc_marker: false,
+            line_starts: Vec::new(),
+            line_origins: Vec::new(),
+            last_item_end: 0,
+            references: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+        parser.load(contents, 0, false);
+        parser
+    }
+
+    // Start parsing `contents`, whose first line is the one after `first_line` in the file, and
+    // which `more` text follows. Headings, references and warnings carry over from whatever was
+    // parsed before
+    fn load(&mut self, contents: &str, first_line: usize, more: bool) {
+        let (text, line_origins, references) =
+            MDParser::preprocess_md(contents.to_string(), first_line);
+        self.line_starts = MDParser::find_line_starts(&text);
+        self.line_bytes = MDParser::find_line_bytes(&text);
+        let mut text = text;
+        // The last line keeps its newline, which a hard line break needs
+        if more && !text.is_empty() {
+            text.push('\n');
         }
+        self.text = text.chars().collect();
+        self.source = text;
+        self.length = self.text.len();
+        self.index = 0;
+        self.states = vec![MarkdownState::TEXT];
+        self.line_origins = line_origins;
+        for (label, url) in references {
+            self.references.entry(label).or_insert(url);
+        }
+    }
+
+    // Parse `md` as the next piece of a stream and write out its HTML
+    fn write_block(
+        &mut self,
+        md: &str,
+        first_line: usize,
+        more: bool,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        self.load(md, first_line, more);
+        self.parse_blocks();
+        let nodes = std::mem::take(&mut self.out).finish();
+        output.write_all(html::serialize(&nodes, self.options.format).as_bytes())
     }
 
-    fn preprocess_md(mut md_contents: String) -> String {
+    // Drop blank lines and pull out reference link definitions
+    fn preprocess_md(
+        mut md_contents: String,
+        first_line: usize,
+    ) -> (String, Vec<(usize, usize)>, BTreeMap<String, String>) {
         let lines = md_contents.lines();
         let mut new_lines: Vec<&str> = Vec::new();
+        let mut line_origins = Vec::new();
+        let mut references = BTreeMap::new();
         let mut in_code_block = false;
-        for line in lines {
+        for (line_number, line) in lines.enumerate() {
             if line.trim().is_empty() {
                 continue;
             }
             if line.starts_with("```") {
                 in_code_block = !in_code_block;
             }
+            if !in_code_block {
+                if let Some((label, url)) = reference_definition(line) {
+                    references.entry(label).or_insert(url);
+                    continue;
+                }
+            }
             new_lines.push(line);
+            line_origins.push((first_line + line_number + 1, 0));
         }
         md_contents = new_lines.into_iter().collect::<Vec<&str>>().join("\n");
-        md_contents
+        (md_contents, line_origins, references)
+    }
+
+    fn find_line_starts(text: &str) -> Vec<usize> {
+        let mut line_starts = vec![0];
+        for (i, c) in text.chars().enumerate() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        line_starts
+    }
+
+    fn find_line_bytes(text: &str) -> Vec<usize> {
+        let mut line_bytes = vec![0];
+        line_bytes.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        line_bytes
+    }
+
+    // Line and column (both 1-based) in the original file of the char at `index`
+    fn source_position(&self, index: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&index) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let (origin_line, column_offset) = self.line_origins.get(line).copied().unwrap_or((1, 0));
+        (
+            origin_line,
+            index - self.line_starts[line] + column_offset + 1,
+        )
+    }
+
+    // The last non-whitespace char consumed since `start`
+    fn consumed_end(&self, start: usize) -> usize {
+        let mut end = self.index.min(self.length);
+        while end > start + 1 && self.get_ith_char(end - 1).is_none_or(|c| c.is_whitespace()) {
+            end -= 1;
+        }
+        end.saturating_sub(1).max(start)
+    }
+
+    fn sourcepos(&self, start: usize, end: usize) -> Option<String> {
+        if !self.options.sourcepos {
+            return None;
+        }
+        let (start_line, start_col) = self.source_position(start);
+        let (end_line, end_col) = self.source_position(end);
+        Some(format!(
+            "{}:{}-{}:{}",
+            start_line, start_col, end_line, end_col
+        ))
+    }
+
+    // Add data-sourcepos to the innermost open element
+    fn annotate_block(&mut self, start: usize, end: usize) {
+        if let Some(sourcepos) = self.sourcepos(start, end) {
+            self.out.attr("data-sourcepos", &sourcepos);
+        }
     }
 
     fn handle_header(&mut self) {
+        let start = self.index;
         let mut level = 0;
-        let mut char = self.get_ith_char(self.index).unwrap();
         while self.check_next_chars(self.index, "#") {
             level += 1;
             self.index += 1;
-            char = self.get_ith_char(self.index).unwrap();
         }
         // Skip whitespace following hashtags if present
-        if char == ' ' {
+        if self.check_next_chars(self.index, " ") {
             self.index += 1;
         }
+        if let Some(previous) = self.headings.last().map(|h| h.level) {
+            if level > previous + 1 {
+                self.warn(
+                    start,
+                    format!("heading level jumps from h{} to h{}", previous, level),
+                );
+            }
+        }
         self.push_state(MarkdownState::HEADER);
-        self.html.push_str(&format!("<h{}>", level));
+        self.out.open(&format!("h{}", level));
+        // Render the heading contents first so the id can be derived from its text
         self.parse_inline();
-        self.html.push_str(&format!("</h{}>", level));
+        let text = self.out.current().text_content();
+        let slug = match self.options.heading_ids {
+            HeadingIds::Ascii => fold_to_ascii(&slugify(&text)),
+            _ => slugify(&text),
+        };
+        // Headings of only punctuation or markup still need an id to link to
+        let slug = if slug.is_empty() {
+            "section".to_string()
+        } else {
+            slug
+        };
+        let id = self.unique_heading_id(&slug);
+        if self.options.heading_ids != HeadingIds::None || self.options.toc {
+            self.out.attr("id", &id);
+        }
+        self.annotate_block(start, self.consumed_end(start));
+        self.close();
+        self.headings.push(Heading {
+            level,
+            id,
+            text: text.trim().to_string(),
+        });
         self.pop_state();
-        self.optionally_push_newline();
+    }
+
+    // Deduplicate slugs GitHub-style by appending -1, -2, ... to repeated ids
+    fn unique_heading_id(&mut self, slug: &str) -> String {
+        let mut id = slug.to_string();
+        let mut suffix = 0;
+        while self.heading_ids.contains(&id) {
+            suffix += 1;
+            id = format!("{}-{}", slug, suffix);
+        }
+        self.heading_ids.insert(id.clone());
+        id
+    }
+
+    fn handle_toc_marker(&mut self) {
+        self.index += "[TOC]".len();
+        // Leave an empty nav to be filled in once every heading has been seen
+        if self.options.toc && !self.toc_marker {
+            self.toc_marker = true;
+            self.out
+                .push(Element::new("nav").with_attr("class", "toc").into());
+        }
+    }
+
+    fn is_toc_marker(&self, index: usize) -> bool {
+        self.check_next_chars(index, "[TOC]")
+            && matches!(self.get_ith_char(index + 5), None | Some('\n'))
+    }
+
+    // None when no heading is shallow enough to be listed
+    fn render_toc(&self) -> Option<Element> {
+        let mut toc = HtmlBuilder::default();
+        // Levels of the lists that are currently open, innermost last
+        let mut open_levels: Vec<usize> = Vec::new();
+        for heading in self
+            .headings
+            .iter()
+            .filter(|h| h.level <= self.options.toc_depth)
+        {
+            match open_levels.last() {
+                Some(&top) if heading.level <= top => {
+                    toc.close();
+                    while let [.., parent, top] = open_levels[..] {
+                        if top <= heading.level {
+                            break;
+                        }
+                        if parent < heading.level {
+                            // A heading between two levels joins the deeper list
+                            *open_levels.last_mut().unwrap() = heading.level;
+                            break;
+                        }
+                        // Close the nested list and the item that holds it
+                        toc.close();
+                        toc.close();
+                        open_levels.pop();
+                    }
+                    // The outermost list takes the shallowest level seen so far, so later
+                    // deeper headings nest under this one
+                    if let [top] = &mut open_levels[..] {
+                        *top = (*top).min(heading.level);
+                    }
+                }
+                _ => {
+                    toc.open("ul");
+                    open_levels.push(heading.level);
+                }
+            }
+            toc.open("li");
+            toc.open("a");
+            toc.attr("href", &format!("#{}", heading.id));
+            toc.text(&heading.text);
+            toc.close();
+        }
+        let mut nav = Element::new("nav").with_attr("class", "toc");
+        nav.children = toc.finish();
+        (!nav.children.is_empty()).then_some(nav)
     }
 
     fn handle_italic(&mut self, state: MarkdownState) {
@@ -108,11 +546,12 @@ impl MDParser {
                 self.pop_state();
             }
             _ => {
+                let start = self.index - 1;
                 self.push_state(MarkdownState::ITALIC);
-                self.html.push_str("<em>");
+                self.out.open("em");
                 self.parse_inline();
-                self.html.push_str("</em>");
-                self.optionally_push_newline();
+                self.close();
+                self.end_unclosed(MarkdownState::ITALIC, start, "*");
             }
         }
     }
@@ -124,11 +563,12 @@ impl MDParser {
                 self.pop_state();
             }
             _ => {
+                let start = self.index - 2;
                 self.push_state(MarkdownState::BOLD);
-                self.html.push_str("<strong>");
+                self.out.open("strong");
                 self.parse_inline();
-                self.html.push_str("</strong>");
-                self.optionally_push_newline();
+                self.close();
+                self.end_unclosed(MarkdownState::BOLD, start, "**");
             }
         }
     }
@@ -140,11 +580,14 @@ impl MDParser {
                 self.pop_state();
             }
             _ => {
+                let start = self.index - 3;
                 self.push_state(MarkdownState::BoldAndItalic);
-                self.html.push_str("<em><strong>");
+                self.out.open("em");
+                self.out.open("strong");
                 self.parse_inline();
-                self.html.push_str("</em></strong>");
-                self.optionally_push_newline();
+                self.close();
+                self.close();
+                self.end_unclosed(MarkdownState::BoldAndItalic, start, "***");
             }
         }
     }
@@ -177,194 +620,467 @@ impl MDParser {
         }
     }
 
-    fn handle_link(&mut self) {
-        self.push_state(MarkdownState::LINK);
+    // Close the innermost element through the renderer hook for its node type
+    fn close(&mut self) {
+        let renderer = &self.options.renderer;
+        let tag = self.out.current().tag.clone();
+        match tag.as_str() {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.out.close_with(|element| {
+                let level = tag[1..].parse().unwrap_or(1);
+                let id = element.attr("id").map(str::to_string);
+                renderer.heading(level, id.as_deref(), element)
+            }),
+            "p" => self.out.close_with(|element| renderer.paragraph(element)),
+            "em" => self.out.close_with(|element| renderer.emphasis(element)),
+            "strong" => self.out.close_with(|element| renderer.strong(element)),
+            "s" => self
+                .out
+                .close_with(|element| renderer.strikethrough(element)),
+            "a" => self.out.close_with(|element| {
+                let href = element.attr("href").unwrap_or_default().to_string();
+                renderer.link(&href, element)
+            }),
+            "ul" | "ol" => self
+                .out
+                .close_with(|element| renderer.list(tag == "ol", element)),
+            "li" => self.out.close_with(|element| renderer.list_item(element)),
+            "blockquote" => self.out.close_with(|element| renderer.blockquote(element)),
+            _ => self.out.close(),
+        }
+    }
+
+    // The text and target of `[text](target)`, with the index just past it
+    fn take_link_parts(&mut self) -> (String, String) {
         self.index += 1;
         let mut link_text = "".to_string();
-        while self.index < self.length && self.get_ith_char(self.index).unwrap() != ']' {
-            link_text.push(self.get_ith_char(self.index).unwrap());
+        while let Some(c) = self.get_ith_char(self.index).filter(|&c| c != ']') {
+            link_text.push(c);
             self.index += 1;
         }
         self.index += 2; // Skip ']('
         let mut link_url = "".to_string();
-        while self.index < self.length && self.get_ith_char(self.index).unwrap() != ')' {
-            link_url.push(self.get_ith_char(self.index).unwrap());
+        while let Some(c) = self
+            .get_ith_char(self.index)
+            .filter(|&c| c != ')' && c != '\n')
+        {
+            link_url.push(c);
             self.index += 1;
         }
-        self.index += 1;
-        self.html
-            .push_str(&format!("<a href={}>{}</a>", link_url, link_text));
+        if self.check_next_chars(self.index, ")") {
+            self.index += 1;
+        }
+        (link_text, link_url)
+    }
+
+    // `[text][label]` or `[label][]`, resolved against the document's definitions. Returns
+    // false when the brackets are not a link at all, leaving them as text
+    fn handle_reference_link(&mut self) -> bool {
+        let start = self.index;
+        let Some(text_end) = self.find_closing(start + 1, "]", true) else {
+            return false;
+        };
+        if !self.check_next_chars(text_end + 1, "[") {
+            return false;
+        }
+        let Some(label_end) = self.find_closing(text_end + 2, "]", true) else {
+            return false;
+        };
+        let link_text = self.slice(start + 1, text_end);
+        let label = match self.slice(text_end + 2, label_end) {
+            label if label.is_empty() => link_text.clone(),
+            label => label,
+        };
+        self.index = label_end + 1;
+        match self.references.get(&label.to_lowercase()).cloned() {
+            Some(url) => self.push_link(&link_text, &url),
+            None => {
+                self.warn(start, format!("unresolved reference link '[{}]'", label));
+                let source = self.slice(start, label_end + 1);
+                self.push_text(&source);
+            }
+        }
+        true
+    }
+
+    fn handle_link(&mut self) {
+        if !self.check_link_target() {
+            if !self.handle_reference_link() {
+                self.push_text("[");
+                self.index += 1;
+            }
+            return;
+        }
+        let (link_text, link_url) = self.take_link_parts();
+        self.push_link(&link_text, &link_url);
+    }
+
+    // Whether the `[` at the index starts `[text](target)` on this line
+    fn check_link_target(&self) -> bool {
+        self.find_closing(self.index + 1, "]", true)
+            .is_some_and(|end| self.check_next_chars(end + 1, "("))
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if self.options.safe {
+            self.out.text(text);
+        } else {
+            self.out.raw(text);
+        }
+    }
+
+    fn push_link(&mut self, link_text: &str, link_url: &str) {
+        self.push_state(MarkdownState::LINK);
+        self.out.open("a");
+        if !(self.options.safe && is_script_url(link_url)) {
+            self.out.attr("href", link_url);
+        }
+        self.push_text(link_text);
+        self.close();
         self.pop_state();
-        self.optionally_push_newline();
+    }
+
+    // `![alt](src)`
+    fn handle_image(&mut self) {
+        self.index += 1;
+        if !self.check_link_target() {
+            self.push_text("!");
+            return;
+        }
+        let (alt, src) = self.take_link_parts();
+        let src = if self.options.safe && is_script_url(&src) {
+            String::new()
+        } else {
+            src
+        };
+        let element = Element::new("img")
+            .with_attr("src", &src)
+            .with_attr("alt", &alt);
+        let node = self.options.renderer.image(&src, &alt, element);
+        self.out.push(node);
     }
 
     fn handle_code(&mut self) {
         if self.check_next_chars(self.index, "```") {
+            let start = self.index;
             self.index += 3;
             let state = self.get_current_state();
             match state {
                 MarkdownState::TEXT => {
                     self.push_state(MarkdownState::CodeBlock);
+                    // The info string after the opening fence selects the highlighter
+                    let mut info = "".to_string();
+                    while let Some(c) = self.get_ith_char(self.index).filter(|&c| c != '\n') {
+                        info.push(c);
+                        self.index += 1;
+                    }
+                    self.index += 1;
+                    let language = info.split_whitespace().next();
                     let mut code_block = "".to_string();
-                    while self.index < self.length
-                        && &self.text[self.index..=self.index + 2] != "```"
+                    while let Some(c) = self
+                        .get_ith_char(self.index)
+                        .filter(|_| !self.check_next_chars(self.index, "```"))
                     {
-                        code_block.push(self.get_ith_char(self.index).unwrap());
+                        code_block.push(c);
                         self.index += 1;
                     }
-                    self.index += 3;
-                    self.html
-                        .push_str(&format!("<pre><code>{}</code></pre>", code_block));
+                    if self.index >= self.length {
+                        self.warn(start, "unclosed code fence".to_string());
+                    }
+                    self.index = (self.index + 3).min(self.length);
+                    self.out.open("pre");
+                    self.annotate_block(start, self.consumed_end(start));
+                    self.out.open("code");
+                    if let Some(lang) = language {
+                        self.out.attr("class", &format!("language-{}", lang));
+                    }
+                    self.out.raw(&highlight::highlight(&code_block, language));
+                    self.out.close();
+                    let renderer = &self.options.renderer;
+                    self.out
+                        .close_with(|element| renderer.code_block(language, &code_block, element));
                     self.pop_state();
-                    self.optionally_push_newline();
                 }
                 _ => {
-                    self.html.push_str("```");
+                    self.out.raw("```");
                 }
             }
+        } else if self.find_closing(self.index + 1, "`", true).is_none() {
+            self.warn(self.index, "unclosed code span".to_string());
+            self.out.raw("`");
+            self.index += 1;
         } else if self.check_next_chars(self.index, "`") {
             self.push_state(MarkdownState::InlineCode);
             self.index += 1;
             let mut code_text = "".to_string();
-            while !self.check_next_chars(self.index, "`") {
-                code_text.push(self.get_ith_char(self.index).unwrap());
+            while let Some(c) = self.get_ith_char(self.index).filter(|&c| c != '`') {
+                code_text.push(c);
                 self.index += 1;
             }
             self.index += 1;
-            self.html.push_str(&format!("<code>{}</code>", code_text));
+            self.out.open("code");
+            self.out.text(&code_text);
+            let renderer = &self.options.renderer;
+            self.out
+                .close_with(|element| renderer.inline_code(&code_text, element));
             self.pop_state();
-            self.optionally_push_newline();
-        }
-    }
-
-    fn get_list_level_from_indent(&mut self, indent_level: usize) -> (usize, usize) {
-        match self.indent_to_list_level.get(&indent_level) {
-            Some(&x) => (indent_level, x.clone()),
-            None => {
-                // If there exists a higher index level we've already dealt with, then
-                // we try to find the closest indent level that is less than
-                // the current indent level
-                // if nothing is less than it, then we add a new level
-
-                let mut higher_indent = self.indent_to_list_level.range(indent_level + 1..);
-                if higher_indent.next().is_some() {
-                    let closest_key = self.indent_to_list_level.range(..indent_level).next_back();
-                    match closest_key {
-                        Some((&k, &v)) => (k.clone(), v.clone()),
-                        None => {
-                            panic!("Curr indent is not highest, but nothing lower exists");
-                        }
-                    }
-                } else {
-                    self.indent_to_list_level
-                        .insert(indent_level, self.max_list_level);
-                    (indent_level, self.max_list_level)
-                }
-            }
-        }
-    }
-
-    // Each of the bottom two handle functions will record what list level they were associated
-    // with. If our list level is valid (i.e. the next line contains a list) and equivalent to theirs, they know to keep going. Every
-    // time our indentation level is lower than it was previously, we will decrease the size of the
-    // callstack until we get to a function that was called to handle that level
-    fn handle_list_items(&mut self) {
-        let list_type_snapshot = self.curr_list_type;
-        println!("List Level: {}", self.list_level.unwrap());
-        println!("Curr_indent: {}", self.indentation_level);
-        let spaces = " ".repeat(self.list_level.unwrap() * 4);
-        self.html.push_str(&format!("{}<li>", spaces));
-        self.parse_inline();
-        self.html.push_str("</li>\n");
-        self.list_level = None;
-        let dist_to_char = self.find_distance_to_non_whitespace(self.index);
-        let indent_difference: i32 = dist_to_char as i32 - self.indentation_level as i32;
-        if indent_difference > 8 {
-            return;
-        }
-        // If the curr indent level exists in the map and there is a swap in list type, then return
-        // But that doesn't mean we stop parsing at this indent level
-        let curr_char = match self.get_ith_char(self.index + dist_to_char) {
-            Some(x) => x,
-            None => ' ',
-        };
-        // Find the appropriate list level based off of the indentation level
-        let (curr_indent, curr_list_level) = self.get_list_level_from_indent(dist_to_char);
-        let current_state: MarkdownState = self.get_current_state();
-        if curr_char == '-' {
-            self.curr_list_type = ListType::UNORDERED;
-            self.list_level = Some(curr_list_level);
-            self.index += dist_to_char;
-            self.indentation_level = curr_indent;
-            if indent_difference < 0
-                || (list_type_snapshot != self.curr_list_type && indent_difference == 0)
-            {
-                return;
-            }
-            if current_state == MarkdownState::UnorderedList && indent_difference == 0 {
-                self.index += 1;
-                self.handle_list_items();
-            } else {
-                self.handle_list(ListType::UNORDERED);
-            }
-        } else if curr_char.is_digit(10)
-            && self.check_next_chars(self.index + dist_to_char + 1, ".")
-        {
-            self.curr_list_type = ListType::ORDERED;
-            self.list_level = Some(curr_list_level);
-            self.index += dist_to_char + 1;
-            self.indentation_level = curr_indent;
-            // If we are dealing with a lower level or the same level with a different type
-            if indent_difference < 0
-                || (list_type_snapshot != self.curr_list_type && indent_difference == 0)
-            {
-                return;
-            }
-            if current_state == MarkdownState::OrderedList && indent_difference == 0 {
-                self.index += 2;
-                self.handle_list_items();
-            } else {
-                self.handle_list(ListType::ORDERED);
-            }
         }
     }
 
     fn handle_paragraph(&mut self) {
+        let start = self.index;
         self.push_state(MarkdownState::PARAGRAPH);
-        self.html.push_str("<p>");
+        self.out.open("p");
         while self.index < self.length {
             // Use parse inline to parse till the end of the line and then do a check if there is a
             // tag immediately following a newline
             self.parse_inline();
-            let char = match self.get_ith_char(self.index) {
-                Some(x) => x,
-                None => break,
-            };
-            if "#>-".contains(char)
-                || self.check_next_chars(self.index, "```")
-                || (char.is_digit(10) && self.get_ith_char(self.index + 1).unwrap() == '.')
-            {
+            if self.breaks_paragraph(self.index) {
                 break;
             }
         }
-        self.html.push_str("</p>\n");
+        self.annotate_block(start, self.consumed_end(start));
+        self.close();
         self.pop_state();
     }
 
+    // Whether the line starting at `index` begins a block of its own rather than continuing a
+    // paragraph
+    fn breaks_paragraph(&self, index: usize) -> bool {
+        let Some(char) = self.get_ith_char(index) else {
+            return true;
+        };
+        "#>-".contains(char)
+            || self.is_toc_marker(index)
+            || self.check_next_chars(index, "```")
+            || (self.options.extensions.math && self.check_next_chars(index, "$$"))
+            || (self.options.extensions.containers && self.check_next_chars(index, ":::"))
+            || (char.is_ascii_digit() && self.check_next_chars(index + 1, "."))
+            || self.match_block_extension(index).is_some()
+    }
+
+    // Where running text at `index` has to end: with its paragraph, or elsewhere its line
+    fn inline_end(&self, index: usize) -> usize {
+        let paragraph = self.get_current_state() == MarkdownState::PARAGRAPH;
+        let mut end = index;
+        loop {
+            while self.get_ith_char(end).is_some_and(|c| c != '\n') {
+                end += 1;
+            }
+            if !paragraph || end >= self.length || self.breaks_paragraph(end + 1) {
+                return end;
+            }
+            end += 1;
+        }
+    }
+
     fn handle_quotes(&mut self) {
+        let start = self.index;
         self.push_state(MarkdownState::QUOTE);
-        self.html.push_str("<quoteblock>\n");
+        self.out.open("blockquote");
         while self.check_next_chars(self.index, ">") {
             self.index += 1;
             self.parse_inline();
-            self.html.push('\n');
+            self.out.raw("\n");
+        }
+        self.annotate_block(start, self.consumed_end(start));
+        self.close();
+        self.pop_state();
+    }
+
+    fn alert_kind(line: &str) -> Option<&'static str> {
+        let marker = line.strip_prefix('>')?.trim();
+        match marker.strip_prefix("[!")?.strip_suffix(']')? {
+            "NOTE" => Some("Note"),
+            "TIP" => Some("Tip"),
+            "IMPORTANT" => Some("Important"),
+            "WARNING" => Some("Warning"),
+            "CAUTION" => Some("Caution"),
+            _ => None,
+        }
+    }
+
+    fn is_alert(&self) -> bool {
+        MDParser::alert_kind(&self.peek_line()).is_some()
+    }
+
+    // GitHub alerts are quotes whose first line is a `[!KIND]` marker, e.g. `> [!NOTE]`. The
+    // body, lifted out of its `>` markers, becomes the text parsed until the alert closes
+    fn open_alert(&mut self, open: &mut Vec<OpenBlock>) {
+        let start = self.index;
+        let title = MDParser::alert_kind(&self.take_line()).unwrap();
+        let mut body = String::new();
+        let mut origins = Vec::new();
+        while self.check_next_chars(self.index, ">") {
+            let (line_number, column) = self.source_position(self.index);
+            let line = self.take_line();
+            let line = &line[1..];
+            let content = line.strip_prefix(' ').unwrap_or(line);
+            origins.push((line_number, column + line.len() - content.len()));
+            body.push_str(content);
+            body.push('\n');
+        }
+        let end = self.consumed_end(start);
+        self.push_state(MarkdownState::QUOTE);
+        self.out.open("div");
+        self.out.attr(
+            "class",
+            &format!("markdown-alert markdown-alert-{}", title.to_lowercase()),
+        );
+        self.annotate_block(start, end);
+        self.out.open("p");
+        self.out.attr("class", "markdown-alert-title");
+        self.out.text(title);
+        self.close();
+        let outer = self.enter_text(body, origins);
+        open.push(OpenBlock::Alert { title, outer });
+    }
+
+    // `::: name` opens a container rendered as <div class="name">, closed by a bare `:::`.
+    // Containers nest, and their bodies are parsed in place, so fences inside code blocks are
+    // never seen. A bare `:::` with no container open in this text opens one without a class
+    fn handle_container_fence(&mut self, open: &mut Vec<OpenBlock>) {
+        let start = self.index;
+        let line = self.take_line();
+        let name = line.trim_start_matches(':').trim().to_string();
+        if name.is_empty() && matches!(open.last(), Some(OpenBlock::Container { .. })) {
+            let container = open.pop().unwrap();
+            self.close_block(container);
+            return;
+        }
+        self.push_state(MarkdownState::CONTAINER);
+        self.out.open("div");
+        if let Some(class) = name.split_whitespace().next() {
+            self.out.attr("class", class);
+        }
+        let states = std::mem::replace(&mut self.states, vec![MarkdownState::TEXT]);
+        open.push(OpenBlock::Container {
+            name,
+            start,
+            states,
+        });
+    }
+
+    fn close_block(&mut self, block: OpenBlock) {
+        match block {
+            OpenBlock::Container {
+                name,
+                start,
+                states,
+            } => {
+                self.states = states;
+                self.annotate_block(start, self.consumed_end(start));
+                let renderer = &self.options.renderer;
+                self.out
+                    .close_with(|element| renderer.container(&name, element));
+            }
+            OpenBlock::Alert { title, outer } => {
+                self.swap_text(*outer);
+                let renderer = &self.options.renderer;
+                self.out
+                    .close_with(|element| renderer.alert(&title.to_lowercase(), element));
+            }
         }
-        self.html.push_str("</quoteblock>\n");
         self.pop_state();
     }
 
+    fn peek_line(&self) -> String {
+        let mut line = String::new();
+        let mut index = self.index;
+        while let Some(c) = self.get_ith_char(index).filter(|&c| c != '\n') {
+            line.push(c);
+            index += 1;
+        }
+        line
+    }
+
+    fn take_line(&mut self) -> String {
+        let line = self.peek_line();
+        self.index = (self.index + line.chars().count() + 1).min(self.length);
+        line
+    }
+
+    fn handle_display_math(&mut self) {
+        match self.find_closing(self.index + 2, "$$", false) {
+            Some(end) => {
+                let start = self.index;
+                self.push_state(MarkdownState::MATH);
+                let tex = self.slice(self.index + 2, end).trim().to_string();
+                self.index = end + 2;
+                let mut element = math::render_math(&tex, true, self.options.math);
+                if let Some(sourcepos) = self.sourcepos(start, end + 1) {
+                    element.set_attr("data-sourcepos", &sourcepos);
+                }
+                self.push_math(&tex, true, element);
+                self.pop_state();
+            }
+            None => self.handle_paragraph(),
+        }
+    }
+
+    fn handle_math(&mut self) {
+        if self.check_next_chars(self.index, "$$") {
+            // Unlike a block of display math, math in running text can't close past its paragraph
+            let limit = self.inline_end(self.index);
+            let closing = (self.index + 2..limit).find(|&end| self.check_next_chars(end, "$$"));
+            if let Some(end) = closing {
+                let tex = self.slice(self.index + 2, end).trim().to_string();
+                self.index = end + 2;
+                // A div can't sit inside a paragraph, so display math in running text is a span
+                let mut element = math::render_math(&tex, true, self.options.math);
+                element.tag = "span".to_string();
+                self.push_math(&tex, true, element);
+                return;
+            }
+        } else if self
+            .get_ith_char(self.index + 1)
+            .is_some_and(|c| !c.is_whitespace())
+        {
+            // Pandoc's rules keep prices like $5 and $10 from turning into math: the closing $
+            // must follow a non-space character and must not be followed by a digit
+            let mut search = self.index + 1;
+            while let Some(end) = self.find_closing(search, "$", true) {
+                let before = self.get_ith_char(end - 1).unwrap();
+                let after = self.get_ith_char(end + 1);
+                if end > self.index + 1
+                    && !before.is_whitespace()
+                    && before != '\\'
+                    && !after.is_some_and(|c| c.is_ascii_digit())
+                {
+                    let tex = self.slice(self.index + 1, end);
+                    self.index = end + 1;
+                    let element = math::render_math(&tex, false, self.options.math);
+                    self.push_math(&tex, false, element);
+                    return;
+                }
+                search = end + 1;
+            }
+        }
+        self.out.raw("$");
+        self.index += 1;
+    }
+
+    fn push_math(&mut self, tex: &str, display: bool, element: Element) {
+        let node = self.options.renderer.math(tex, display, element);
+        self.out.push(node);
+    }
+
+    fn find_closing(&self, mut index: usize, delimiter: &str, same_line: bool) -> Option<usize> {
+        while index < self.length {
+            if self.check_next_chars(index, delimiter) {
+                return Some(index);
+            }
+            if same_line && self.check_next_chars(index, "\n") {
+                return None;
+            }
+            index += 1;
+        }
+        None
+    }
+
     fn handle_strikethrough(&mut self) {
         if !self.check_next_chars(self.index, "~~") {
+            self.out.raw("~");
+            self.index += 1;
             return;
         }
         self.index += 2;
@@ -374,11 +1090,12 @@ impl MDParser {
                 self.pop_state();
             }
             _ => {
+                let start = self.index - 2;
                 self.push_state(MarkdownState::STRIKETHROUGH);
-                self.html.push_str("<s>");
+                self.out.open("s");
                 self.parse_inline();
-                self.html.push_str("</s>");
-                self.optionally_push_newline();
+                self.close();
+                self.end_unclosed(MarkdownState::STRIKETHROUGH, start, "~~");
             }
         }
     }
@@ -387,46 +1104,73 @@ impl MDParser {
         // We keep track of the length of the stack, if it changes, meaning we have fulfilled the
         // purpose of this inline, we break;
         let stack_size: usize = self.states.len();
+        let start = self.index;
         while self.index < self.length {
             let new_stack_size = self.states.len();
             if new_stack_size != stack_size {
                 break;
             }
-            let char = self.get_ith_char(self.index).unwrap();
+            // A nested span left unclosed has already taken the newline, and ends this one too
+            if self.index > start && self.get_ith_char(self.index - 1) == Some('\n') {
+                break;
+            }
+            let Some(char) = self.get_ith_char(self.index) else {
+                break;
+            };
             if char == '\n' {
                 self.index += 1;
                 break;
             }
+            if self.handle_inline_extension(char) {
+                continue;
+            }
 
             match char {
                 '*' => {
                     self.handle_asterisks_inline();
                 }
+                '!' if self.check_next_chars(self.index, "![") => {
+                    self.handle_image();
+                }
                 '[' => {
                     self.handle_link();
                 }
                 '`' => {
                     self.handle_code();
                 }
-                '~' => {
+                '~' if self.options.extensions.strikethrough => {
                     self.handle_strikethrough();
                 }
-                ' ' if self.get_current_state() == MarkdownState::PARAGRAPH
+                '$' if self.options.extensions.math => {
+                    self.handle_math();
+                }
+                '\\' if self.options.extensions.math
+                    && self.check_next_chars(self.index, "\\$") =>
+                {
+                    self.out.raw("$");
+                    self.index += 2;
+                }
+                ' ' if self.get_current_state() == MarkdownState::PARAGRAPH
                     && self.check_next_chars(self.index, "  \n") =>
                 {
-                    self.html.push_str("<br>\n");
+                    self.out.open("br");
+                    self.close();
                     self.index += 3;
                     break;
                 }
+                '<' | '>' if self.options.safe => {
+                    self.out.text(char.encode_utf8(&mut [0; 4]));
+                    self.index += 1;
+                }
                 _ => {
-                    self.html.push(char);
+                    self.out.raw(char.encode_utf8(&mut [0; 4]));
                     self.index += 1;
                 }
             }
         }
     }
 
-    fn find_distance_to_non_whitespace(&mut self, mut start_index: usize) -> usize {
+    fn find_distance_to_non_whitespace(&self, mut start_index: usize) -> usize {
         let mut indentation: usize = 0;
         while let Some(x) = self.get_ith_char(start_index) {
             match x {
@@ -439,13 +1183,17 @@ impl MDParser {
     }
 
     fn get_ith_char(&self, index: usize) -> Option<char> {
-        if index >= self.length {
-            return None;
-        }
-        self.text.chars().nth(index)
+        self.text.get(index).copied()
+    }
+
+    // The chars from `start` up to `end`, clamped to the text
+    fn slice(&self, start: usize, end: usize) -> String {
+        let end = end.min(self.length);
+        self.text[start.min(end)..end].iter().collect()
     }
 
     fn push_state(&mut self, state: MarkdownState) {
+        self.trace(format_args!("push {:?}", state));
         self.states.push(state);
     }
 
@@ -453,161 +1201,1324 @@ impl MDParser {
         if self.states.len() <= 1 {
             panic!("Removing the bottom TEXT state");
         }
+        self.trace(format_args!("pop {:?}", self.get_current_state()));
         self.states.pop();
     }
 
+    // With --debug, log a parser event with its source position and the state stack
+    fn trace(&self, event: fmt::Arguments) {
+        if diag::enabled(Level::Debug) {
+            let (line, column) = self.source_position(self.index.min(self.length));
+            diag::debug!("md {}:{} {:?} {}", line, column, self.states, event);
+        }
+    }
+
+    fn warn(&mut self, index: usize, message: String) {
+        let (line, column) = self.source_position(index.min(self.length));
+        self.warnings.push(Warning {
+            line,
+            column,
+            message,
+        });
+    }
+
+    // An inline span whose closing delimiter never came ends with its line
+    fn end_unclosed(&mut self, state: MarkdownState, start: usize, delimiter: &str) {
+        if self.states.last() == Some(&state) {
+            self.warn(start, format!("unclosed emphasis '{}'", delimiter));
+            self.pop_state();
+        }
+    }
+
     fn get_current_state(&self) -> MarkdownState {
         self.states.last().unwrap().clone()
     }
 
-    fn optionally_push_newline(&mut self) {
-        let index = self.html.len() - 1;
-        let mut chars_since_newline = 0;
-        for c in self.html[..index].chars().rev() {
-            if c == '\n' {
-                break;
-            }
-            chars_since_newline += 1;
-        }
+    fn check_next_chars(&self, index: usize, substring: &str) -> bool {
+        substring
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.get_ith_char(index + i) == Some(c))
+    }
 
-        if self.get_current_state() == MarkdownState::TEXT || chars_since_newline >= 80 {
-            self.html.push('\n');
+    // `-` or a digit and `.` at `index`, with the number of chars the marker takes
+    fn list_marker(&self, index: usize) -> Option<(ListType, usize)> {
+        match self.get_ith_char(index)? {
+            '-' => Some((ListType::UNORDERED, 1)),
+            c if c.is_ascii_digit() && self.check_next_chars(index + 1, ".") => {
+                Some((ListType::ORDERED, 2))
+            }
+            _ => None,
         }
     }
 
-    fn check_next_chars(&self, index: usize, substring: &str) -> bool {
-        let substring_length = substring.len();
-        if index + substring_length <= self.text.len() {
-            return &self.text[index..=index + substring_length - 1] == substring;
+    // Lists nest by indentation: an item indented past the innermost open list starts a list
+    // inside that list's open item, and a shallower one closes lists until one lines up with
+    // it. The open lists live on a stack rather than the call stack, so neither the number of
+    // items nor the depth is limited by recursion
+    fn handle_list(&mut self) {
+        let mut open: Vec<OpenList> = Vec::new();
+        loop {
+            let indent = self.find_distance_to_non_whitespace(self.index);
+            let marker = self.index + indent;
+            let Some((list_type, marker_length)) = self.list_marker(marker) else {
+                break;
+            };
+            match open.last() {
+                None => self.open_list(&mut open, list_type, indent, marker),
+                // An item far past the list's indent ends the lists instead
+                Some(top) if indent > top.indent + 8 => break,
+                Some(top) if indent > top.indent => {
+                    self.open_list(&mut open, list_type, indent, marker)
+                }
+                Some(_) => {
+                    while open.len() > 1 && open.last().is_some_and(|top| top.indent > indent) {
+                        self.close_list(&mut open);
+                    }
+                    let top = open.last().unwrap();
+                    let top_indent = top.indent;
+                    if top_indent != indent {
+                        self.warn(
+                            marker,
+                            format!(
+                                "list item indented {} spaces lines up with no enclosing list",
+                                indent
+                            ),
+                        );
+                    }
+                    if top.list_type == list_type {
+                        // The previous item at this level
+                        self.close();
+                    } else {
+                        self.trace(format_args!("list type changes at indent {}", top_indent));
+                        self.close_list(&mut open);
+                        self.open_list(&mut open, list_type, top_indent, marker);
+                    }
+                }
+            }
+            self.trace(format_args!(
+                "list item at depth {} indent {}",
+                open.len(),
+                indent
+            ));
+            self.index = marker + marker_length;
+            self.out.open("li");
+            self.parse_inline();
+            self.last_item_end = self.consumed_end(marker);
+            self.annotate_block(marker, self.last_item_end);
+        }
+        while !open.is_empty() {
+            self.close_list(&mut open);
         }
-        false
     }
 
-    fn handle_list(&mut self, list_type: ListType) {
-        let list_level_snapshot = self.list_level.unwrap();
-        println!("Creating handle_list for level: {}", list_level_snapshot);
-        let indent_level_snapshot = self.indentation_level;
-        self.curr_list_type = list_type;
-        let list_type_snapshot = self.curr_list_type;
-        let spaces = " ".repeat((list_level_snapshot - 1) * 4);
+    fn open_list(
+        &mut self,
+        open: &mut Vec<OpenList>,
+        list_type: ListType,
+        indent: usize,
+        start: usize,
+    ) {
+        self.trace(format_args!("{:?} list at indent {}", list_type, indent));
         match list_type {
             ListType::ORDERED => {
-                self.html.push_str(&format!("{}<ol>\n", spaces));
+                self.out.open("ol");
                 self.push_state(MarkdownState::OrderedList);
             }
             ListType::UNORDERED => {
-                self.html.push_str(&format!("{}<ul>\n", spaces));
+                self.out.open("ul");
                 self.push_state(MarkdownState::UnorderedList);
             }
-            ListType::NOTHING => panic!("list_type argument should not be 'NOTHING'"),
         }
-        self.curr_list_type = list_type;
+        open.push(OpenList {
+            list_type,
+            indent,
+            start,
+        });
+    }
 
-        while let Some(x) = self.list_level {
-            if x == list_level_snapshot && self.curr_list_type == list_type_snapshot {
-                self.index += 1;
-                self.max_list_level += 1;
-                self.handle_list_items();
-                self.max_list_level -= 1;
-            } else if x == list_level_snapshot {
-                println!("Changing types at list level: {}", x);
-                break;
-            } else {
-                println!("Removing Indent Level: {}", indent_level_snapshot);
-                println!(
-                    "Removing list level: {}",
-                    self.indent_to_list_level
-                        .get(&indent_level_snapshot)
-                        .unwrap()
-                );
-                self.indent_to_list_level.remove(&indent_level_snapshot);
+    // Close the innermost list and its last item
+    fn close_list(&mut self, open: &mut Vec<OpenList>) {
+        if let Some(list) = open.pop() {
+            self.close();
+            self.annotate_block(list.start, self.last_item_end);
+            self.close();
+            self.pop_state();
+        }
+    }
+
+    fn parse_blocks(&mut self) {
+        // Containers and alerts still open, innermost last, and how many of them are alerts
+        let mut open: Vec<OpenBlock> = Vec::new();
+        let mut alerts = 0;
+        loop {
+            self.parse_text_blocks(&mut open, &mut alerts);
+            // The end of a text closes the containers opened in it, then the alert it is the
+            // body of, which goes back to the text around that alert
+            match open.pop() {
+                Some(block) => {
+                    if let OpenBlock::Alert { .. } = block {
+                        alerts -= 1;
+                    }
+                    self.close_block(block);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn parse_text_blocks(&mut self, open: &mut Vec<OpenBlock>, alerts: &mut usize) {
+        use MarkdownState::*;
+        while self.index < self.length {
+            let i = self.index;
+            let Some(char) = self.get_ith_char(i) else {
                 break;
+            };
+            if self.get_current_state() == TEXT {
+                self.trace(format_args!("block at {:?}", char));
+                if self.handle_block_extension() {
+                    continue;
+                }
+                match char {
+                    '#' => self.handle_header(),
+                    '*' => {
+                        self.handle_asterisks();
+                    }
+                    '[' if self.is_toc_marker(i) => self.handle_toc_marker(),
+                    '$' if self.options.extensions.math && self.check_next_chars(i, "$$") => {
+                        self.handle_display_math()
+                    }
+                    '[' => self.handle_link(),
+                    '`' => self.handle_code(),
+                    '~' if self.options.extensions.strikethrough
+                        && self.check_next_chars(i, "~~") =>
+                    {
+                        self.handle_strikethrough()
+                    }
+                    '>' if self.options.extensions.alerts
+                        && *alerts < MAX_ALERT_DEPTH
+                        && self.is_alert() =>
+                    {
+                        *alerts += 1;
+                        self.open_alert(open);
+                    }
+                    '>' => self.handle_quotes(),
+                    ':' if self.options.extensions.containers
+                        && self.check_next_chars(i, ":::") =>
+                    {
+                        self.handle_container_fence(open)
+                    }
+                    '\n' => self.index += 1,
+                    _ if self.list_marker(i).is_some() => self.handle_list(),
+
+                    _ => {
+                        self.handle_paragraph();
+                    }
+                }
             }
         }
-        match list_type {
-            ListType::ORDERED => {
-                self.html.push_str(&format!("{}</ol>\n", spaces));
+    }
+
+    // The first registered extension that claims a block starting here, with the lines it covers
+    fn match_block_extension(
+        &self,
+        index: usize,
+    ) -> Option<(Arc<dyn SyntaxExtension>, Vec<String>)> {
+        let char = self.get_ith_char(index)?;
+        let mut extensions = self
+            .options
+            .syntax
+            .iter()
+            .filter(|extension| extension.block_triggers().contains(&char))
+            .peekable();
+        extensions.peek()?;
+        let rest = self.rest_of_source(index);
+        extensions.find_map(|extension| {
+            let count = extension.parse_block(rest).filter(|&count| count > 0)?;
+            let matched: Vec<String> = rest.split('\n').take(count).map(String::from).collect();
+            (matched.len() == count).then(|| (Arc::clone(extension), matched))
+        })
+    }
+
+    // The source from `index` on, borrowed rather than collected from the chars
+    fn rest_of_source(&self, index: usize) -> &str {
+        let line = match self.line_starts.binary_search(&index) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let offset: usize = self.text[self.line_starts[line]..index]
+            .iter()
+            .map(|c| c.len_utf8())
+            .sum();
+        &self.source[self.line_bytes[line] + offset..]
+    }
+
+    fn handle_block_extension(&mut self) -> bool {
+        let Some((extension, lines)) = self.match_block_extension(self.index) else {
+            return false;
+        };
+        self.trace(format_args!("{:?} takes {} lines", extension, lines.len()));
+        let start = self.index;
+        let consumed: usize = lines.iter().map(|line| line.chars().count() + 1).sum();
+        self.index = (self.index + consumed).min(self.length);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut nodes = extension.render_block(&lines);
+        if let (Some(sourcepos), Some(Node::Element(first))) = (
+            self.sourcepos(start, self.consumed_end(start)),
+            nodes.first_mut(),
+        ) {
+            first.set_attr("data-sourcepos", &sourcepos);
+        }
+        for node in nodes {
+            self.out.push(node);
+        }
+        true
+    }
+
+    fn handle_inline_extension(&mut self, char: char) -> bool {
+        let extensions: Vec<Arc<dyn SyntaxExtension>> = self
+            .options
+            .syntax
+            .iter()
+            .filter(|extension| extension.inline_triggers().contains(&char))
+            .cloned()
+            .collect();
+        if extensions.is_empty() {
+            return false;
+        }
+        let rest = self.peek_line();
+        let length = rest.chars().count();
+        for extension in extensions {
+            // An empty match would never advance, so it counts as no match
+            if let Some(count) = extension
+                .parse_inline(&rest)
+                .filter(|&count| count > 0 && count <= length)
+            {
+                self.trace(format_args!("{:?} takes {} chars", extension, count));
+                let source: String = rest.chars().take(count).collect();
+                for node in extension.render_inline(&source) {
+                    self.out.push(node);
+                }
+                self.index += count;
+                return true;
             }
-            ListType::UNORDERED => {
-                self.html.push_str(&format!("{}</ul>\n", spaces));
+        }
+        false
+    }
+
+    // Switch to parsing a block-level fragment, the body of an alert, sharing heading ids and
+    // options with the enclosing document. Returns the text to go back to once it ends
+    fn enter_text(&mut self, md: String, line_origins: Vec<(usize, usize)>) -> Box<SavedText> {
+        let line_starts = MDParser::find_line_starts(&md);
+        let line_bytes = MDParser::find_line_bytes(&md);
+        let text: Vec<char> = md.chars().collect();
+        let inner = SavedText {
+            length: text.len(),
+            text,
+            source: md,
+            line_bytes,
+            index: 0,
+            states: vec![MarkdownState::TEXT],
+            line_starts,
+            line_origins,
+        };
+        Box::new(self.swap_text(inner))
+    }
+
+    // Make `text` the one being parsed, returning the one that was
+    fn swap_text(&mut self, text: SavedText) -> SavedText {
+        SavedText {
+            text: std::mem::replace(&mut self.text, text.text),
+            source: std::mem::replace(&mut self.source, text.source),
+            line_bytes: std::mem::replace(&mut self.line_bytes, text.line_bytes),
+            length: std::mem::replace(&mut self.length, text.length),
+            index: std::mem::replace(&mut self.index, text.index),
+            states: std::mem::replace(&mut self.states, text.states),
+            line_starts: std::mem::replace(&mut self.line_starts, text.line_starts),
+            line_origins: std::mem::replace(&mut self.line_origins, text.line_origins),
+        }
+    }
+}
+
+// GitHub-style anchor: lowercase, drop punctuation, and turn spaces into hyphens
+fn slugify(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            '-' | '_' => Some(c),
+            _ if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Reduce accented Latin letters to their base letters, like ü to u and æ to ae, and drop
+// anything else outside ASCII. Expects lowercase text, as slugify produces
+fn fold_to_ascii(slug: &str) -> String {
+    let mut folded = String::with_capacity(slug.len());
+    for c in slug.chars() {
+        if c.is_ascii() {
+            folded.push(c);
+            continue;
+        }
+        let base = match c {
+            'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+            'æ' => "ae",
+            'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+            'ð' | 'ď' | 'đ' => "d",
+            'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+            'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+            'ĥ' | 'ħ' => "h",
+            'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+            'ĳ' => "ij",
+            'ĵ' => "j",
+            'ķ' | 'ĸ' => "k",
+            'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+            'ñ' | 'ń' | 'ņ' | 'ň' | 'ŉ' | 'ŋ' => "n",
+            'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+            'œ' => "oe",
+            'ŕ' | 'ŗ' | 'ř' => "r",
+            'ś' | 'ŝ' | 'ş' | 'š' | 'ſ' => "s",
+            'ß' => "ss",
+            'ţ' | 'ť' | 'ŧ' => "t",
+            'þ' => "th",
+            'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+            'ŵ' => "w",
+            'ý' | 'ÿ' | 'ŷ' => "y",
+            'ź' | 'ż' | 'ž' => "z",
+            _ => "",
+        };
+        folded.push_str(base);
+    }
+    folded
+}
+
+// `[label]: url`, with the label lowercased for case-insensitive matching
+fn reference_definition(line: &str) -> Option<(String, String)> {
+    let rest = line.trim_start().strip_prefix('[')?;
+    let (label, rest) = rest.split_once("]:")?;
+    let url = rest.trim();
+    if label.is_empty() || label == "TOC" || url.is_empty() || url.contains(' ') {
+        return None;
+    }
+    Some((label.to_lowercase(), url.to_string()))
+}
+
+// Links a browser would run as code, which safe mode leaves without an href
+fn is_script_url(url: &str) -> bool {
+    let scheme: String = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .take_while(|&c| c != ':')
+        .collect();
+    url.contains(':')
+        && ["javascript", "vbscript", "data"].contains(&scheme.to_ascii_lowercase().as_str())
+}
+
+// Fill the empty nav left by a `[TOC]` marker, or drop it when there is no table of contents,
+// returning false if there was none
+fn fill_toc_marker(nodes: &mut Vec<Node>, toc: Option<&Element>) -> bool {
+    for i in 0..nodes.len() {
+        if let Node::Element(element) = &mut nodes[i] {
+            if element.tag == "nav" && element.attr("class") == Some("toc") {
+                if element.children.is_empty() {
+                    match toc {
+                        Some(toc) => *element = toc.clone(),
+                        None => {
+                            nodes.remove(i);
+                        }
+                    }
+                    return true;
+                }
+            } else if fill_toc_marker(&mut element.children, toc) {
+                return true;
             }
-            ListType::NOTHING => panic!("list_type argument should not be 'NOTHING'"),
         }
-        self.pop_state();
-        //
-        if let Some(x) = self.list_level {
-            if x == list_level_snapshot {
-                self.handle_list(self.curr_list_type);
+    }
+    false
+}
+
+pub fn md_to_html(md_path: &str) -> Result<String, String> {
+    md_to_html_with_options(md_path, &MDOptions::default())
+}
+
+/// Render the Markdown file at `md_path`, failing if it can't be read or isn't UTF-8
+pub fn md_to_html_with_options(md_path: &str, options: &MDOptions) -> Result<String, String> {
+    let contents = fs::read_to_string(md_path).map_err(|e| format!("{}: {}", md_path, e))?;
+    Ok(md_string_to_html(&contents, options))
+}
+
+pub fn md_string_to_html(md: &str, options: &MDOptions) -> String {
+    md_string_to_html_with_warnings(md, options).0
+}
+
+/// The `[label]: url` definitions in `input`, keyed by lowercased label, found without
+/// rendering anything. Passing them to `md_stream_to_html` lets links resolve to definitions
+/// further down the document
+pub fn md_reference_definitions(input: impl BufRead) -> io::Result<BTreeMap<String, String>> {
+    let mut references = BTreeMap::new();
+    let mut in_code_block = false;
+    for line in input.lines() {
+        let line = line?;
+        if line.starts_with("```") {
+            in_code_block = !in_code_block;
+        } else if !in_code_block {
+            if let Some((label, url)) = reference_definition(&line) {
+                references.entry(label).or_insert(url);
             }
         }
     }
+    Ok(references)
 }
 
-pub fn md_to_html(md_path: &str) -> Result<String, &'static str> {
-    use MDParser;
-    use MarkdownState::*;
-    let md_file = File::open(md_path).expect("IO Error");
-    let mut parser = MDParser::md_init_parser(md_file);
-    println!("====================================\nMarkdown Contents:\n====================================\n {}\n=====================================", &parser.text);
+/// Render Markdown from `input` to `output` a top-level block at a time, so memory stays
+/// proportional to the largest block rather than the whole document. Links only resolve to
+/// definitions in `references` or earlier in the stream, and block extensions only see the
+/// lines up to the next place the document can be split. A table of contents needs every
+/// heading before it can be written, so with `toc` set the whole document is read first
+pub fn md_stream_to_html(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    options: &MDOptions,
+    references: BTreeMap<String, String>,
+) -> io::Result<Vec<Warning>> {
+    if options.toc {
+        let mut md = String::new();
+        input.read_to_string(&mut md)?;
+        let (html, warnings) = md_string_to_html_with_warnings(&md, options);
+        output.write_all(html.as_bytes())?;
+        return Ok(warnings);
+    }
+    let mut parser = MDParser::md_init_parser("", options.clone());
+    parser.references = references;
+    if let Some(theme) = options.highlight_theme {
+        let style = Element::new("style").with_child(Node::Raw(theme.css().to_string()));
+        output.write_all(html::serialize(&[style.into()], options.format).as_bytes())?;
+    }
+    let mut splitter = BlockSplitter {
+        extensions: options.extensions,
+        ..BlockSplitter::default()
+    };
+    let mut block = String::new();
+    let mut first_line = 0;
+    for (line_number, line) in input.lines().enumerate() {
+        let line = line?;
+        if splitter.starts_block(&line) && !block.is_empty() {
+            parser.write_block(&block, first_line, true, output)?;
+            block.clear();
+            first_line = line_number;
+        }
+        block.push_str(&line);
+        block.push('\n');
+    }
+    parser.write_block(&block, first_line, false, output)?;
+    parser.warnings.sort_by_key(|w| (w.line, w.column));
+    Ok(parser.warnings)
+}
 
-    while parser.index < parser.length {
-        let i = parser.index;
-        let char: char = parser.get_ith_char(i).unwrap();
-        if parser.get_current_state() == TEXT {
-            match char {
-                '#' => parser.handle_header(),
-                '*' => {
-                    parser.handle_asterisks();
-                }
-                '[' => parser.handle_link(),
-                '`' => parser.handle_code(),
-                '~' => parser.handle_strikethrough(),
-                '>' => parser.handle_quotes(),
-                '\n' => parser.index += 1,
-                '-' => {
-                    parser.handle_list(ListType::UNORDERED);
-                    parser.list_level = Some(1);
-                    parser.max_list_level = 1;
-                    // Clear the BTreeMap of everyting but the 0,0 pair
-                    parser.indent_to_list_level.retain(|&k, _| k == 0);
-                    parser.indentation_level = 0;
+// Finds the lines where a top-level block has to start whatever came before, which is where a
+// stream can be cut without changing how it renders. Lines inside fences, containers and
+// display math never qualify, and neither does a line that could continue a quote or list.
+// Fences are followed with the parser's rules, so only a line starting with one opens it
+#[derive(Default)]
+struct BlockSplitter {
+    extensions: Extensions,
+    in_code_block: bool,
+    in_display_math: bool,
+    container_depth: usize,
+    // The preprocessor's idea of a code block, which toggles on every line starting with ```
+    // and decides where definitions are taken out
+    definitions_fenced: bool,
+    // Set once a fence might have opened partway through a line, after which nothing is cut
+    uncertain: bool,
+    previous: String,
+}
+
+impl BlockSplitter {
+    fn starts_block(&mut self, line: &str) -> bool {
+        if line.trim().is_empty() {
+            return false;
+        }
+        let definitions_fenced = self.definitions_fenced;
+        if line.starts_with("```") {
+            self.definitions_fenced = !definitions_fenced;
+        }
+        // Definitions are taken out before parsing, so the lines either side of one meet
+        if !definitions_fenced && reference_definition(line).is_some() {
+            return false;
+        }
+        let enclosed = self.in_code_block
+            || self.in_display_math
+            || self.container_depth > 0
+            || definitions_fenced
+            || self.uncertain;
+        let previous = std::mem::replace(&mut self.previous, line.to_string());
+        self.follow_fences(line);
+        if enclosed {
+            return false;
+        }
+        // Quotes and lists can start partway through a line, after a link or emphasis
+        let has_list_marker = |line: &str| {
+            line.contains('-')
+                || line
+                    .as_bytes()
+                    .windows(2)
+                    .any(|pair| pair[0].is_ascii_digit() && pair[1] == b'.')
+        };
+        let is_list_item = line.starts_with('-')
+            || (line.starts_with(|c: char| c.is_ascii_digit()) && line[1..].starts_with('.'));
+        line.starts_with('#')
+            || line.starts_with("```")
+            || (self.extensions.math && line.starts_with("$$"))
+            || (self.extensions.containers && line.starts_with(":::"))
+            || (line.starts_with('>') && !previous.contains('>'))
+            || (is_list_item && !has_list_marker(&previous))
+    }
+
+    // Open and close fences through `line`. Closing a code block or display math leaves the
+    // rest of the line to start another block
+    fn follow_fences(&mut self, line: &str) {
+        let mut rest = line;
+        loop {
+            if self.in_code_block {
+                // The first ``` anywhere ends a code block
+                let Some(end) = rest.find("```") else {
+                    return;
+                };
+                self.in_code_block = false;
+                rest = &rest[end + 3..];
+            } else if self.in_display_math {
+                let Some(end) = rest.find("$$") else {
+                    return;
+                };
+                self.in_display_math = false;
+                rest = &rest[end + 2..];
+            } else if rest.starts_with("```") {
+                // The rest of the line is the info string
+                self.in_code_block = true;
+                return;
+            } else if self.extensions.math && rest.starts_with("$$") {
+                self.in_display_math = true;
+                rest = &rest[2..];
+            } else if self.extensions.containers && rest.starts_with(":::") {
+                // A bare fence closes the innermost container, or opens one when none is open
+                let bare = rest.trim_start_matches(':').trim().is_empty();
+                if bare && self.container_depth > 0 {
+                    self.container_depth -= 1;
+                } else {
+                    self.container_depth += 1;
                 }
-                _ if char.is_digit(10) && parser.get_ith_char(i + 1).unwrap() == '.' => {
-                    parser.handle_list(ListType::ORDERED);
-                    parser.list_level = Some(1);
-                    parser.max_list_level = 1;
-                    parser.indent_to_list_level.retain(|&k, _| k == 0);
-                    parser.indentation_level = 0;
+                return;
+            } else {
+                // Links, emphasis and code spans that start a block are parsed where they stand,
+                // so a fence right after one can open as though it started the line. Telling
+                // would take parsing the spans, so the rest is left in one piece instead
+                if rest.starts_with(['*', '[', '`']) || rest.starts_with("~~") {
+                    let fences = [
+                        ("```", true),
+                        ("$$", self.extensions.math),
+                        (":::", self.extensions.containers),
+                    ];
+                    // Fences can overlap the span's own delimiters, as in `a````
+                    self.uncertain |= rest.char_indices().skip(1).any(|(i, _)| {
+                        rest[..i].ends_with(SPAN_ENDS)
+                            && fences
+                                .iter()
+                                .any(|&(fence, enabled)| enabled && rest[i..].starts_with(fence))
+                    });
                 }
+                return;
+            }
+        }
+    }
+}
 
-                _ => {
-                    parser.handle_paragraph();
+// The chars a link, emphasis, code span or strikethrough can end on
+const SPAN_ENDS: &[char] = &['*', '[', ']', ')', '`', '~', '$'];
+
+/// Render `md`, also returning whatever in it looks malformed
+pub fn md_string_to_html_with_warnings(md: &str, options: &MDOptions) -> (String, Vec<Warning>) {
+    let mut parser = MDParser::md_init_parser(md, options.clone());
+    parser.parse_blocks();
+    parser.trace(format_args!("done"));
+    let mut nodes = std::mem::take(&mut parser.out).finish();
+
+    if parser.options.toc {
+        // A document without headings gets no table of contents rather than an empty one
+        match parser.render_toc() {
+            Some(toc) => {
+                if !fill_toc_marker(&mut nodes, Some(&toc)) {
+                    nodes.insert(0, toc.into());
                 }
             }
+            None => {
+                fill_toc_marker(&mut nodes, None);
+            }
         }
     }
+    if let Some(theme) = parser.options.highlight_theme {
+        let style = Element::new("style").with_child(Node::Raw(theme.css().to_string()));
+        nodes.insert(0, style.into());
+    }
 
-    println!("HTML Contents:\n====================================\n {}\n=====================================", &parser.html);
-    Ok(parser.html)
+    // Inner spans finish first, so put warnings back in source order
+    parser.warnings.sort_by_key(|w| (w.line, w.column));
+    (
+        html::serialize(&nodes, parser.options.format),
+        parser.warnings,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io;
+    use std::fs::File;
+    use std::io::Read;
 
     #[test]
     fn validate_html() -> io::Result<()> {
         let mut real_file = File::open("test_files/baby.html")?;
         let mut real_contents = String::new();
         real_file.read_to_string(&mut real_contents)?;
-        real_contents = real_contents.to_string();
 
-        let mut test_file = File::open("test_files/test.html")?;
-        let mut test_contents = String::new();
-        test_file.read_to_string(&mut test_contents)?;
-        test_contents = test_contents.trim().to_string();
+        let test_contents = md_to_html("test_files/baby.md").expect("error");
         assert_eq!(real_contents, test_contents);
         Ok(())
     }
+
+    fn render(name: &str, md: &str, options: &MDOptions) -> String {
+        let path = std::env::temp_dir().join(format!("flux_{}.md", name));
+        std::fs::write(&path, md).unwrap();
+        md_to_html_with_options(path.to_str().unwrap(), options).unwrap()
+    }
+
+    #[test]
+    fn alerts_and_containers() {
+        let md = "> [!TIP]\n> Use `flux`\n::: note\n# Title\n:::\n";
+        let html = render("alerts", md, &MDOptions::default());
+        assert_eq!(
+            html,
+            "<div class=\"markdown-alert markdown-alert-tip\">\n\
+             \x20   <p class=\"markdown-alert-title\">Tip</p>\n\
+             \x20   <p>Use <code>flux</code></p>\n</div>\n\
+             <div class=\"note\">\n    <h1 id=\"title\">Title</h1>\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn minified_output() {
+        let options = MDOptions {
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        let html = render("minify", "# A\n- b\n  - c\n", &options);
+        assert_eq!(
+            html,
+            "<h1 id=\"a\">A</h1><ul><li>b<ul><li>c</li></ul></li></ul>"
+        );
+    }
+
+    #[test]
+    fn display_math_in_running_text() {
+        let options = MDOptions {
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        assert_eq!(
+            md_string_to_html("formula and $$x$$ done\n", &options),
+            "<p>formula and <span class=\"math display\">\\[x\\]</span> done</p>"
+        );
+        assert_eq!(
+            md_string_to_html("$$\nx\n$$\n", &options),
+            "<div class=\"math display\">\\[x\\]</div>"
+        );
+        // It can span the lines of a paragraph, but not run on into the blocks after it
+        assert_eq!(
+            md_string_to_html("a $$x\ny$$ b\n# Costs $$\n# More $$\n", &options),
+            "<p>a <span class=\"math display\">\\[x\ny\\]</span> b</p>\
+             <h1 id=\"costs-\">Costs $$</h1><h1 id=\"more-\">More $$</h1>"
+        );
+    }
+
+    #[test]
+    fn source_positions_skip_blank_lines() {
+        let options = MDOptions {
+            sourcepos: true,
+            ..MDOptions::default()
+        };
+        let html = render("sourcepos", "# Hi\n\n> [!NOTE]\n> body\n", &options);
+        assert!(html.starts_with("<h1 id=\"hi\" data-sourcepos=\"1:1-1:4\">"));
+        assert!(html.contains("data-sourcepos=\"3:1-4:6\""));
+        assert!(html.contains("<p data-sourcepos=\"4:3-4:6\">body</p>"));
+    }
+
+    #[test]
+    fn safe_mode_and_disabled_extensions() {
+        let options = MDOptions {
+            safe: true,
+            ..MDOptions::default()
+        };
+        let md = "# Cafe menu\nHi <script>x</script> [a](JavaScript:void) [b](/ok)\n";
+        let html = render("safe", md, &options);
+        assert!(html.starts_with("<h1 id=\"cafe-menu\">"));
+        assert!(html.contains("Hi &lt;script&gt;x&lt;/script&gt; <a>a</a> <a href=\"/ok\">b</a>"));
+
+        let options = MDOptions {
+            extensions: Extensions::from_names(&["alerts"]).unwrap(),
+            heading_ids: HeadingIds::None,
+            ..MDOptions::default()
+        };
+        let html = render("extensions", "# A\n$x$ and ~~y~~\n", &options);
+        assert_eq!(html, "<h1>A</h1>\n<p>$x$ and ~~y~~</p>\n");
+        assert!(Extensions::from_names(&["tables"]).is_err());
+    }
+
+    // `@name` links to a profile and `%%% title` ... `%%%` becomes a details block
+    #[derive(Debug)]
+    struct Mentions;
+
+    impl SyntaxExtension for Mentions {
+        fn inline_triggers(&self) -> &[char] {
+            &['@']
+        }
+
+        fn parse_inline(&self, text: &str) -> Option<usize> {
+            let name = text[1..]
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .count();
+            (name > 0).then_some(name + 1)
+        }
+
+        fn render_inline(&self, source: &str) -> Vec<Node> {
+            let link = Element::new("a").with_attr("href", &format!("/u/{}", &source[1..]));
+            vec![link.with_child(Node::Text(source.to_string())).into()]
+        }
+
+        fn block_triggers(&self) -> &[char] {
+            &['%']
+        }
+
+        fn parse_block(&self, text: &str) -> Option<usize> {
+            let mut lines = text.lines();
+            lines.next()?.strip_prefix("%%% ")?;
+            let end = lines.position(|line| line == "%%%")?;
+            Some(end + 2)
+        }
+
+        fn render_block(&self, lines: &[&str]) -> Vec<Node> {
+            let summary = Element::new("summary").with_child(Node::Text(lines[0][4..].into()));
+            let body = Node::Text(lines[1..lines.len() - 1].join("\n"));
+            vec![Element::new("details")
+                .with_child(summary.into())
+                .with_child(body)
+                .into()]
+        }
+    }
+
+    #[test]
+    fn registered_syntax_extensions() {
+        let mut options = MDOptions {
+            sourcepos: true,
+            ..MDOptions::default()
+        };
+        options.register(Mentions);
+        let md = "Thanks @ana and @ for this\n%%% More\nhidden\n%%%\n% not a block\n";
+        let html = render("extension", md, &options);
+        assert_eq!(
+            html,
+            "<p data-sourcepos=\"1:1-1:26\">Thanks <a href=\"/u/ana\">@ana</a> and @ for this</p>\n\
+             <details data-sourcepos=\"2:1-4:3\">\n    <summary>More</summary>\n    hidden\n</details>\n\
+             <p data-sourcepos=\"5:1-5:13\">% not a block</p>\n"
+        );
+    }
+
+    // External links open in a new tab, headings get permalinks and images become figures
+    #[derive(Debug)]
+    struct Custom;
+
+    impl Renderer for Custom {
+        fn heading(&self, _level: usize, id: Option<&str>, mut element: Element) -> Node {
+            if let Some(id) = id {
+                let anchor = Element::new("a").with_attr("href", &format!("#{}", id));
+                element
+                    .children
+                    .push(anchor.with_child(Node::Text("#".into())).into());
+            }
+            element.into()
+        }
+
+        fn link(&self, href: &str, mut element: Element) -> Node {
+            if href.starts_with("https://") {
+                element.set_attr("target", "_blank");
+                element.set_attr("rel", "noopener");
+            }
+            element.into()
+        }
+
+        fn image(&self, _src: &str, alt: &str, element: Element) -> Node {
+            let caption = Element::new("figcaption").with_child(Node::Text(alt.to_string()));
+            Element::new("figure")
+                .with_child(element.into())
+                .with_child(caption.into())
+                .into()
+        }
+
+        fn blockquote(&self, mut element: Element) -> Node {
+            element.set_attr("class", "quote");
+            element.into()
+        }
+
+        fn alert(&self, kind: &str, mut element: Element) -> Node {
+            element.tag = "aside".to_string();
+            element.set_attr("role", kind);
+            element.into()
+        }
+
+        fn container(&self, name: &str, mut element: Element) -> Node {
+            element.tag = "section".to_string();
+            element.set_attr("title", name);
+            element.into()
+        }
+    }
+
+    #[test]
+    fn renderer_overrides() {
+        let md = "# Intro\nSee [a](https://x.org) [b](/b) ![Logo](logo.png)\n";
+        let plain = render("renderer_plain", md, &MDOptions::default());
+        assert_eq!(
+            plain,
+            "<h1 id=\"intro\">Intro</h1>\n<p>See <a href=\"https://x.org\">a</a> <a href=\"/b\">b</a> \
+             <img src=\"logo.png\" alt=\"Logo\"></p>\n"
+        );
+
+        let options = MDOptions {
+            renderer: Arc::new(Custom),
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        let html = render("renderer_custom", md, &options);
+        assert_eq!(
+            html,
+            "<h1 id=\"intro\">Intro<a href=\"#intro\">#</a></h1>\
+             <p>See <a href=\"https://x.org\" target=\"_blank\" rel=\"noopener\">a</a> <a href=\"/b\">b</a>\
+             <figure><img src=\"logo.png\" alt=\"Logo\"><figcaption>Logo</figcaption></figure></p>"
+        );
+
+        let md = "> [!TIP]\n> Try it\n::: note Heads up\nInside\n:::\n> quoted\n";
+        let html = render("renderer_blocks", md, &options);
+        assert_eq!(
+            html,
+            "<aside class=\"markdown-alert markdown-alert-tip\" role=\"tip\">\
+             <p class=\"markdown-alert-title\">Tip</p><p>Try it</p></aside>\
+             <section class=\"note\" title=\"note Heads up\"><p>Inside</p></section>\
+             <blockquote class=\"quote\">quoted</blockquote>"
+        );
+    }
+
+    #[test]
+    fn warnings_carry_source_positions() {
+        let md = "# A\n\n### B\n\nsome *em and `code\n\n\
+                  [x][nope] and [y][ref]\n\n[ref]: https://example.com\n\n```rust\nfn x";
+        let (html, warnings) = md_string_to_html_with_warnings(md, &MDOptions::default());
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "3:1: heading level jumps from h1 to h3",
+                "5:6: unclosed emphasis '*'",
+                "5:14: unclosed code span",
+                "7:1: unresolved reference link '[nope]'",
+                "11:1: unclosed code fence",
+            ]
+        );
+        assert!(html.contains("<a href=\"https://example.com\">y</a>"));
+        assert!(!html.contains("[ref]:"));
+    }
+
+    // Pieces of Markdown syntax for random documents, weighted toward the ones that open or close
+    // something and so can be left dangling
+    const FRAGMENTS: &[&str] = &[
+        "*",
+        "**",
+        "***",
+        "`",
+        "```",
+        "~",
+        "~~",
+        "[",
+        "]",
+        "(",
+        ")",
+        "![",
+        "](",
+        "#",
+        "### ",
+        ">",
+        "> [!NOTE]",
+        "- ",
+        "1. ",
+        "  ",
+        "    ",
+        "\n",
+        "\n\n",
+        "$",
+        "$$",
+        ":::",
+        "::: note",
+        "\\",
+        "<",
+        "&",
+        "é",
+        "日本",
+        "word ",
+        "[TOC]",
+        "[r]: /x",
+        "[a][r]",
+        "[a][]",
+    ];
+
+    // xorshift64, so failures reproduce from the seed without a dependency
+    fn random_document(seed: &mut u64) -> String {
+        let mut next = || {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *seed as usize
+        };
+        let length = next() % 60;
+        (0..length)
+            .map(|_| FRAGMENTS[next() % FRAGMENTS.len()])
+            .collect()
+    }
+
+    // Every tag opened in `html` is closed, in order
+    fn tags_balance(html: &str) -> bool {
+        let mut open = Vec::new();
+        for tag in html.split('<').skip(1) {
+            let Some(end) = tag.find('>') else {
+                return false;
+            };
+            let tag = &tag[..end];
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            if VOID_TAGS.contains(&name.as_str()) {
+                continue;
+            } else if tag.starts_with('/') {
+                if open.pop() != Some(name) {
+                    return false;
+                }
+            } else {
+                open.push(name);
+            }
+        }
+        open.is_empty()
+    }
+
+    const VOID_TAGS: &[&str] = &["br", "img"];
+
+    #[test]
+    fn malformed_input_renders() {
+        let options = MDOptions::default();
+        for md in [
+            "a `b",
+            "```rust\nfn x",
+            "[x",
+            "![x",
+            "[x](y",
+            "#",
+            "a ~ b",
+            "- a\n  - b\n - c\n- d",
+            "      - a\n   - b\n- c",
+            "1.",
+            "é *ü* `ö`",
+        ] {
+            let (html, _) = md_string_to_html_with_warnings(md, &options);
+            assert!(tags_balance(&html), "{:?} gave {:?}", md, html);
+        }
+
+        // Unreadable files are errors too
+        assert!(md_to_html("test_files/missing.md").is_err());
+        let binary = std::env::temp_dir().join("flux_not_utf8.md");
+        fs::write(&binary, [b'#', b' ', 0xff, b'\n']).unwrap();
+        assert!(md_to_html(binary.to_str().unwrap()).is_err());
+        fs::remove_file(binary).unwrap();
+    }
+
+    #[test]
+    fn random_input_terminates_with_balanced_tags() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let options = MDOptions {
+                safe: true,
+                ..MDOptions::default()
+            };
+            let mut seed = 0x5eed_f100_u64;
+            for _ in 0..3000 {
+                let md = random_document(&mut seed);
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    md_string_to_html_with_warnings(&md, &options).0
+                }));
+                match result {
+                    Ok(html) if tags_balance(&html) => {}
+                    Ok(html) => panic!("unbalanced tags for {:?}: {:?}", md, html),
+                    Err(_) => panic!("panicked on {:?}", md),
+                }
+            }
+            // Deeply nested containers and alerts, closed and left open. Minified, since pretty
+            // output indents every level
+            let options = MDOptions {
+                format: Format::Minify,
+                ..options
+            };
+            let containers = "::: a\n".repeat(16_000);
+            let closed = format!("{}x\n{}", containers, ":::\n".repeat(16_000));
+            let alerts: String = (0..500)
+                .map(|depth| format!("{}> [!NOTE]\n", "> ".repeat(depth)))
+                .collect();
+            for md in [containers, closed, alerts] {
+                let html = md_string_to_html_with_warnings(&md, &options).0;
+                assert!(tags_balance(&html), "unbalanced tags for deep nesting");
+            }
+            sender.send(()).unwrap();
+        });
+        receiver
+            .recv_timeout(std::time::Duration::from_secs(60))
+            .expect("random documents did not all finish rendering");
+    }
+
+    #[test]
+    fn long_and_deep_lists() {
+        let options = MDOptions {
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        let flat: String = (0..100_000).map(|i| format!("- item {}\n", i)).collect();
+        let html = md_string_to_html(&flat, &options);
+        assert_eq!(html.matches("<li>").count(), 100_000);
+        assert!(html.ends_with("<li>item 99999</li></ul>"));
+
+        let deep: String = (0..1000)
+            .map(|depth| format!("{}1. level {}\n", "  ".repeat(depth), depth))
+            .collect();
+        let html = md_string_to_html(&deep, &options);
+        assert_eq!(html.matches("<ol>").count(), 1000);
+        assert!(html.starts_with("<ol><li>level 0<ol><li>level 1<ol>"));
+        assert!(html.ends_with(&"</li></ol>".repeat(1000)));
+    }
+
+    #[test]
+    fn streaming_matches_whole_documents() -> io::Result<()> {
+        let options = MDOptions {
+            sourcepos: true,
+            ..MDOptions::default()
+        };
+        let mut documents = vec![
+            std::fs::read_to_string("test_files/baby.md")?,
+            std::fs::read_to_string("test_files/full_test.md")?,
+        ];
+        documents.push(
+            "[home]: /index.html\n# Intro\nSee [home][] and\nmore text  \n- a\n  1. b\n\
+             - c\n> quote\n> more\n> [!NOTE]\n> alert\n::: note\n# Inside\n:::\n\
+             ```sh\n# not a heading\n```\n$$\n# x\n$$\n# Intro\n#### Jump *open\n"
+                .to_string(),
+        );
+        // Definitions after their links, and between the items of one list
+        documents.push(
+            "# Intro\nSee [the docs][docs].\n\n## Links\n[docs]: https://example.com\n\
+             [Docs]: /ignored\n"
+                .to_string(),
+        );
+        documents.push("1. one\n[r]: /url\n2. [two][r]\n- a\n\n[s]: /s\n\n- b\n".to_string());
+        // Fences inside display math, stray and bare container fences, and $$ in prose
+        documents.extend(
+            [
+                "$$\n```\nx\n$$\n# After\n::: note\n$$\n:::\n$$\n:::\n# End\n",
+                "Use ::: to open a container\n# Next\n:::\nbare\n:::\n# After\n- a ::: b\n",
+                "Costs $$ more\n# Title $$\n> quoted $$\nand $$x$$ inline\n$$\ny\n$$ trailing\n# End\n",
+                "```\n ```\n:::\n[r]: /x\n",
+                "*a*:::\n# Inside\n:::\n`a````\n# Code\n",
+            ]
+            .map(String::from),
+        );
+        let mut seed = 0x5eed_f100_u64;
+        documents.extend((0..2000).map(|_| random_document(&mut seed)));
+        for md in documents {
+            let whole = md_string_to_html_with_warnings(&md, &options);
+            let mut streamed = Vec::new();
+            let references = md_reference_definitions(md.as_bytes())?;
+            let warnings = md_stream_to_html(md.as_bytes(), &mut streamed, &options, references)?;
+            assert_eq!(String::from_utf8(streamed).unwrap(), whole.0, "{:?}", md);
+            assert_eq!(warnings, whole.1, "{:?}", md);
+        }
+
+        // Without the first pass a stream only knows the definitions it has already passed
+        let md = "See [docs][].\n# Links\n[docs]: /docs\n[docs][]\n";
+        let mut streamed = Vec::new();
+        let warnings = md_stream_to_html(md.as_bytes(), &mut streamed, &options, BTreeMap::new())?;
+        assert_eq!(warnings.len(), 1);
+        assert!(String::from_utf8(streamed)
+            .unwrap()
+            .contains("<a href=\"/docs\">docs</a>"));
+        Ok(())
+    }
+
+    // Counts the bytes taken from it, so a test can see how much input was read before output
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        read: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.data.read(buf)?;
+            self.read.set(self.read.get() + count);
+            Ok(count)
+        }
+    }
+
+    struct FirstWrite {
+        read: std::rc::Rc<std::cell::Cell<usize>>,
+        at: Option<usize>,
+    }
+
+    impl Write for FirstWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.at.get_or_insert(self.read.get());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streaming_writes_blocks_as_they_complete() -> io::Result<()> {
+        // Container fences and $$ in running text don't stop the splitting
+        let md: String = (0..10_000)
+            .map(|i| format!("## Section {}\nSome text, ::: and $$\n\n", i))
+            .collect();
+        let read = std::rc::Rc::new(std::cell::Cell::new(0));
+        let input = CountingReader {
+            data: md.as_bytes(),
+            read: read.clone(),
+        };
+        let mut output = FirstWrite { read, at: None };
+        md_stream_to_html(
+            io::BufReader::with_capacity(1024, input),
+            &mut output,
+            &MDOptions::default(),
+            BTreeMap::new(),
+        )?;
+        assert!(output.at.unwrap() < md.len() / 100);
+        Ok(())
+    }
+
+    #[test]
+    fn table_of_contents() {
+        let options = MDOptions {
+            toc: true,
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        let toc = |md: &str, options: &MDOptions| {
+            let html = md_string_to_html(md, options);
+            let end = html.find("</nav>").map_or(0, |end| end + "</nav>".len());
+            html[html.find("<nav").unwrap_or(end)..end].to_string()
+        };
+        let item = |id: &str| format!("<li><a href=\"#{}\">{}</a>", id, id);
+        assert_eq!(
+            toc("# a\n## b\n### c\n## d\n# e\n", &options),
+            format!(
+                "<nav class=\"toc\"><ul>{}<ul>{}<ul>{}</li></ul></li>{}</li></ul></li>{}</li></ul></nav>",
+                item("a"),
+                item("b"),
+                item("c"),
+                item("d"),
+                item("e")
+            )
+        );
+        // A later heading shallower than the first still takes the deeper ones under it
+        assert_eq!(
+            toc("## b\n# a\n### c\n## d\n", &options),
+            format!(
+                "<nav class=\"toc\"><ul>{}</li>{}<ul>{}</li>{}</li></ul></li></ul></nav>",
+                item("b"),
+                item("a"),
+                item("c"),
+                item("d")
+            )
+        );
+        let shallow = MDOptions {
+            toc_depth: 1,
+            ..options.clone()
+        };
+        assert_eq!(
+            toc("# a\n## b\n# e\n", &shallow),
+            format!(
+                "<nav class=\"toc\"><ul>{}</li>{}</li></ul></nav>",
+                item("a"),
+                item("e")
+            )
+        );
+
+        let html = md_string_to_html("# Title\n[TOC]\n## a\n", &options);
+        assert!(html.starts_with("<h1 id=\"title\">Title</h1><nav class=\"toc\">"));
+        assert_eq!(html.matches("<nav").count(), 1);
+        // A marker right after a paragraph ends it
+        let html = md_string_to_html("Intro\n[TOC]\n# a\n", &options);
+        assert!(html.starts_with("<p>Intro</p><nav class=\"toc\">"));
+        // Nothing to list leaves out the nav, marker or not
+        assert_eq!(md_string_to_html("Text\n", &options), "<p>Text</p>");
+        assert_eq!(
+            md_string_to_html("[TOC]\n\nText\n", &options),
+            "<p>Text</p>"
+        );
+        assert_eq!(toc("## b\n", &shallow), "");
+    }
+
+    #[test]
+    fn heading_ids_come_from_the_text() {
+        let options = MDOptions {
+            format: Format::Minify,
+            ..MDOptions::default()
+        };
+        assert_eq!(
+            md_string_to_html("# a < b and c\n## x &amp; <em>y</em>\n", &options),
+            "<h1 id=\"a--b-and-c\">a < b and c</h1><h2 id=\"x--y\">x &amp; <em>y</em></h2>"
+        );
+        assert_eq!(
+            md_string_to_html("# !!!\n# ?\n#\n", &options),
+            "<h1 id=\"section\">!!!</h1><h1 id=\"section-1\">?</h1><h1 id=\"section-2\"></h1>"
+        );
+    }
+
+    #[test]
+    fn slugify_headings() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(slugify("  Setup fast "), "setup-fast");
+        assert_eq!(slugify("snake_case & kebab-case"), "snake_case--kebab-case");
+    }
+
+    #[test]
+    fn ascii_heading_ids_fold_accents() {
+        let options = MDOptions {
+            format: Format::Minify,
+            heading_ids: HeadingIds::Ascii,
+            ..MDOptions::default()
+        };
+        assert_eq!(
+            md_string_to_html("# Ünïcode\n# Straße Œuvre\n# 日本語\n# 中文\n", &options),
+            "<h1 id=\"unicode\">Ünïcode</h1><h1 id=\"strasse-oeuvre\">Straße Œuvre</h1>\
+             <h1 id=\"section\">日本語</h1><h1 id=\"section-1\">中文</h1>"
+        );
+        // Decomposed accents are combining marks, which the slug drops anyway
+        assert_eq!(fold_to_ascii(&slugify("Cafe\u{301} Ågård")), "cafe-agard");
+    }
 }
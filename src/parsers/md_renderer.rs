@@ -0,0 +1,115 @@
+// Rendering hooks for Markdown nodes. The parser builds the standard HTML element for every
+// node, children included, and hands it to the `Renderer`, whose result goes into the document
+// instead. Each method defaults to returning the element unchanged, so an implementation only
+// overrides the node types it wants to render differently. The table of contents, the
+// highlighting stylesheet, line breaks, inline HTML and the nodes of syntax extensions have no
+// hook and are written as they are
+
+use std::fmt;
+
+use crate::html::{Element, Node};
+
+/// Custom HTML for Markdown nodes, set on `MDOptions::renderer`
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use flux::html::{Element, Format, Node};
+/// use flux::parsers::md_parser::{md_string_to_html, MDOptions};
+/// use flux::parsers::md_renderer::Renderer;
+///
+/// // Paragraphs get a class for the site's stylesheet
+/// #[derive(Debug)]
+/// struct Styled;
+///
+/// impl Renderer for Styled {
+///     fn paragraph(&self, mut element: Element) -> Node {
+///         element.set_attr("class", "prose");
+///         element.into()
+///     }
+/// }
+///
+/// let options = MDOptions {
+///     format: Format::Minify,
+///     renderer: Arc::new(Styled),
+///     ..MDOptions::default()
+/// };
+/// assert_eq!(
+///     md_string_to_html("Hello\n", &options),
+///     "<p class=\"prose\">Hello</p>"
+/// );
+/// ```
+pub trait Renderer: fmt::Debug + Send + Sync {
+    /// `<h1>` to `<h6>`; `id` is missing when heading ids are switched off
+    fn heading(&self, _level: usize, _id: Option<&str>, element: Element) -> Node {
+        element.into()
+    }
+
+    fn paragraph(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    fn emphasis(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    fn strong(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    fn strikethrough(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    /// `href` is empty when safe mode dropped a script link
+    fn link(&self, _href: &str, element: Element) -> Node {
+        element.into()
+    }
+
+    fn image(&self, _src: &str, _alt: &str, element: Element) -> Node {
+        element.into()
+    }
+
+    fn inline_code(&self, _code: &str, element: Element) -> Node {
+        element.into()
+    }
+
+    /// A fenced block, as `<pre><code>` with the code already highlighted
+    fn code_block(&self, _language: Option<&str>, _code: &str, element: Element) -> Node {
+        element.into()
+    }
+
+    /// Display math is a `<div>` on its own line and a `<span>` in running text
+    fn math(&self, _tex: &str, _display: bool, element: Element) -> Node {
+        element.into()
+    }
+
+    fn list(&self, _ordered: bool, element: Element) -> Node {
+        element.into()
+    }
+
+    fn list_item(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    fn blockquote(&self, element: Element) -> Node {
+        element.into()
+    }
+
+    /// A GitHub alert like `> [!NOTE]`, as a `<div>` holding the title and body; `kind` is
+    /// `note`, `tip`, `important`, `warning` or `caution`
+    fn alert(&self, _kind: &str, element: Element) -> Node {
+        element.into()
+    }
+
+    /// A `::: name` container as a `<div>`; `name` is the rest of the opening line
+    fn container(&self, _name: &str, element: Element) -> Node {
+        element.into()
+    }
+}
+
+/// The standard HTML output, with every node as the parser built it
+#[derive(Debug)]
+pub struct HtmlRenderer;
+
+impl Renderer for HtmlRenderer {}
@@ -0,0 +1,1300 @@
+use std::fmt;
+
+use crate::value::{key_to_string, Date, Datetime, Offset, Time, Value, MAX_DEPTH};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TomlError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for TomlError {}
+
+// While parsing, tables remember how they came to exist, since that decides whether a later
+// header or dotted key may add to them
+#[derive(Clone, Copy, PartialEq)]
+enum TableKind {
+    // Created as the parent of a `[a.b]` header; `[a]` may still define it once
+    Implicit,
+    // Defined by its own `[header]` or `[[header]]`
+    Header,
+    // Created by a dotted key like `a.b = 1`
+    Dotted,
+}
+
+struct Table {
+    kind: TableKind,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    key: String,
+    item: Item,
+    // The line that first defined the key, for redefinition errors
+    line: usize,
+}
+
+enum Item {
+    // Scalars, arrays and inline tables, none of which can be extended later
+    Value(Value),
+    Table(Table),
+    ArrayOfTables(Vec<Table>),
+}
+
+impl Table {
+    fn new(kind: TableKind) -> Self {
+        Table {
+            kind,
+            entries: Vec::new(),
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.entries
+                .into_iter()
+                .map(|entry| {
+                    let value = match entry.item {
+                        Item::Value(value) => value,
+                        Item::Table(table) => table.into_value(),
+                        Item::ArrayOfTables(tables) => {
+                            Value::Array(tables.into_iter().map(Table::into_value).collect())
+                        }
+                    };
+                    (Value::String(entry.key), value)
+                })
+                .collect(),
+        )
+    }
+}
+
+struct TomlParser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    column: usize,
+    root: Table,
+    // The keys of the table the latest header selected; arrays of tables resolve to their
+    // last element
+    current: Vec<String>,
+    // How many arrays and inline tables enclose the current value
+    depth: usize,
+}
+
+impl TomlParser {
+    fn parse_document(&mut self) -> Result<(), TomlError> {
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Ok(()),
+                Some('#') => self.skip_comment()?,
+                Some('\n') => self.advance(),
+                Some('\r') if self.peek_at(1) == Some('\n') => self.advance(),
+                Some('[') => {
+                    self.parse_header()?;
+                    self.expect_line_end()?;
+                }
+                Some(_) => {
+                    let (line, column) = (self.line, self.column);
+                    let keys = self.parse_key()?;
+                    self.skip_whitespace();
+                    self.expect('=')?;
+                    self.skip_whitespace();
+                    let value = self.parse_value()?;
+                    let table = table_at(&mut self.root, &self.current);
+                    insert_value(table, &keys, value, line, column)?;
+                    self.expect_line_end()?;
+                }
+            }
+        }
+    }
+
+    fn parse_header(&mut self) -> Result<(), TomlError> {
+        let (line, column) = (self.line, self.column);
+        self.advance();
+        let array = self.peek() == Some('[');
+        if array {
+            self.advance();
+        }
+        self.skip_whitespace();
+        let keys = self.parse_key()?;
+        self.skip_whitespace();
+        self.expect(']')?;
+        if array {
+            self.expect(']')?;
+        }
+        define_table(&mut self.root, &keys, array, line, column)?;
+        self.current = keys;
+        Ok(())
+    }
+
+    // A key is one or more bare or quoted parts joined by dots
+    fn parse_key(&mut self) -> Result<Vec<String>, TomlError> {
+        let mut keys = Vec::new();
+        loop {
+            let key = match self.peek() {
+                Some('"') => self.parse_basic_string()?,
+                Some('\'') => self.parse_literal_string()?,
+                Some(c) if is_bare_key_char(c) => {
+                    let mut key = String::new();
+                    while let Some(c) = self.peek().filter(|&c| is_bare_key_char(c)) {
+                        key.push(c);
+                        self.advance();
+                    }
+                    key
+                }
+                _ => return Err(self.error("Expected a key")),
+            };
+            keys.push(key);
+            if keys.len() > MAX_DEPTH {
+                return Err(self.error(&format!("Keys of more than {} parts", MAX_DEPTH)));
+            }
+            self.skip_whitespace();
+            if self.peek() != Some('.') {
+                return Ok(keys);
+            }
+            self.advance();
+            self.skip_whitespace();
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, TomlError> {
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                Ok(Value::String(self.parse_multiline_basic_string()?))
+            }
+            Some('"') => Ok(Value::String(self.parse_basic_string()?)),
+            Some('\'') if self.starts_with("'''") => {
+                Ok(Value::String(self.parse_multiline_literal_string()?))
+            }
+            Some('\'') => Ok(Value::String(self.parse_literal_string()?)),
+            Some('[' | '{') if self.depth == MAX_DEPTH => Err(self.error(&format!(
+                "Arrays and inline tables nested more than {} deep",
+                MAX_DEPTH
+            ))),
+            Some('[') => self.parse_nested(TomlParser::parse_array),
+            Some('{') => self.parse_nested(TomlParser::parse_inline_table),
+            Some('t') if self.starts_with("true") => {
+                self.advance_by(4);
+                Ok(Value::Bool(true))
+            }
+            Some('f') if self.starts_with("false") => {
+                self.advance_by(5);
+                Ok(Value::Bool(false))
+            }
+            Some('+' | '-' | '0'..='9' | 'i' | 'n') => self.parse_number_or_datetime(),
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn parse_nested(
+        &mut self,
+        parse: fn(&mut TomlParser) -> Result<Value, TomlError>,
+    ) -> Result<Value, TomlError> {
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_array(&mut self) -> Result<Value, TomlError> {
+        self.advance();
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace_and_newlines()?;
+            if self.peek() == Some(']') {
+                self.advance();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_whitespace_and_newlines()?;
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some(']') => {}
+                _ => return Err(self.error("Expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    // Inline tables sit on one line, take no trailing comma and are complete once closed
+    fn parse_inline_table(&mut self) -> Result<Value, TomlError> {
+        self.advance();
+        let mut table = Table::new(TableKind::Dotted);
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.advance();
+            return Ok(table.into_value());
+        }
+        loop {
+            self.skip_whitespace();
+            let (line, column) = (self.line, self.column);
+            let keys = self.parse_key()?;
+            self.skip_whitespace();
+            self.expect('=')?;
+            self.skip_whitespace();
+            let value = self.parse_value()?;
+            insert_value(&mut table, &keys, value, line, column)?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some('}') => {
+                    self.advance();
+                    return Ok(table.into_value());
+                }
+                _ => return Err(self.error("Expected ',' or '}' in inline table")),
+            }
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, TomlError> {
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some('\\') => text.push(self.parse_escape()?),
+                Some('\n') | None => return Err(self.error("Unterminated string")),
+                Some(c) => {
+                    self.check_control(c)?;
+                    text.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_multiline_basic_string(&mut self) -> Result<String, TomlError> {
+        self.advance_by(3);
+        self.skip_leading_newline();
+        let mut text = String::new();
+        loop {
+            if self.starts_with("\"\"\"") {
+                // Up to two quotes may directly precede the closing delimiter
+                let mut quotes = 3;
+                while quotes < 5 && self.peek_at(quotes) == Some('"') {
+                    quotes += 1;
+                }
+                text.push_str(&"\"".repeat(quotes - 3));
+                self.advance_by(quotes);
+                return Ok(text);
+            }
+            match self.peek() {
+                Some('\\') if self.at_line_ending_backslash() => {
+                    // A backslash at the end of a line trims the break and following whitespace
+                    self.advance();
+                    while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+                        self.advance();
+                    }
+                }
+                Some('\\') => text.push(self.parse_escape()?),
+                None => return Err(self.error("Unterminated multi-line string")),
+                Some(c) => {
+                    self.check_multiline_control(c)?;
+                    if c != '\r' {
+                        text.push(c);
+                    }
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, TomlError> {
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some('\n') | None => return Err(self.error("Unterminated string")),
+                Some(c) => {
+                    self.check_control(c)?;
+                    text.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_multiline_literal_string(&mut self) -> Result<String, TomlError> {
+        self.advance_by(3);
+        self.skip_leading_newline();
+        let mut text = String::new();
+        loop {
+            if self.starts_with("'''") {
+                let mut quotes = 3;
+                while quotes < 5 && self.peek_at(quotes) == Some('\'') {
+                    quotes += 1;
+                }
+                text.push_str(&"'".repeat(quotes - 3));
+                self.advance_by(quotes);
+                return Ok(text);
+            }
+            match self.peek() {
+                None => return Err(self.error("Unterminated multi-line string")),
+                Some(c) => {
+                    self.check_multiline_control(c)?;
+                    if c != '\r' {
+                        text.push(c);
+                    }
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, TomlError> {
+        self.advance();
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("Unterminated escape"))?;
+        self.advance();
+        let escaped = match c {
+            'b' => '\u{8}',
+            't' => '\t',
+            'n' => '\n',
+            'f' => '\u{c}',
+            'r' => '\r',
+            '"' => '"',
+            '\\' => '\\',
+            'u' => self.parse_unicode_escape(4)?,
+            'U' => self.parse_unicode_escape(8)?,
+            _ => return Err(self.error(&format!("Invalid escape '\\{}'", c))),
+        };
+        Ok(escaped)
+    }
+
+    fn parse_unicode_escape(&mut self, digits: usize) -> Result<char, TomlError> {
+        let mut code_point = 0;
+        for _ in 0..digits {
+            let digit = self
+                .peek()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("Invalid unicode escape"))?;
+            code_point = code_point * 16 + digit;
+            self.advance();
+        }
+        char::from_u32(code_point).ok_or_else(|| self.error("Invalid unicode scalar value"))
+    }
+
+    fn parse_number_or_datetime(&mut self) -> Result<Value, TomlError> {
+        let (line, column) = (self.line, self.column);
+        let mut token = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|&c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-' | '.' | ':'))
+        {
+            token.push(c);
+            self.advance();
+        }
+        // A date and time may also be separated by a space
+        if is_date(&token)
+            && self.peek() == Some(' ')
+            && self.peek_at(1).is_some_and(|c| c.is_ascii_digit())
+        {
+            self.advance();
+            token.push('T');
+            while let Some(c) = self
+                .peek()
+                .filter(|&c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | ':'))
+            {
+                token.push(c);
+                self.advance();
+            }
+        }
+        let error = |message: String| TomlError {
+            message,
+            line,
+            column,
+        };
+        if is_date(&token) || token.get(2..3) == Some(":") {
+            return parse_datetime(&token)
+                .map(Value::Datetime)
+                .ok_or_else(|| error(format!("Invalid datetime '{}'", token)));
+        }
+        parse_number(&token).map_err(|message| error(format!("{} '{}'", message, token)))
+    }
+
+    fn skip_leading_newline(&mut self) {
+        if self.peek() == Some('\n') {
+            self.advance();
+        } else if self.starts_with("\r\n") {
+            self.advance_by(2);
+        }
+    }
+
+    fn at_line_ending_backslash(&self) -> bool {
+        let mut offset = 1;
+        while matches!(self.peek_at(offset), Some(' ' | '\t')) {
+            offset += 1;
+        }
+        matches!(self.peek_at(offset), Some('\n' | '\r'))
+    }
+
+    fn check_control(&self, c: char) -> Result<(), TomlError> {
+        if c.is_control() && c != '\t' {
+            return Err(self.error("Control characters must be escaped"));
+        }
+        Ok(())
+    }
+
+    fn check_multiline_control(&self, c: char) -> Result<(), TomlError> {
+        if c == '\n' || (c == '\r' && self.peek_at(1) == Some('\n')) {
+            return Ok(());
+        }
+        self.check_control(c)
+    }
+
+    fn expect_line_end(&mut self) -> Result<(), TomlError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.peek_at(1) == Some('\n') => Ok(()),
+            Some('#') => self.skip_comment(),
+            Some(_) => Err(self.error("Expected a newline after the value")),
+        }
+    }
+
+    fn skip_comment(&mut self) -> Result<(), TomlError> {
+        while let Some(c) = self.peek().filter(|&c| c != '\n') {
+            if c.is_control() && c != '\t' && !(c == '\r' && self.peek_at(1) == Some('\n')) {
+                return Err(self.error("Control characters are not allowed in comments"));
+            }
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.advance();
+        }
+    }
+
+    // Arrays may span lines and hold comments between their items
+    fn skip_whitespace_and_newlines(&mut self) -> Result<(), TomlError> {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\n' | '\r') => self.advance(),
+                Some('#') => self.skip_comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), TomlError> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{}'", expected)));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.index + offset).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.peek() == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        self.index += 1;
+    }
+
+    fn advance_by(&mut self, count: usize) {
+        for _ in 0..count {
+            self.advance();
+        }
+    }
+
+    fn error(&self, message: &str) -> TomlError {
+        TomlError {
+            message: message.to_string(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn table_at<'a>(root: &'a mut Table, keys: &[String]) -> &'a mut Table {
+    let mut table = root;
+    for key in keys {
+        let entry = table.entries.iter_mut().find(|e| e.key == *key).unwrap();
+        table = match &mut entry.item {
+            Item::Table(table) => table,
+            Item::ArrayOfTables(tables) => tables.last_mut().unwrap(),
+            Item::Value(_) => unreachable!("headers only select tables"),
+        };
+    }
+    table
+}
+
+// Handle a `[a.b.c]` or `[[a.b.c]]` header
+fn define_table(
+    root: &mut Table,
+    keys: &[String],
+    array: bool,
+    line: usize,
+    column: usize,
+) -> Result<(), TomlError> {
+    let error = |message: String| TomlError {
+        message,
+        line,
+        column,
+    };
+    let (last, parents) = keys.split_last().unwrap();
+    let mut table = root;
+    for key in parents {
+        if !table.entries.iter().any(|e| e.key == *key) {
+            table.entries.push(Entry {
+                key: key.clone(),
+                item: Item::Table(Table::new(TableKind::Implicit)),
+                line,
+            });
+        }
+        let entry = table.entries.iter_mut().find(|e| e.key == *key).unwrap();
+        table = match &mut entry.item {
+            Item::Table(table) => table,
+            Item::ArrayOfTables(tables) => tables.last_mut().unwrap(),
+            Item::Value(_) => {
+                return Err(error(format!(
+                    "Key '{}' is not a table (defined on line {})",
+                    key, entry.line
+                )))
+            }
+        };
+    }
+    match table.entries.iter_mut().find(|e| e.key == *last) {
+        None => {
+            let item = if array {
+                Item::ArrayOfTables(vec![Table::new(TableKind::Header)])
+            } else {
+                Item::Table(Table::new(TableKind::Header))
+            };
+            table.entries.push(Entry {
+                key: last.clone(),
+                item,
+                line,
+            });
+            Ok(())
+        }
+        Some(entry) => match (&mut entry.item, array) {
+            (Item::Table(table), false) if table.kind == TableKind::Implicit => {
+                table.kind = TableKind::Header;
+                Ok(())
+            }
+            (Item::ArrayOfTables(tables), true) => {
+                tables.push(Table::new(TableKind::Header));
+                Ok(())
+            }
+            _ => Err(error(format!(
+                "Table '{}' is already defined on line {}",
+                keys.join("."),
+                entry.line
+            ))),
+        },
+    }
+}
+
+// Handle `a.b.c = value` within `table`. Dotted keys may only extend tables that dotted keys
+// created themselves
+fn insert_value(
+    table: &mut Table,
+    keys: &[String],
+    value: Value,
+    line: usize,
+    column: usize,
+) -> Result<(), TomlError> {
+    let error = |message: String| TomlError {
+        message,
+        line,
+        column,
+    };
+    let (last, parents) = keys.split_last().unwrap();
+    let mut table = table;
+    for key in parents {
+        if !table.entries.iter().any(|e| e.key == *key) {
+            table.entries.push(Entry {
+                key: key.clone(),
+                item: Item::Table(Table::new(TableKind::Dotted)),
+                line,
+            });
+        }
+        let entry = table.entries.iter_mut().find(|e| e.key == *key).unwrap();
+        table = match &mut entry.item {
+            Item::Table(table) if table.kind == TableKind::Dotted => table,
+            _ => {
+                return Err(error(format!(
+                    "Cannot add keys to '{}', which is defined on line {}",
+                    key, entry.line
+                )))
+            }
+        };
+    }
+    if let Some(entry) = table.entries.iter().find(|e| e.key == *last) {
+        return Err(error(format!(
+            "Duplicate key '{}' (first defined on line {})",
+            last, entry.line
+        )));
+    }
+    table.entries.push(Entry {
+        key: last.clone(),
+        item: Item::Value(value),
+        line,
+    });
+    Ok(())
+}
+
+fn is_date(token: &str) -> bool {
+    let bytes = token.as_bytes();
+    bytes.len() >= 10
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && bytes[..4].iter().all(u8::is_ascii_digit)
+}
+
+fn parse_datetime(token: &str) -> Option<Datetime> {
+    let (date, rest) = if is_date(token) {
+        (Some(parse_date(&token[..10])?), &token[10..])
+    } else {
+        (None, token)
+    };
+    let rest = match (date, rest.chars().next()) {
+        (Some(_), None) => {
+            return Some(Datetime {
+                date,
+                time: None,
+                offset: None,
+            })
+        }
+        (Some(_), Some('T' | 't')) => &rest[1..],
+        (Some(_), Some(_)) => return None,
+        (None, _) => rest,
+    };
+    let offset_start = rest.find(['Z', 'z', '+', '-']).unwrap_or(rest.len());
+    let time = parse_time(&rest[..offset_start])?;
+    // Local times have no offset
+    let offset = match &rest[offset_start..] {
+        "" => None,
+        _ if date.is_none() => return None,
+        "Z" | "z" => Some(Offset::Z),
+        offset => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let (hours, minutes) = (parse_fixed(hours, 2)?, parse_fixed(minutes, 2)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            Some(Offset::Minutes(sign * (hours * 60 + minutes) as i16))
+        }
+    };
+    Some(Datetime {
+        date,
+        time: Some(time),
+        offset,
+    })
+}
+
+fn parse_date(text: &str) -> Option<Date> {
+    let year = parse_fixed(&text[..4], 4)?;
+    let month = parse_fixed(&text[5..7], 2)?;
+    let day = parse_fixed(&text[8..10], 2)?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if day == 0 || day > days_in_month {
+        return None;
+    }
+    Some(Date {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+    })
+}
+
+fn parse_time(text: &str) -> Option<Time> {
+    let (clock, fraction) = match text.split_once('.') {
+        Some((clock, fraction)) => (clock, Some(fraction)),
+        None => (text, None),
+    };
+    let mut parts = clock.split(':');
+    let hour = parse_fixed(parts.next()?, 2)?;
+    let minute = parse_fixed(parts.next()?, 2)?;
+    let second = parse_fixed(parts.next()?, 2)?;
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Precision beyond nanoseconds is truncated
+    let nanosecond = match fraction {
+        Some(digits) if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<9}", &digits[..digits.len().min(9)])
+                .parse()
+                .ok()?
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+    Some(Time {
+        hour: hour as u8,
+        minute: minute as u8,
+        second: second as u8,
+        nanosecond,
+    })
+}
+
+fn parse_fixed(text: &str, digits: usize) -> Option<u32> {
+    if text.len() != digits || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn parse_number(token: &str) -> Result<Value, &'static str> {
+    let (sign, unsigned) = match token.strip_prefix(['+', '-']) {
+        Some(rest) => (&token[..1], rest),
+        None => ("", token),
+    };
+    match unsigned {
+        "inf" => {
+            let f = if sign == "-" {
+                f64::NEG_INFINITY
+            } else {
+                f64::INFINITY
+            };
+            return Ok(Value::Float(f));
+        }
+        "nan" => return Ok(Value::Float(f64::NAN)),
+        _ => {}
+    }
+    for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+        if let Some(digits) = unsigned.strip_prefix(prefix) {
+            if !sign.is_empty() {
+                return Err("Signs are not allowed on non-decimal integers");
+            }
+            let digits = strip_underscores(digits, radix).ok_or("Invalid integer")?;
+            return i64::from_str_radix(&digits, radix)
+                .map(Value::Integer)
+                .map_err(|_| "Integer out of range");
+        }
+    }
+    let is_float = unsigned.contains(['.', 'e', 'E']);
+    if !is_float {
+        let digits = strip_underscores(unsigned, 10).ok_or("Invalid number")?;
+        if digits.len() > 1 && digits.starts_with('0') {
+            return Err("Leading zeros are not allowed");
+        }
+        return format!("{}{}", sign, digits)
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| "Integer out of range");
+    }
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let whole = strip_underscores(whole, 10).ok_or("Invalid float")?;
+    if whole.len() > 1 && whole.starts_with('0') {
+        return Err("Leading zeros are not allowed");
+    }
+    let mut normalized = format!("{}{}", sign, whole);
+    if let Some(fraction) = fraction {
+        normalized.push('.');
+        normalized.push_str(&strip_underscores(fraction, 10).ok_or("Invalid float")?);
+    }
+    if let Some(exponent) = exponent {
+        let (exponent_sign, digits) = match exponent.strip_prefix(['+', '-']) {
+            Some(digits) => (&exponent[..1], digits),
+            None => ("", exponent),
+        };
+        normalized.push('e');
+        normalized.push_str(exponent_sign);
+        normalized.push_str(&strip_underscores(digits, 10).ok_or("Invalid float")?);
+    }
+    normalized
+        .parse()
+        .map(Value::Float)
+        .map_err(|_| "Invalid float")
+}
+
+// Underscores are allowed only between two digits
+fn strip_underscores(text: &str, radix: u32) -> Option<String> {
+    if text.is_empty() || text.starts_with('_') || text.ends_with('_') || text.contains("__") {
+        return None;
+    }
+    let digits: String = text.chars().filter(|&c| c != '_').collect();
+    digits.chars().all(|c| c.is_digit(radix)).then_some(digits)
+}
+
+pub fn parse(input: &str) -> Result<Value, TomlError> {
+    let mut parser = TomlParser {
+        chars: input.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 1,
+        root: Table::new(TableKind::Header),
+        current: Vec::new(),
+        depth: 0,
+    };
+    parser.parse_document()?;
+    Ok(parser.root.into_value())
+}
+
+const INDENT: &str = "    ";
+const MAX_LINE: usize = 80;
+// Nested tables with at most this many flat entries, and short enough, are written inline
+const MAX_INLINE_ENTRIES: usize = 3;
+const MAX_INLINE_WIDTH: usize = 60;
+
+/// Serialize a table as a TOML document. Top-level tables always get a `[section]` header,
+/// while small, flat tables deeper down are written inline. TOML has no null, so null values
+/// are left out; `value::check` reports them beforehand
+pub fn to_string(value: &Value) -> Result<String, String> {
+    let Value::Map(entries) = value else {
+        return Err("A TOML document must be a table at the top level".to_string());
+    };
+    let mut out = String::new();
+    write_table(entries, &[], &mut out);
+    Ok(out)
+}
+
+fn write_table(entries: &[(Value, Value)], path: &[String], out: &mut String) {
+    let depth = path.len();
+    // Plain key/value pairs have to come before any sub-table header
+    for (key, value) in present_entries(entries) {
+        if !is_section(value, depth + 1) {
+            out.push_str(&format!("{} = {}\n", format_key(&key), format_value(value)));
+        }
+    }
+    for (key, value) in present_entries(entries) {
+        if !is_section(value, depth + 1) {
+            continue;
+        }
+        let mut child_path = path.to_vec();
+        child_path.push(key);
+        let header = child_path
+            .iter()
+            .map(|k| format_key(k))
+            .collect::<Vec<String>>()
+            .join(".");
+        match value {
+            Value::Map(child) => {
+                // Tables holding only sub-tables are implied by the headers below them
+                let has_values = present_entries(child).any(|(_, v)| !is_section(v, depth + 2));
+                if has_values || present_entries(child).next().is_none() {
+                    write_separator(out);
+                    out.push_str(&format!("[{}]\n", header));
+                }
+                write_table(child, &child_path, out);
+            }
+            Value::Array(tables) => {
+                for table in tables {
+                    if let Value::Map(child) = table {
+                        write_separator(out);
+                        out.push_str(&format!("[[{}]]\n", header));
+                        write_table(child, &child_path, out);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Entries with their keys as strings, leaving out null values
+fn present_entries(entries: &[(Value, Value)]) -> impl Iterator<Item = (String, &Value)> {
+    entries.iter().filter_map(|(key, value)| match value {
+        Value::Null => None,
+        _ => Some((key_to_string(key)?, value)),
+    })
+}
+
+fn write_separator(out: &mut String) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+}
+
+// Whether a value at `depth` (1 for top-level keys) is written under its own header
+fn is_section(value: &Value, depth: usize) -> bool {
+    match value {
+        Value::Map(entries) => depth == 1 || !is_inline_candidate(entries),
+        Value::Array(items) => {
+            !items.is_empty() && items.iter().all(|i| matches!(i, Value::Map(_)))
+        }
+        _ => false,
+    }
+}
+
+fn is_inline_candidate(entries: &[(Value, Value)]) -> bool {
+    let flat = present_entries(entries)
+        .all(|(_, value)| !matches!(value, Value::Map(_)) && !is_section(value, 2));
+    flat && present_entries(entries).count() <= MAX_INLINE_ENTRIES
+        && format_inline_table(entries).len() <= MAX_INLINE_WIDTH
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) if s.contains('\n') => format_multiline_string(s),
+        Value::Array(items) => {
+            let single_line = format_inline(value);
+            if single_line.len() <= MAX_LINE || items.is_empty() {
+                return single_line;
+            }
+            let mut out = String::from("[\n");
+            for item in items.iter().filter(|item| **item != Value::Null) {
+                out.push_str(&format!("{}{},\n", INDENT, format_inline(item)));
+            }
+            out.push(']');
+            out
+        }
+        _ => format_inline(value),
+    }
+}
+
+fn format_inline(value: &Value) -> String {
+    match value {
+        Value::String(s) => format_string(s),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => format_float(*f),
+        Value::Bool(b) => b.to_string(),
+        Value::Datetime(datetime) => datetime.to_string(),
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .filter(|item| **item != Value::Null)
+                .map(format_inline)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Value::Map(entries) => format_inline_table(entries),
+        Value::Null => String::new(),
+    }
+}
+
+fn format_inline_table(entries: &[(Value, Value)]) -> String {
+    if present_entries(entries).next().is_none() {
+        return "{}".to_string();
+    }
+    let members = present_entries(entries)
+        .map(|(key, value)| format!("{} = {}", format_key(&key), format_inline(value)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!("{{ {} }}", members)
+}
+
+fn format_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        key.to_string()
+    } else {
+        format_string(key)
+    }
+}
+
+fn format_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        push_escaped(c, &mut quoted);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_multiline_string(s: &str) -> String {
+    let mut quoted = String::from("\"\"\"\n");
+    for c in s.chars() {
+        if c == '\n' {
+            quoted.push('\n');
+        } else {
+            push_escaped(c, &mut quoted);
+        }
+    }
+    quoted.push_str("\"\"\"");
+    quoted
+}
+
+fn push_escaped(c: char, out: &mut String) {
+    match c {
+        '"' => out.push_str("\\\""),
+        '\\' => out.push_str("\\\\"),
+        '\n' => out.push_str("\\n"),
+        '\t' => out.push_str("\\t"),
+        '\r' => out.push_str("\\r"),
+        c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+        c => out.push(c),
+    }
+}
+
+fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        "nan".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        let formatted = format!("{:?}", f);
+        if formatted.contains(['.', 'e', 'E']) {
+            formatted
+        } else {
+            format!("{}.0", formatted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (Value::String(k.to_string()), v))
+                .collect(),
+        )
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn parses_tables_keys_and_values() {
+        let input = r#"
+# Package metadata
+title = "TOML \"example\""
+"quoted key" = 'C:\path'
+site.owner.name = "Tom"
+numbers = [0xff, 0o17, 0b101, 1_000, -3.5e2, +inf]
+lines = """
+one \
+  two
+three"""
+raw = '''
+keep \n'''
+when = 1979-05-27 07:32:00.5-08:00
+day = 1979-05-27
+
+[servers.alpha]
+ip = "10.0.0.1"
+roles = { web = true, db.primary = false }
+
+[[products]]
+name = "Hammer"
+
+[[products]]
+name = "Nail"
+[products.size]
+mm = 10
+"#;
+        let datetime = Datetime {
+            date: Some(Date {
+                year: 1979,
+                month: 5,
+                day: 27,
+            }),
+            time: Some(Time {
+                hour: 7,
+                minute: 32,
+                second: 0,
+                nanosecond: 500_000_000,
+            }),
+            offset: Some(Offset::Minutes(-480)),
+        };
+        let expected = table(vec![
+            ("title", string("TOML \"example\"")),
+            ("quoted key", string("C:\\path")),
+            (
+                "site",
+                table(vec![("owner", table(vec![("name", string("Tom"))]))]),
+            ),
+            (
+                "numbers",
+                Value::Array(vec![
+                    Value::Integer(255),
+                    Value::Integer(15),
+                    Value::Integer(5),
+                    Value::Integer(1000),
+                    Value::Float(-350.0),
+                    Value::Float(f64::INFINITY),
+                ]),
+            ),
+            ("lines", string("one two\nthree")),
+            ("raw", string("keep \\n")),
+            ("when", Value::Datetime(datetime)),
+            (
+                "day",
+                Value::Datetime(Datetime {
+                    time: None,
+                    offset: None,
+                    ..datetime
+                }),
+            ),
+            (
+                "servers",
+                table(vec![(
+                    "alpha",
+                    table(vec![
+                        ("ip", string("10.0.0.1")),
+                        (
+                            "roles",
+                            table(vec![
+                                ("web", Value::Bool(true)),
+                                ("db", table(vec![("primary", Value::Bool(false))])),
+                            ]),
+                        ),
+                    ]),
+                )]),
+            ),
+            (
+                "products",
+                Value::Array(vec![
+                    table(vec![("name", string("Hammer"))]),
+                    table(vec![
+                        ("name", string("Nail")),
+                        ("size", table(vec![("mm", Value::Integer(10))])),
+                    ]),
+                ]),
+            ),
+        ]);
+        assert_eq!(parse(input).unwrap(), expected);
+        assert_eq!(datetime.to_string(), "1979-05-27T07:32:00.5-08:00");
+    }
+
+    #[test]
+    fn rejects_duplicates_and_redefinitions() {
+        let cases = [
+            (
+                "a = 1\nb = 2\na = 3",
+                3,
+                "Duplicate key 'a' (first defined on line 1)",
+            ),
+            (
+                "[a]\nx = 1\n[a]",
+                3,
+                "Table 'a' is already defined on line 1",
+            ),
+            ("a.b = 1\n[a]", 2, "Table 'a' is already defined on line 1"),
+            (
+                "a = { b = 1 }\n[a.c]",
+                2,
+                "Key 'a' is not a table (defined on line 1)",
+            ),
+            (
+                "[a.b.c]\n[a]\nb.d = 1",
+                3,
+                "Cannot add keys to 'b', which is defined on line 1",
+            ),
+            ("[[a]]\n[a]", 2, "Table 'a' is already defined on line 1"),
+        ];
+        for (input, line, message) in cases {
+            let error = parse(input).unwrap_err();
+            assert_eq!((error.line, error.message.as_str()), (line, message));
+        }
+        for input in [
+            "a = 01",
+            "a = 1__0",
+            "a = 2023-02-29",
+            "a = \"open",
+            "a = 1 b = 2",
+        ] {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+        // Dotted-key tables can still gain sub-tables from headers
+        assert!(parse("[fruit]\napple.color = 1\n[fruit.apple.texture]\nsmooth = true").is_ok());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let error = parse(&format!("a = {}", "[".repeat(200_000))).unwrap_err();
+        assert_eq!((error.line, error.column), (1, MAX_DEPTH + 5));
+        let error = parse(&format!("a = {}", "{b = ".repeat(200_000))).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 5 * MAX_DEPTH + 5));
+        let dotted = vec!["k"; 200_000].join(".");
+        let error = parse(&format!("{} = 1", dotted)).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 2 * MAX_DEPTH + 2));
+        assert!(parse(&format!("[{}]", dotted)).is_err());
+        let deepest = format!("a = {}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&deepest).is_ok());
+    }
+
+    #[test]
+    fn serializes_sections_and_inline_tables() {
+        let value = table(vec![
+            ("name", string("flux")),
+            (
+                "package",
+                table(vec![
+                    ("version", string("0.1.0")),
+                    ("notes", string("a\nb")),
+                ]),
+            ),
+            (
+                "dependencies",
+                table(vec![
+                    (
+                        "clap",
+                        table(vec![
+                            ("version", string("4")),
+                            ("features", Value::Array(vec![string("derive")])),
+                        ]),
+                    ),
+                    ("empty", table(vec![])),
+                ]),
+            ),
+            (
+                "bin",
+                Value::Array(vec![table(vec![("name", string("flux"))])]),
+            ),
+        ]);
+        let toml = to_string(&value).unwrap();
+        assert_eq!(
+            toml,
+            "name = \"flux\"\n\n[package]\nversion = \"0.1.0\"\nnotes = \"\"\"\na\nb\"\"\"\n\n\
+             [dependencies]\nclap = { version = \"4\", features = [\"derive\"] }\nempty = {}\n\n\
+             [[bin]]\nname = \"flux\"\n"
+        );
+        assert_eq!(parse(&toml).unwrap(), value);
+    }
+}
@@ -0,0 +1,1232 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::value::{Value, MAX_DEPTH};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct YamlError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for YamlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for YamlError {}
+
+struct YamlParser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    // 0-based, since it doubles as the indentation of whatever starts here
+    column: usize,
+    anchors: HashMap<String, Value>,
+    // How many collections enclose the current node
+    depth: usize,
+}
+
+impl YamlParser {
+    fn parse_stream(&mut self) -> Result<Vec<(Value, usize)>, YamlError> {
+        let mut documents = Vec::new();
+        loop {
+            self.skip_to_next_content();
+            // Directives like %YAML 1.2 only matter to the document that follows
+            while self.column == 0 && self.peek() == Some('%') {
+                self.skip_line();
+                self.skip_to_next_content();
+            }
+            if self.peek().is_none() {
+                break;
+            }
+            let start_line = self.line;
+            if self.at_document_marker("---") {
+                self.advance_by(3);
+            }
+            self.skip_to_next_content();
+            let value = if self.peek().is_none()
+                || self.at_document_marker("---")
+                || self.at_document_marker("...")
+            {
+                Value::Null
+            } else {
+                self.parse_node(-1, true)?
+            };
+            documents.push((value, start_line));
+            self.skip_to_next_content();
+            if self.at_document_marker("...") {
+                self.advance_by(3);
+            } else if self.peek().is_some() && !self.at_document_marker("---") {
+                return Err(self.error("Unexpected content after the end of the document"));
+            }
+        }
+        Ok(documents)
+    }
+
+    // Parse the node starting at the current position. Nodes that start on a later line must
+    // be indented further than `parent_indent`. `allow_key` is false for inline mapping values,
+    // where `a: b: c` is not a nested mapping
+    fn parse_node(&mut self, parent_indent: isize, allow_key: bool) -> Result<Value, YamlError> {
+        self.skip_inline_spaces();
+        if self.at_line_end() {
+            self.skip_to_next_content();
+            if self.at_block_end(parent_indent) {
+                return Ok(Value::Null);
+            }
+        }
+        let mut anchor = None;
+        let mut tag = None;
+        loop {
+            match self.peek() {
+                Some('&') => {
+                    self.advance();
+                    anchor = Some(self.read_name());
+                }
+                Some('!') => tag = Some(self.read_name()),
+                _ => break,
+            }
+            self.skip_inline_spaces();
+        }
+        let value = if (anchor.is_some() || tag.is_some()) && self.at_line_end() {
+            // The properties sit on their own line above the node they describe
+            self.skip_to_next_content();
+            if self.at_block_end(parent_indent) {
+                Value::Null
+            } else {
+                self.parse_nested(|parser| parser.parse_node(parent_indent, true))?
+            }
+        } else {
+            self.parse_node_content(parent_indent, allow_key, tag.as_deref())?
+        };
+        if let Some(anchor) = anchor {
+            self.anchors.insert(anchor, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn parse_node_content(
+        &mut self,
+        parent_indent: isize,
+        allow_key: bool,
+        tag: Option<&str>,
+    ) -> Result<Value, YamlError> {
+        let indent = self.column;
+        match self.peek() {
+            Some('*') => {
+                self.advance();
+                let (line, column) = (self.line, self.column);
+                let name = self.read_name();
+                match self.anchors.get(&name) {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(YamlError {
+                        message: format!("Unknown alias '{}'", name),
+                        line,
+                        column,
+                    }),
+                }
+            }
+            Some('-') if self.at_sequence_entry() => {
+                self.parse_nested(|parser| parser.parse_block_sequence(indent))
+            }
+            Some('|' | '>') => self.parse_block_scalar(parent_indent),
+            Some('[' | '{') => self.parse_flow_node(),
+            Some('?') if self.at_sequence_entry_with('?') => {
+                Err(self.error("Complex mapping keys are not supported"))
+            }
+            Some('"' | '\'') => {
+                let scalar = Value::String(self.parse_quoted()?);
+                self.skip_inline_spaces();
+                if allow_key && self.at_mapping_colon() {
+                    self.parse_nested(|parser| parser.parse_block_mapping(indent, scalar))
+                } else {
+                    Ok(scalar)
+                }
+            }
+            _ => {
+                let plain = self.read_plain_scalar(false);
+                if allow_key && self.at_mapping_colon() {
+                    let key = resolve_plain(&plain, None);
+                    return self.parse_nested(|parser| parser.parse_block_mapping(indent, key));
+                }
+                if plain.is_empty() {
+                    return Err(self.error("Expected a value"));
+                }
+                let plain = self.read_plain_continuation(plain, parent_indent);
+                Ok(resolve_plain(&plain, tag))
+            }
+        }
+    }
+
+    // Parse a collection or other node inside the current one, failing past `MAX_DEPTH`
+    fn parse_nested(
+        &mut self,
+        parse: impl FnOnce(&mut YamlParser) -> Result<Value, YamlError>,
+    ) -> Result<Value, YamlError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!("Collections nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_block_mapping(&mut self, indent: usize, first_key: Value) -> Result<Value, YamlError> {
+        let mut entries = Vec::new();
+        let mut key = first_key;
+        loop {
+            // Skip the ':' after the key
+            self.advance();
+            let value = self.parse_mapping_value(indent)?;
+            insert_entry(&mut entries, key, value);
+            self.skip_to_next_content();
+            if self.at_block_end(indent as isize - 1) || self.column != indent {
+                if self.peek().is_some() && self.column > indent && !self.at_any_marker() {
+                    return Err(self.error("Bad indentation of a mapping entry"));
+                }
+                break;
+            }
+            key = self.parse_key()?;
+        }
+        Ok(Value::Map(entries))
+    }
+
+    fn parse_key(&mut self) -> Result<Value, YamlError> {
+        let key = match self.peek() {
+            Some('"' | '\'') => Value::String(self.parse_quoted()?),
+            Some('[' | '{') => self.parse_flow_node()?,
+            _ => resolve_plain(&self.read_plain_scalar(false), None),
+        };
+        self.skip_inline_spaces();
+        if !self.at_mapping_colon() {
+            return Err(self.error("Expected ':' after a mapping key"));
+        }
+        Ok(key)
+    }
+
+    fn parse_mapping_value(&mut self, indent: usize) -> Result<Value, YamlError> {
+        self.skip_inline_spaces();
+        if !self.at_line_end() {
+            return self.parse_node(indent as isize, false);
+        }
+        self.skip_to_next_content();
+        // A sequence may sit at the same indentation as the key that owns it
+        if self.column == indent && self.at_sequence_entry() && !self.at_any_marker() {
+            return self.parse_nested(|parser| parser.parse_block_sequence(indent));
+        }
+        if self.at_block_end(indent as isize) {
+            return Ok(Value::Null);
+        }
+        self.parse_node(indent as isize, true)
+    }
+
+    fn parse_block_sequence(&mut self, indent: usize) -> Result<Value, YamlError> {
+        let mut items = Vec::new();
+        loop {
+            // Skip the '-' indicator
+            self.advance();
+            self.skip_inline_spaces();
+            let item = if self.at_line_end() {
+                self.skip_to_next_content();
+                if self.at_block_end(indent as isize) {
+                    Value::Null
+                } else {
+                    self.parse_node(indent as isize, true)?
+                }
+            } else {
+                self.parse_node(indent as isize, true)?
+            };
+            items.push(item);
+            self.skip_to_next_content();
+            if self.at_block_end(indent as isize - 1)
+                || self.column != indent
+                || !self.at_sequence_entry()
+            {
+                if self.peek().is_some() && self.column > indent && !self.at_any_marker() {
+                    return Err(self.error("Bad indentation of a sequence entry"));
+                }
+                break;
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    // `|` keeps line breaks and `>` folds them into spaces. Either may be followed by a
+    // chomping indicator (`-` strips the final line break, `+` keeps trailing blank lines) and
+    // an explicit indentation digit
+    fn parse_block_scalar(&mut self, parent_indent: isize) -> Result<Value, YamlError> {
+        let folded = self.peek() == Some('>');
+        self.advance();
+        let mut chomping = '=';
+        let mut explicit_indent = None;
+        while let Some(c) = self.peek() {
+            match c {
+                '-' | '+' => chomping = c,
+                '1'..='9' => explicit_indent = c.to_digit(10).map(|d| d as usize),
+                _ => break,
+            }
+            self.advance();
+        }
+        self.skip_inline_spaces();
+        if self.peek() == Some('#') {
+            self.skip_line_content();
+        }
+        if !self.at_line_end() {
+            return Err(self.error("Unexpected text after a block scalar indicator"));
+        }
+        if self.peek() == Some('\n') {
+            self.advance();
+        }
+
+        let base = parent_indent.max(0) as usize;
+        let mut content_indent = explicit_indent.map(|d| base + d);
+        let mut lines: Vec<String> = Vec::new();
+        loop {
+            let line_start = self.index;
+            let mut spaces = 0;
+            while self.chars.get(line_start + spaces) == Some(&' ') {
+                spaces += 1;
+            }
+            let rest = self.chars.get(line_start + spaces).copied();
+            let blank = matches!(rest, None | Some('\n' | '\r'));
+            if rest.is_none() && spaces == 0 {
+                break;
+            }
+            if !blank {
+                let indent = *content_indent.get_or_insert(spaces);
+                if spaces < indent || spaces as isize <= parent_indent {
+                    break;
+                }
+                if spaces == 0 && self.at_any_marker() {
+                    break;
+                }
+            }
+            let line_text: String = {
+                let skip = content_indent.map_or(spaces, |indent| indent.min(spaces));
+                let mut end = line_start + skip;
+                while self.chars.get(end).is_some_and(|&c| c != '\n') {
+                    end += 1;
+                }
+                self.chars[line_start + skip..end].iter().collect()
+            };
+            lines.push(line_text.trim_end_matches('\r').to_string());
+            self.skip_line();
+            if self.peek().is_none() {
+                break;
+            }
+        }
+
+        let trailing_blank = lines
+            .iter()
+            .rev()
+            .take_while(|l| l.trim().is_empty())
+            .count();
+        let content_lines = &lines[..lines.len() - trailing_blank];
+        let mut text = if folded {
+            fold_lines(content_lines)
+        } else {
+            content_lines.join("\n")
+        };
+        match chomping {
+            '-' => {}
+            '+' => {
+                let breaks = trailing_blank + usize::from(!content_lines.is_empty());
+                text.push_str(&"\n".repeat(breaks));
+            }
+            _ if !content_lines.is_empty() => text.push('\n'),
+            _ => {}
+        }
+        Ok(Value::String(text))
+    }
+
+    fn parse_flow_node(&mut self) -> Result<Value, YamlError> {
+        self.skip_flow_whitespace();
+        let mut anchor = None;
+        let mut tag = None;
+        loop {
+            match self.peek() {
+                Some('&') => {
+                    self.advance();
+                    anchor = Some(self.read_name());
+                }
+                Some('!') => tag = Some(self.read_name()),
+                _ => break,
+            }
+            self.skip_flow_whitespace();
+        }
+        let value = match self.peek() {
+            Some('[') => self.parse_nested(YamlParser::parse_flow_sequence)?,
+            Some('{') => self.parse_nested(YamlParser::parse_flow_mapping)?,
+            Some('"' | '\'') => Value::String(self.parse_quoted()?),
+            Some('*') => {
+                self.advance();
+                let name = self.read_name();
+                match self.anchors.get(&name) {
+                    Some(value) => value.clone(),
+                    None => return Err(self.error(&format!("Unknown alias '{}'", name))),
+                }
+            }
+            Some(',' | ']' | '}') | None => Value::Null,
+            _ => resolve_plain(&self.read_plain_scalar(true), tag.as_deref()),
+        };
+        if let Some(anchor) = anchor {
+            self.anchors.insert(anchor, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn parse_flow_sequence(&mut self) -> Result<Value, YamlError> {
+        self.advance();
+        let mut items = Vec::new();
+        loop {
+            self.skip_flow_whitespace();
+            match self.peek() {
+                Some(']') => {
+                    self.advance();
+                    return Ok(Value::Array(items));
+                }
+                None => return Err(self.error("Unterminated flow sequence")),
+                _ => {}
+            }
+            let item = self.parse_flow_node()?;
+            self.skip_flow_whitespace();
+            // `[a: b]` is a sequence holding a single-pair mapping
+            let item = if self.peek() == Some(':') {
+                self.advance();
+                let value = self.parse_flow_node()?;
+                self.skip_flow_whitespace();
+                Value::Map(vec![(item, value)])
+            } else {
+                item
+            };
+            items.push(item);
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some(']') => {}
+                _ => return Err(self.error("Expected ',' or ']' in flow sequence")),
+            }
+        }
+    }
+
+    fn parse_flow_mapping(&mut self) -> Result<Value, YamlError> {
+        self.advance();
+        let mut entries = Vec::new();
+        loop {
+            self.skip_flow_whitespace();
+            match self.peek() {
+                Some('}') => {
+                    self.advance();
+                    return Ok(Value::Map(entries));
+                }
+                None => return Err(self.error("Unterminated flow mapping")),
+                _ => {}
+            }
+            let key = self.parse_flow_node()?;
+            self.skip_flow_whitespace();
+            let value = if self.peek() == Some(':') {
+                self.advance();
+                self.parse_flow_node()?
+            } else {
+                Value::Null
+            };
+            insert_entry(&mut entries, key, value);
+            self.skip_flow_whitespace();
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some('}') => {}
+                _ => return Err(self.error("Expected ',' or '}' in flow mapping")),
+            }
+        }
+    }
+
+    fn parse_quoted(&mut self) -> Result<String, YamlError> {
+        let quote = self.peek().unwrap();
+        let (line, column) = (self.line, self.column + 1);
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(YamlError {
+                        message: "Unterminated quoted scalar".to_string(),
+                        line,
+                        column,
+                    })
+                }
+                Some('\'') if quote == '\'' && self.peek_at(1) == Some('\'') => {
+                    text.push('\'');
+                    self.advance_by(2);
+                }
+                Some(c) if c == quote => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some('\\') if quote == '"' => {
+                    self.advance();
+                    if self.peek() == Some('\n') {
+                        // An escaped line break joins the lines without a space
+                        self.advance();
+                        self.skip_inline_spaces();
+                    } else {
+                        text.push(self.parse_escape()?);
+                    }
+                }
+                Some('\n') => {
+                    // Line folding: a single break becomes a space, blank lines become breaks
+                    let trimmed = text.trim_end_matches([' ', '\t']).len();
+                    text.truncate(trimmed);
+                    self.advance();
+                    let mut breaks = 0;
+                    loop {
+                        self.skip_inline_spaces();
+                        if self.peek() == Some('\n') {
+                            breaks += 1;
+                            self.advance();
+                        } else {
+                            break;
+                        }
+                    }
+                    if breaks == 0 {
+                        text.push(' ');
+                    } else {
+                        text.push_str(&"\n".repeat(breaks));
+                    }
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, YamlError> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("Unterminated escape"))?;
+        self.advance();
+        let escaped = match c {
+            '0' => '\0',
+            'a' => '\u{7}',
+            'b' => '\u{8}',
+            't' | '\t' => '\t',
+            'n' => '\n',
+            'v' => '\u{b}',
+            'f' => '\u{c}',
+            'r' => '\r',
+            'e' => '\u{1b}',
+            ' ' => ' ',
+            '"' => '"',
+            '/' => '/',
+            '\\' => '\\',
+            'N' => '\u{85}',
+            '_' => '\u{a0}',
+            'L' => '\u{2028}',
+            'P' => '\u{2029}',
+            'x' => self.parse_hex_escape(2)?,
+            'u' => self.parse_hex_escape(4)?,
+            'U' => self.parse_hex_escape(8)?,
+            _ => return Err(self.error(&format!("Invalid escape '\\{}'", c))),
+        };
+        Ok(escaped)
+    }
+
+    fn parse_hex_escape(&mut self, digits: usize) -> Result<char, YamlError> {
+        let mut code_point = 0;
+        for _ in 0..digits {
+            let digit = self
+                .peek()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("Invalid hex escape"))?;
+            code_point = code_point * 16 + digit;
+            self.advance();
+        }
+        char::from_u32(code_point).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    // A plain scalar runs to the end of the line, a ': ' or a ' #' comment. Inside flow
+    // collections it also stops at ',', '[', ']', '{' and '}'
+    fn read_plain_scalar(&mut self, flow: bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            let next = self.peek_at(1);
+            let ends_value = |n: Option<char>| {
+                matches!(n, None | Some(' ' | '\t' | '\n' | '\r'))
+                    || (flow && matches!(n, Some(',' | '[' | ']' | '{' | '}')))
+            };
+            if c == '\n'
+                || c == '\r'
+                || (c == ':' && ends_value(next))
+                || (c == '#' && text.ends_with([' ', '\t']))
+                || (flow && matches!(c, ',' | '[' | ']' | '{' | '}'))
+            {
+                break;
+            }
+            if flow && c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.advance();
+        }
+        text.trim_end().to_string()
+    }
+
+    // Plain scalars may continue on following lines that are indented past their parent
+    fn read_plain_continuation(&mut self, mut text: String, parent_indent: isize) -> String {
+        loop {
+            let checkpoint = (self.index, self.line, self.column);
+            self.skip_inline_spaces();
+            if self.peek() == Some('#') || !self.at_line_end() {
+                self.restore(checkpoint);
+                return text;
+            }
+            let mut breaks = 0;
+            while self.peek().is_some() && self.at_line_end() {
+                self.skip_line();
+                breaks += 1;
+                self.skip_inline_spaces();
+            }
+            let continues = self.peek().is_some()
+                && self.column as isize > parent_indent
+                && self.peek() != Some('#')
+                && !self.at_any_marker()
+                && !self.line_has_mapping_colon();
+            if !continues {
+                self.restore(checkpoint);
+                return text;
+            }
+            let more = self.read_plain_scalar(false);
+            if breaks > 1 {
+                text.push_str(&"\n".repeat(breaks - 1));
+            } else {
+                text.push(' ');
+            }
+            text.push_str(&more);
+        }
+    }
+
+    fn line_has_mapping_colon(&self) -> bool {
+        let mut i = self.index;
+        while let Some(&c) = self.chars.get(i) {
+            if c == '\n' {
+                return false;
+            }
+            if c == ':' && matches!(self.chars.get(i + 1), None | Some(' ' | '\t' | '\n')) {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    fn read_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || matches!(c, ',' | '[' | ']' | '{' | '}') {
+                break;
+            }
+            name.push(c);
+            self.advance();
+        }
+        name
+    }
+
+    fn at_mapping_colon(&self) -> bool {
+        self.peek() == Some(':') && matches!(self.peek_at(1), None | Some(' ' | '\t' | '\n' | '\r'))
+    }
+
+    fn at_sequence_entry(&self) -> bool {
+        self.at_sequence_entry_with('-')
+    }
+
+    fn at_sequence_entry_with(&self, indicator: char) -> bool {
+        self.peek() == Some(indicator)
+            && matches!(self.peek_at(1), None | Some(' ' | '\t' | '\n' | '\r'))
+    }
+
+    fn at_document_marker(&self, marker: &str) -> bool {
+        self.column == 0
+            && marker
+                .chars()
+                .enumerate()
+                .all(|(i, c)| self.peek_at(i) == Some(c))
+            && matches!(self.peek_at(3), None | Some(' ' | '\t' | '\n' | '\r'))
+    }
+
+    fn at_any_marker(&self) -> bool {
+        self.at_document_marker("---") || self.at_document_marker("...")
+    }
+
+    // After skipping to content, true if that content does not belong to a block whose
+    // parent is at `parent_indent`
+    fn at_block_end(&self, parent_indent: isize) -> bool {
+        self.peek().is_none() || self.at_any_marker() || self.column as isize <= parent_indent
+    }
+
+    fn at_line_end(&self) -> bool {
+        matches!(self.peek(), None | Some('\n' | '\r' | '#'))
+    }
+
+    fn skip_inline_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.advance();
+        }
+    }
+
+    // Skip whitespace, line breaks and comments up to the next meaningful character
+    fn skip_to_next_content(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\n' | '\r') => self.advance(),
+                Some('#') => self.skip_line_content(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_flow_whitespace(&mut self) {
+        self.skip_to_next_content();
+    }
+
+    fn skip_line_content(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.advance();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        self.skip_line_content();
+        if self.peek() == Some('\n') {
+            self.advance();
+        }
+    }
+
+    fn restore(&mut self, (index, line, column): (usize, usize, usize)) {
+        self.index = index;
+        self.line = line;
+        self.column = column;
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.index + offset).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.peek() == Some('\n') {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        self.index += 1;
+    }
+
+    fn advance_by(&mut self, count: usize) {
+        for _ in 0..count {
+            self.advance();
+        }
+    }
+
+    fn error(&self, message: &str) -> YamlError {
+        YamlError {
+            message: message.to_string(),
+            line: self.line,
+            column: self.column + 1,
+        }
+    }
+}
+
+// `<<` merges the entries of another mapping without overriding keys already present
+fn insert_entry(entries: &mut Vec<(Value, Value)>, key: Value, value: Value) {
+    if key == Value::String("<<".to_string()) {
+        let merged = match value {
+            Value::Map(merged) => merged,
+            Value::Array(mappings) => mappings
+                .into_iter()
+                .filter_map(|m| match m {
+                    Value::Map(merged) => Some(merged),
+                    _ => None,
+                })
+                .flatten()
+                .collect(),
+            other => {
+                entries.push((key, other));
+                return;
+            }
+        };
+        for (k, v) in merged {
+            if !entries.iter().any(|(existing, _)| *existing == k) {
+                entries.push((k, v));
+            }
+        }
+        return;
+    }
+    match entries.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, existing)) => *existing = value,
+        None => entries.push((key, value)),
+    }
+}
+
+fn fold_lines(lines: &[String]) -> String {
+    let mut text = String::new();
+    let mut pending_breaks = 0;
+    let mut previous_more_indented = false;
+    let mut first = true;
+    for line in lines {
+        if line.is_empty() {
+            pending_breaks += 1;
+            continue;
+        }
+        let more_indented = line.starts_with([' ', '\t']);
+        if first {
+            text.push_str(&"\n".repeat(pending_breaks));
+        } else if more_indented || previous_more_indented {
+            text.push_str(&"\n".repeat(pending_breaks + 1));
+        } else if pending_breaks > 0 {
+            text.push_str(&"\n".repeat(pending_breaks));
+        } else {
+            text.push(' ');
+        }
+        text.push_str(line);
+        pending_breaks = 0;
+        previous_more_indented = more_indented;
+        first = false;
+    }
+    text
+}
+
+// Resolve a plain scalar with the YAML 1.2 core schema, unless a tag says otherwise
+fn resolve_plain(text: &str, tag: Option<&str>) -> Value {
+    match tag {
+        Some("!!str") => return Value::String(text.to_string()),
+        Some("!!float") => {
+            if let Some(f) = parse_float(text).or_else(|| text.parse().ok()) {
+                return Value::Float(f);
+            }
+        }
+        _ => {}
+    }
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => return Value::Null,
+        "true" | "True" | "TRUE" => return Value::Bool(true),
+        "false" | "False" | "FALSE" => return Value::Bool(false),
+        _ => {}
+    }
+    if let Some(i) = parse_integer(text) {
+        return Value::Integer(i);
+    }
+    if let Some(f) = parse_float(text) {
+        return Value::Float(f);
+    }
+    Value::String(text.to_string())
+}
+
+fn parse_integer(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(octal) = text.strip_prefix("0o") {
+        return i64::from_str_radix(octal, 8).ok();
+    }
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn parse_float(text: &str) -> Option<f64> {
+    match text {
+        ".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" => return Some(f64::INFINITY),
+        "-.inf" | "-.Inf" | "-.INF" => return Some(f64::NEG_INFINITY),
+        ".nan" | ".NaN" | ".NAN" => return Some(f64::NAN),
+        _ => {}
+    }
+    // [-+]?(\.[0-9]+|[0-9]+(\.[0-9]*)?)([eE][-+]?[0-9]+)?
+    let unsigned = text.strip_prefix(['-', '+']).unwrap_or(text);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((m, e)) => (m, Some(e)),
+        None => (unsigned, None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let valid_mantissa = (!whole.is_empty() || !fraction.is_empty())
+        && whole.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit())
+        && (mantissa.contains('.') || exponent.is_some());
+    let valid_exponent = exponent.is_none_or(|e| {
+        let digits = e.strip_prefix(['-', '+']).unwrap_or(e);
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    });
+    if valid_mantissa && valid_exponent {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn new_parser(input: &str) -> YamlParser {
+    YamlParser {
+        chars: input.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 0,
+        anchors: HashMap::new(),
+        depth: 0,
+    }
+}
+
+/// Parse every document in a YAML stream
+pub fn parse_documents(input: &str) -> Result<Vec<Value>, YamlError> {
+    let documents = new_parser(input).parse_stream()?;
+    Ok(documents.into_iter().map(|(value, _)| value).collect())
+}
+
+/// Parse a stream holding at most one document. An empty stream is null
+pub fn parse(input: &str) -> Result<Value, YamlError> {
+    let mut documents = new_parser(input).parse_stream()?;
+    match documents.len() {
+        0 => Ok(Value::Null),
+        1 => Ok(documents.remove(0).0),
+        count => Err(YamlError {
+            message: format!("Expected a single document but found {}", count),
+            line: documents[1].1,
+            column: 1,
+        }),
+    }
+}
+
+const INDENT: &str = "  ";
+
+/// Emit a single document as block-style YAML
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    for line in emit_lines(value) {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Emit several documents separated by `---` markers
+pub fn to_string_documents(documents: &[Value]) -> String {
+    documents
+        .iter()
+        .map(to_string)
+        .collect::<Vec<String>>()
+        .join("---\n")
+}
+
+fn emit_lines(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            let mut lines = Vec::new();
+            for item in items {
+                let item_lines = emit_lines(item);
+                // Nested collections and block scalars start on the dash's line
+                for (i, line) in item_lines.into_iter().enumerate() {
+                    let prefix = if i == 0 { "- " } else { INDENT };
+                    lines.push(format!("{}{}", prefix, line).trim_end().to_string());
+                }
+            }
+            lines
+        }
+        Value::Map(entries) if !entries.is_empty() => {
+            let mut lines = Vec::new();
+            for (key, entry) in entries {
+                let key = emit_key(key);
+                match entry {
+                    Value::Map(m) if !m.is_empty() => {
+                        lines.push(format!("{}:", key));
+                        lines.extend(emit_lines(entry).into_iter().map(|l| indent_line(&l)));
+                    }
+                    Value::Array(s) if !s.is_empty() => {
+                        lines.push(format!("{}:", key));
+                        lines.extend(emit_lines(entry).into_iter().map(|l| indent_line(&l)));
+                    }
+                    _ => {
+                        let mut entry_lines = emit_lines(entry).into_iter();
+                        let first = entry_lines.next().unwrap_or_default();
+                        lines.push(format!("{}: {}", key, first));
+                        lines.extend(entry_lines);
+                    }
+                }
+            }
+            lines
+        }
+        Value::String(s) if is_block_candidate(s) => emit_literal_block(s),
+        scalar => vec![emit_flow(scalar)],
+    }
+}
+
+fn indent_line(line: &str) -> String {
+    if line.is_empty() {
+        String::new()
+    } else {
+        format!("{}{}", INDENT, line)
+    }
+}
+
+// Multi-line strings read best as `|` literal blocks
+fn is_block_candidate(s: &str) -> bool {
+    s.trim_end_matches('\n').contains('\n')
+        && !s.starts_with([' ', '\t', '\n'])
+        && !s.chars().any(|c| c.is_control() && c != '\n')
+        && !s.lines().any(|line| line.ends_with([' ', '\t']))
+}
+
+fn emit_literal_block(s: &str) -> Vec<String> {
+    let trailing = s.len() - s.trim_end_matches('\n').len();
+    let header = match trailing {
+        0 => "|-",
+        1 => "|",
+        _ => "|+",
+    };
+    let mut lines = vec![header.to_string()];
+    let body = s.trim_end_matches('\n');
+    lines.extend(body.split('\n').map(indent_line));
+    for _ in 1..trailing {
+        lines.push(String::new());
+    }
+    lines
+}
+
+fn emit_key(key: &Value) -> String {
+    match key {
+        Value::String(s) if s.contains('\n') => quote_string(s),
+        _ => emit_flow(key),
+    }
+}
+
+// The single-line form of a value: plain or quoted scalars and `[...]` / `{...}` collections
+fn emit_flow(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => format_float(*f),
+        Value::String(s) => emit_string(s),
+        Value::Datetime(datetime) => datetime.to_string(),
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(emit_flow)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Value::Map(entries) => format!(
+            "{{{}}}",
+            entries
+                .iter()
+                .map(|(k, v)| format!("{}: {}", emit_flow(k), emit_flow(v)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        ".nan".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { ".inf" } else { "-.inf" }.to_string()
+    } else {
+        let formatted = format!("{:?}", f);
+        if formatted.contains(['.', 'e', 'E']) {
+            formatted
+        } else {
+            format!("{}.0", formatted)
+        }
+    }
+}
+
+fn emit_string(s: &str) -> String {
+    let needs_quotes = s.is_empty()
+        || resolve_plain(s, None) != Value::String(s.to_string())
+        || s.starts_with([
+            '-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%',
+            '@', '`', ' ', '\t',
+        ])
+        || s.ends_with([' ', '\t', ':'])
+        || s.contains(": ")
+        || s.contains(" #")
+        || s.chars().any(|c| c.is_control())
+        || matches!(s, "yes" | "no" | "on" | "off" | "Yes" | "No" | "On" | "Off");
+    if needs_quotes {
+        quote_string(s)
+    } else {
+        s.to_string()
+    }
+}
+
+fn quote_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn parses_block_collections_and_scalars() {
+        let input = "\
+# Service config
+name: flux
+version: 1.5
+ports:
+- 80
+- 0x1F
+env: {debug: true, level: ~}
+tags: [a, 'b''s', \"c\\td\"]
+servers:
+  - host: a.example
+    weight: 2
+  - host: b.example
+script: |
+  echo one
+  echo two
+summary: >-
+  folded
+  text
+
+  kept
+";
+        let expected = Value::Map(vec![
+            (string("name"), string("flux")),
+            (string("version"), Value::Float(1.5)),
+            (
+                string("ports"),
+                Value::Array(vec![Value::Integer(80), Value::Integer(31)]),
+            ),
+            (
+                string("env"),
+                Value::Map(vec![
+                    (string("debug"), Value::Bool(true)),
+                    (string("level"), Value::Null),
+                ]),
+            ),
+            (
+                string("tags"),
+                Value::Array(vec![string("a"), string("b's"), string("c\td")]),
+            ),
+            (
+                string("servers"),
+                Value::Array(vec![
+                    Value::Map(vec![
+                        (string("host"), string("a.example")),
+                        (string("weight"), Value::Integer(2)),
+                    ]),
+                    Value::Map(vec![(string("host"), string("b.example"))]),
+                ]),
+            ),
+            (string("script"), string("echo one\necho two\n")),
+            (string("summary"), string("folded text\nkept")),
+        ]);
+        assert_eq!(parse(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn resolves_anchors_aliases_and_documents() {
+        let input =
+            "base: &base\n  a: 1\nchild:\n  <<: *base\n  b: 2\nsame: *base\n---\n- x\n...\n";
+        let documents = parse_documents(input).unwrap();
+        assert_eq!(documents.len(), 2);
+        let base = Value::Map(vec![(string("a"), Value::Integer(1))]);
+        assert_eq!(
+            documents[0],
+            Value::Map(vec![
+                (string("base"), base.clone()),
+                (
+                    string("child"),
+                    Value::Map(vec![
+                        (string("a"), Value::Integer(1)),
+                        (string("b"), Value::Integer(2)),
+                    ])
+                ),
+                (string("same"), base),
+            ])
+        );
+        assert_eq!(documents[1], Value::Array(vec![string("x")]));
+        assert_eq!(parse(input).unwrap_err().line, 7);
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        let error = parse("a: 1\n  b: 2\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+        assert!(parse("a: *missing").is_err());
+        assert!(parse("a: \"open").is_err());
+        assert!(parse("[1, 2").is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let error = parse(&"[".repeat(200_000)).unwrap_err();
+        assert_eq!((error.line, error.column), (1, MAX_DEPTH + 1));
+        assert!(parse(&"{a: ".repeat(200_000)).is_err());
+        let error = parse(&"- ".repeat(200_000)).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 2 * MAX_DEPTH + 1));
+        let mapping: String = (0..1000)
+            .map(|i| format!("{}k:\n", " ".repeat(i)))
+            .collect();
+        assert_eq!(parse(&mapping).unwrap_err().line, MAX_DEPTH + 1);
+        assert!(parse(&"&a\n".repeat(100_000)).is_err());
+        let deepest = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&deepest).is_ok());
+    }
+
+    #[test]
+    fn emits_idiomatic_block_style() {
+        let value = Value::Map(vec![
+            (string("name"), string("flux")),
+            (string("empty"), Value::Array(vec![])),
+            (string("quoted"), string("true")),
+            (
+                string("items"),
+                Value::Array(vec![
+                    Value::Map(vec![
+                        (string("id"), Value::Integer(1)),
+                        (string("ok"), Value::Bool(false)),
+                    ]),
+                    Value::Float(2.0),
+                ]),
+            ),
+            (string("text"), string("line one\nline two\n")),
+        ]);
+        let yaml = to_string(&value);
+        assert_eq!(
+            yaml,
+            "name: flux\nempty: []\nquoted: \"true\"\nitems:\n  - id: 1\n    ok: false\n  - 2.0\n\
+             text: |\n  line one\n  line two\n"
+        );
+        assert_eq!(parse(&yaml).unwrap(), value);
+    }
+}
@@ -0,0 +1,530 @@
+// A live-preview HTTP/1.1 server on the loopback interface. Markdown is rendered on every
+// request, other files are served as they are, and each rendered page holds a server-sent
+// events connection that tells the browser to reload once anything served changes. A single
+// poller thread watches the files and wakes every open events connection
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::batch;
+use crate::convert::ConvertOptions;
+use crate::diag;
+use crate::html::{escape_html, unescape_html};
+use crate::parsers::md_parser;
+use crate::site;
+
+const EVENTS_PATH: &str = "/__flux/events";
+
+// How often the poller checks the files for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+// How often an idle events connection is written to, which notices a browser that has gone away
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+const RELOAD_SCRIPT: &str = "<script>\
+new EventSource(\"/__flux/events\").onmessage = function () { location.reload(); };\
+</script>";
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn html(status: u16, html: String) -> Self {
+        Response {
+            status,
+            content_type: "text/html; charset=utf-8",
+            body: html.into_bytes(),
+        }
+    }
+
+    fn error(status: u16) -> Self {
+        let html = format!(
+            "<!DOCTYPE html>\n<title>{0}</title>\n<h1>{0} {1}</h1>\n",
+            status,
+            reason(status)
+        );
+        Response::html(status, html)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+// Bumped by the poller each time the served files change
+#[derive(Default)]
+struct Changes {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+pub struct Server {
+    // Served files are looked up under this directory, canonicalized
+    root: PathBuf,
+    // The page served at `/`, when serving a single file. Nothing else under the root is
+    // served then, apart from the files that page references
+    home: Option<PathBuf>,
+    options: ConvertOptions,
+    css: Vec<String>,
+    changes: Changes,
+}
+
+impl Server {
+    /// Serve `path`, either a directory or a single Markdown file shown at `/`
+    pub fn new(path: &Path, options: ConvertOptions, css: Vec<String>) -> io::Result<Self> {
+        let path = path.canonicalize()?;
+        let (root, home) = if path.is_dir() {
+            (path, None)
+        } else {
+            let root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            (root, Some(path))
+        };
+        Ok(Server {
+            root,
+            home,
+            options,
+            css,
+            changes: Changes::default(),
+        })
+    }
+
+    /// Answer a GET request for `target`, the path and query from the request line
+    pub fn respond(&self, target: &str) -> Response {
+        let path = target.split(['?', '#']).next().unwrap_or_default();
+        let Some(relative) = url_to_path(path) else {
+            return Response::error(400);
+        };
+        if let Some(home) = &self.home {
+            if relative.as_os_str().is_empty() {
+                return self.render(home);
+            }
+            if !self.references(home).contains(&relative) {
+                return Response::error(404);
+            }
+        }
+        let requested = self.root.join(&relative);
+        let file = match self.contain(&requested) {
+            Ok(file) => file,
+            // Pages are linked as .html, as they would be once converted
+            Err(404) if requested.extension().is_some_and(|ext| ext == "html") => {
+                match self.contain(&requested.with_extension("md")) {
+                    Ok(source) if source.is_file() => return self.render(&source),
+                    Ok(_) => return Response::error(404),
+                    Err(status) => return Response::error(status),
+                }
+            }
+            Err(status) => return Response::error(status),
+        };
+        if file.is_dir() {
+            return match self.contain(&file.join("index.md")) {
+                Ok(index) if index.is_file() => self.render(&index),
+                Err(403) => Response::error(403),
+                _ => self.listing(&file, path),
+            };
+        }
+        if file.extension().is_some_and(|ext| ext == "md") && file.is_file() {
+            return self.render(&file);
+        }
+        match fs::read(&file) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: content_type(&file),
+                body,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::error(404),
+            Err(_) => Response::error(500),
+        }
+    }
+
+    // The canonical form of `path`, or the status to answer with when it doesn't exist or a
+    // symlink leads out of the root
+    fn contain(&self, path: &Path) -> Result<PathBuf, u16> {
+        match path.canonicalize() {
+            Ok(path) if path.starts_with(&self.root) => Ok(path),
+            Ok(_) => Err(403),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(404),
+            Err(_) => Err(500),
+        }
+    }
+
+    // The files under the root a page links to or embeds, as paths relative to the root.
+    // Stylesheets count, since the rendered page links them
+    fn references(&self, page: &Path) -> Vec<PathBuf> {
+        let html = self.render(page).body;
+        let html = String::from_utf8_lossy(&html);
+        let base = page
+            .parent()
+            .and_then(|dir| dir.strip_prefix(&self.root).ok())
+            .unwrap_or(Path::new(""));
+        let mut references = Vec::new();
+        for attr in ["href=\"", "src=\""] {
+            let mut rest = &html[..];
+            while let Some(start) = rest.find(attr) {
+                rest = &rest[start + attr.len()..];
+                let end = rest.find('"').unwrap_or(rest.len());
+                let url = unescape_html(&rest[..end]);
+                rest = &rest[end..];
+                let url = url.split(['?', '#']).next().unwrap_or_default();
+                // Links with a scheme, like https: or mailto:, or to another host point elsewhere
+                let external = url.starts_with("//")
+                    || url
+                        .find(':')
+                        .is_some_and(|colon| !url[..colon].contains('/'));
+                if url.is_empty() || external {
+                    continue;
+                }
+                let path = match url.strip_prefix('/') {
+                    Some(absolute) => url_to_path(absolute),
+                    None => url_to_path(url).map(|path| base.join(path)),
+                };
+                references.extend(path);
+            }
+        }
+        references
+    }
+
+    fn render(&self, path: &Path) -> Response {
+        let Ok(md) = fs::read_to_string(path) else {
+            return Response::error(500);
+        };
+        let title = path.file_name().unwrap_or_default().to_string_lossy();
+        let options = self.options.for_file(path);
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n{}\n</head>\n<body>\n{}\n{}\n</body>\n</html>\n",
+            escape_html(&title),
+            site::stylesheet_links(&self.css, "/"),
+            md_parser::md_string_to_html(&md, &options.md),
+            RELOAD_SCRIPT
+        );
+        Response::html(200, html)
+    }
+
+    fn listing(&self, dir: &Path, url: &str) -> Response {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Response::error(500);
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(Result::ok)
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+                if is_dir {
+                    name + "/"
+                } else {
+                    name
+                }
+            })
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        names.sort();
+        let base = if url.ends_with('/') {
+            url.to_string()
+        } else {
+            format!("{}/", url)
+        };
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+            escape_html(&base)
+        );
+        for name in names {
+            html.push_str(&format!(
+                "<li><a href=\"{}{}\">{}</a></li>\n",
+                escape_html(&base),
+                escape_html(&name),
+                escape_html(&name)
+            ));
+        }
+        html.push_str(&format!("</ul>\n{}\n</body>\n</html>\n", RELOAD_SCRIPT));
+        Response::html(200, html)
+    }
+
+    // Changes to any file served, including additions and removals, change this. For a single
+    // page that is the page and what it references rather than its whole directory
+    fn stamp(&self) -> (usize, Option<SystemTime>) {
+        let files = match &self.home {
+            Some(home) => {
+                let mut files = vec![home.clone()];
+                files.extend(
+                    self.references(home)
+                        .iter()
+                        .map(|path| self.root.join(path)),
+                );
+                files
+            }
+            None => batch::collect_files(&self.root.to_string_lossy())
+                .map(|(files, _)| files.into_iter().map(|(path, _)| path).collect())
+                .unwrap_or_default(),
+        };
+        let latest = files
+            .iter()
+            .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max();
+        (files.len(), latest)
+    }
+
+    // Check the files every poll interval for as long as the server runs, waking the events
+    // connections whenever they have changed
+    fn poll(&self) {
+        let mut last = self.stamp();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let stamp = self.stamp();
+            if stamp != last {
+                last = stamp;
+                *self.changes.generation.lock().unwrap() += 1;
+                self.changes.changed.notify_all();
+            }
+        }
+    }
+}
+
+// Decode a URL path into a relative file path, refusing anything that climbs out of the root
+fn url_to_path(url: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(url)?;
+    let mut path = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" | "toml" | "yaml" | "yml" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Accept connections on 127.0.0.1:`port` until the process is stopped, one thread each
+pub fn run(server: Server, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    diag::info!(
+        "serving {} at http://{} (press Ctrl-C to stop)",
+        server.root.display(),
+        listener.local_addr()?
+    );
+    let server = Arc::new(server);
+    let poller = Arc::clone(&server);
+    thread::spawn(move || poller.poll());
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&server, stream) {
+                diag::debug!("connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(server: &Server, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers carry nothing this server needs, but must be read before replying
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return write_response(&mut stream, &Response::error(400), false),
+    };
+    let head_only = method == "HEAD";
+    if method != "GET" && !head_only {
+        return write_response(&mut stream, &Response::error(405), false);
+    }
+    if target == EVENTS_PATH {
+        return stream_events(server, stream);
+    }
+    let response = server.respond(target);
+    diag::verbose!("{} {} {}", method, target, response.status);
+    write_response(&mut stream, &response, head_only)
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response, head_only: bool) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    if !head_only {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()
+}
+
+// Hold the connection open until the poller reports a change, then send an event. The comment
+// line sent while idle fails once the browser has gone away, which ends the thread
+fn stream_events(server: &Server, mut stream: TcpStream) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: keep-alive\r\n\r\n"
+    )?;
+    stream.flush()?;
+    let changes = &server.changes;
+    let initial = *changes.generation.lock().unwrap();
+    loop {
+        let generation = changes.generation.lock().unwrap();
+        let (generation, _) = changes
+            .changed
+            .wait_timeout_while(generation, KEEPALIVE_INTERVAL, |g| *g == initial)
+            .unwrap();
+        // Nothing is written while holding the lock, so a slow browser can't stall the poller
+        let changed = *generation != initial;
+        drop(generation);
+        if changed {
+            write!(stream, "data: reload\n\n")?;
+            return stream.flush();
+        }
+        write!(stream, ":\n\n")?;
+        stream.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_urls_inside_the_root() {
+        assert_eq!(url_to_path("/a%20b/c.md"), Some(PathBuf::from("a b/c.md")));
+        assert_eq!(url_to_path("/"), Some(PathBuf::new()));
+        assert_eq!(url_to_path("/../secret"), None);
+        assert_eq!(url_to_path("/a/%2e%2e/%2e%2e/secret"), None);
+        assert_eq!(url_to_path("/%zz"), None);
+    }
+
+    #[test]
+    fn renders_markdown_and_serves_files() -> io::Result<()> {
+        let root = std::env::temp_dir().join("flux_serve_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("guide"))?;
+        fs::write(root.join("guide/setup.md"), "# Setup\n")?;
+        fs::write(root.join("style.css"), "p {}")?;
+
+        let server = Server::new(&root, ConvertOptions::default(), Vec::new())?;
+        let page = server.respond("/guide/setup.html?x=1");
+        assert_eq!(page.status, 200);
+        let html = String::from_utf8(page.body).unwrap();
+        assert!(html.contains("<h1 id=\"setup\">Setup</h1>"));
+        assert!(html.contains(EVENTS_PATH));
+
+        let css = server.respond("/style.css");
+        assert_eq!(
+            (css.content_type, css.body),
+            ("text/css; charset=utf-8", b"p {}".to_vec())
+        );
+        let listing = String::from_utf8(server.respond("/").body).unwrap();
+        assert!(listing.contains("<a href=\"/guide/\">guide/</a>"));
+        assert_eq!(server.respond("/missing.md").status, 404);
+
+        let css = vec!["style.css".to_string()];
+        let single = Server::new(&root.join("guide/setup.md"), ConvertOptions::default(), css)?;
+        let home = String::from_utf8(single.respond("/").body).unwrap();
+        assert!(home.contains("<link rel=\"stylesheet\" href=\"/style.css\">"));
+        fs::remove_dir_all(&root)
+    }
+
+    #[test]
+    fn single_pages_serve_only_what_they_reference() -> io::Result<()> {
+        let root = std::env::temp_dir().join("flux_serve_single_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("img"))?;
+        fs::write(
+            root.join("page.md"),
+            "![logo](./img/logo.png) [next](other.md)\n",
+        )?;
+        fs::write(root.join("img/logo.png"), "png")?;
+        fs::write(root.join("other.md"), "# Other\n")?;
+        fs::write(root.join("secret.txt"), "secret")?;
+
+        let server = Server::new(&root.join("page.md"), ConvertOptions::default(), Vec::new())?;
+        assert_eq!(server.respond("/").status, 200);
+        assert_eq!(server.respond("/img/logo.png").body, b"png".to_vec());
+        assert_eq!(server.respond("/other.md").status, 200);
+        assert_eq!(server.respond("/secret.txt").status, 404);
+        assert_eq!(server.respond("/img/").status, 404);
+        fs::remove_dir_all(&root)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_escape_the_root() -> io::Result<()> {
+        let base = std::env::temp_dir().join("flux_serve_symlink_test");
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("site");
+        fs::create_dir_all(&root)?;
+        fs::write(base.join("secret.md"), "# Secret\n")?;
+        fs::write(root.join("page.md"), "# Page\n")?;
+        std::os::unix::fs::symlink(base.join("secret.md"), root.join("leak.md"))?;
+        std::os::unix::fs::symlink(&base, root.join("up"))?;
+        std::os::unix::fs::symlink(root.join("page.md"), root.join("alias.md"))?;
+
+        let server = Server::new(&root, ConvertOptions::default(), Vec::new())?;
+        assert_eq!(server.respond("/leak.md").status, 403);
+        assert_eq!(server.respond("/leak.html").status, 403);
+        assert_eq!(server.respond("/up/secret.md").status, 403);
+        assert_eq!(server.respond("/up/").status, 403);
+        assert_eq!(server.respond("/alias.md").status, 200);
+        fs::remove_dir_all(&base)
+    }
+}
@@ -0,0 +1,483 @@
+// Static site generation. Every Markdown file under the source directory becomes a page wrapped
+// in a shared layout, with navigation built from the directory tree. Front matter (a YAML block
+// between `---` lines at the top of a page) can set a page's `title` and its nav `order`. Links
+// to `.md` files are rewritten to the generated `.html`, and all other files are copied as-is
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::batch::{self, Summary};
+use crate::convert::ConvertOptions;
+use crate::diag;
+use crate::html::{self, escape_html, Element, Format, Node};
+use crate::parsers::md_parser;
+use crate::parsers::yaml;
+use crate::value::Value;
+
+/// The layout used when none is given. `{{title}}`, `{{site_title}}`, `{{nav}}`, `{{css}}`,
+/// `{{content}}` and `{{root}}` (the relative path back to the site root) are filled in for each
+/// page
+pub const DEFAULT_LAYOUT: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}} - {{site_title}}</title>
+<style>
+body { display: flex; margin: 0; font-family: sans-serif; line-height: 1.5; }
+nav.site { min-width: 14em; padding: 1em; border-right: 1px solid #ddd; }
+nav.site ul { list-style: none; padding-left: 1em; }
+nav.site a[aria-current] { font-weight: bold; }
+main { max-width: 48em; padding: 1em 2em; }
+</style>
+{{css}}
+</head>
+<body>
+<nav class="site">
+<a href="{{root}}index.html">{{site_title}}</a>
+{{nav}}
+</nav>
+<main>
+{{content}}
+</main>
+</body>
+</html>
+"#;
+
+pub struct SiteOptions {
+    pub title: String,
+    pub layout: String,
+    /// Stylesheets linked from every page, relative to the site root unless absolute
+    pub css: Vec<String>,
+    pub options: ConvertOptions,
+}
+
+struct Page {
+    source: PathBuf,
+    // Relative to the source directory, with the `.md` extension
+    relative: PathBuf,
+    title: String,
+    order: Option<i64>,
+    body: String,
+}
+
+impl Page {
+    fn output(&self) -> PathBuf {
+        self.relative.with_extension("html")
+    }
+
+    fn is_index(&self) -> bool {
+        self.relative
+            .file_stem()
+            .is_some_and(|stem| stem == "index")
+    }
+}
+
+// Pages grouped by directory, mirroring the source tree
+#[derive(Default)]
+struct NavDir {
+    index: Option<usize>,
+    pages: Vec<usize>,
+    dirs: BTreeMap<String, NavDir>,
+}
+
+/// Render every Markdown file under `source` into `out_dir`, copying everything else
+pub fn build(source: &Path, out_dir: &Path, options: &SiteOptions) -> io::Result<Summary> {
+    let (files, hidden) = batch::collect_files(&source.to_string_lossy())?;
+    let mut summary = Summary {
+        skipped: hidden,
+        ..Summary::default()
+    };
+    let mut pages = Vec::new();
+    for (path, relative) in files {
+        if batch::in_out_dir(&path, out_dir) {
+            summary.skipped += 1;
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            match read_page(&path, relative) {
+                Ok(page) => pages.push(page),
+                Err(e) => {
+                    diag::error!("{}: {}", path.display(), e);
+                    summary.failed += 1;
+                }
+            }
+        } else {
+            let output = out_dir.join(&relative);
+            let copied = output
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::copy(&path, &output));
+            match copied {
+                Ok(_) => summary.copied += 1,
+                Err(e) => {
+                    diag::error!("{}: {}", path.display(), e);
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+
+    let nav = nav_tree(&pages);
+    for page in &pages {
+        let md = options.options.for_file(&page.source).md;
+        let (content, warnings) = md_parser::md_string_to_html_with_warnings(&page.body, &md);
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        for warning in &warnings {
+            diag::warning!("{}: {}", page.source.display(), warning);
+        }
+        if let Err(e) = options.options.check_warnings(&warnings) {
+            diag::error!("{}: {}", page.source.display(), e);
+            summary.failed += 1;
+            continue;
+        }
+        let content = rewrite_links(&content);
+        let html = render_page(page, &content, &pages, &nav, options);
+        let output = out_dir.join(page.output());
+        write_output(&output, &html)?;
+        diag::verbose!("{} -> {}", page.source.display(), output.display());
+        summary.converted += 1;
+    }
+
+    // Without an index.md at the top, the index page is the navigation on its own
+    if !pages
+        .iter()
+        .any(|page| page.relative == Path::new("index.md"))
+    {
+        let index = Page {
+            source: source.join("index.md"),
+            relative: PathBuf::from("index.md"),
+            title: options.title.clone(),
+            order: None,
+            body: String::new(),
+        };
+        let content = format!(
+            "<h1>{}</h1>\n{}",
+            escape_html(&options.title),
+            render_nav(&nav, &pages, &index, options.options.md.format)
+        );
+        let html = render_page(&index, &content, &pages, &nav, options);
+        write_output(&out_dir.join("index.html"), &html)?;
+    }
+    Ok(summary)
+}
+
+fn write_output(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)
+}
+
+fn read_page(path: &Path, relative: PathBuf) -> Result<Page, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let (front_matter, body) = split_front_matter(&text);
+    let mut title = None;
+    let mut order = None;
+    if let Some(front_matter) = front_matter {
+        let value = yaml::parse(front_matter).map_err(|e| format!("front matter: {}", e))?;
+        if let Value::Map(entries) = value {
+            for (key, value) in entries {
+                match (key, value) {
+                    (Value::String(key), Value::String(value)) if key == "title" => {
+                        title = Some(value)
+                    }
+                    (Value::String(key), Value::Integer(value)) if key == "order" => {
+                        order = Some(value)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let title = title.or_else(|| first_heading(&body)).unwrap_or_else(|| {
+        let stem = relative.file_stem().unwrap_or_default();
+        stem.to_string_lossy().into_owned()
+    });
+    Ok(Page {
+        source: path.to_path_buf(),
+        relative,
+        title,
+        order,
+        body,
+    })
+}
+
+// Split off a leading `---` block. The body keeps one blank line per front matter line so
+// source positions still point at the right line of the file
+fn split_front_matter(text: &str) -> (Option<&str>, String) {
+    let Some(rest) = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))
+    else {
+        return (None, text.to_string());
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            let front_matter = &rest[..offset];
+            let lines = text[..text.len() - rest.len() + offset + line.len()]
+                .matches('\n')
+                .count();
+            let body = "\n".repeat(lines) + &rest[offset + line.len()..];
+            return (Some(front_matter), body);
+        }
+        offset += line.len();
+    }
+    // An unclosed block is not front matter
+    (None, text.to_string())
+}
+
+fn first_heading(body: &str) -> Option<String> {
+    body.lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|heading| heading.trim().to_string())
+}
+
+fn nav_tree(pages: &[Page]) -> NavDir {
+    let mut root = NavDir::default();
+    for (i, page) in pages.iter().enumerate() {
+        let mut dir = &mut root;
+        if let Some(parent) = page.relative.parent() {
+            for component in parent.components() {
+                let name = component.as_os_str().to_string_lossy().into_owned();
+                dir = dir.dirs.entry(name).or_default();
+            }
+        }
+        if page.is_index() {
+            dir.index = Some(i);
+        } else {
+            dir.pages.push(i);
+        }
+    }
+    root
+}
+
+// Explicitly ordered entries come first, then the rest alphabetically by title
+fn sort_key(order: Option<i64>, title: &str) -> (i64, String) {
+    (order.unwrap_or(i64::MAX), title.to_lowercase())
+}
+
+fn render_nav(nav: &NavDir, pages: &[Page], current: &Page, format: Format) -> String {
+    let depth = current.relative.components().count() - 1;
+    let root = "../".repeat(depth);
+    html::serialize(&[nav_list(nav, pages, current, &root).into()], format)
+}
+
+fn nav_list(dir: &NavDir, pages: &[Page], current: &Page, root: &str) -> Element {
+    // Each entry is a page or a subdirectory, which is titled after its index page if it has one
+    let mut entries: Vec<((i64, String), Element)> = Vec::new();
+    for &i in &dir.pages {
+        let page = &pages[i];
+        let item = Element::new("li").with_child(nav_link(page, current, root).into());
+        entries.push((sort_key(page.order, &page.title), item));
+    }
+    for (name, sub) in &dir.dirs {
+        let (key, label) = match sub.index.map(|i| &pages[i]) {
+            Some(index) => (
+                sort_key(index.order, &index.title),
+                nav_link(index, current, root),
+            ),
+            None => (
+                sort_key(None, name),
+                Element::new("span").with_child(Node::Text(name.clone())),
+            ),
+        };
+        let item = Element::new("li")
+            .with_child(label.into())
+            .with_child(nav_list(sub, pages, current, root).into());
+        entries.push((key, item));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut list = Element::new("ul");
+    for (_, item) in entries {
+        list.children.push(item.into());
+    }
+    list
+}
+
+fn nav_link(page: &Page, current: &Page, root: &str) -> Element {
+    let href = format!("{}{}", root, url_path(&page.output()));
+    let mut link = Element::new("a").with_attr("href", &href);
+    if page.relative == current.relative {
+        link.set_attr("aria-current", "page");
+    }
+    link.with_child(Node::Text(page.title.clone()))
+}
+
+fn url_path(path: &Path) -> String {
+    let segments: Vec<_> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    segments.join("/")
+}
+
+fn render_page(
+    page: &Page,
+    content: &str,
+    pages: &[Page],
+    nav: &NavDir,
+    options: &SiteOptions,
+) -> String {
+    let depth = page.relative.components().count() - 1;
+    let root = "../".repeat(depth);
+    let nav = render_nav(nav, pages, page, options.options.md.format);
+    let css = stylesheet_links(&options.css, &root);
+    let title = escape_html(&page.title);
+    let site_title = escape_html(&options.title);
+    fill_template(
+        &options.layout,
+        &[
+            ("title", &title),
+            ("site_title", &site_title),
+            ("nav", &nav),
+            ("css", &css),
+            ("content", content),
+            ("root", &root),
+        ],
+    )
+}
+
+/// `<link>` tags for the stylesheets, with relative paths resolved from `root`
+pub fn stylesheet_links(css: &[String], root: &str) -> String {
+    let links: Vec<String> = css
+        .iter()
+        .map(|href| {
+            let absolute = href.starts_with('/') || href.contains("://");
+            let href = if absolute {
+                href.clone()
+            } else {
+                format!("{}{}", root, href)
+            };
+            format!("<link rel=\"stylesheet\" href=\"{}\">", escape_html(&href))
+        })
+        .collect();
+    links.join("\n")
+}
+
+// Replace `{{name}}` placeholders in one pass, so filled-in content is never expanded again.
+// Unknown placeholders are left as they are
+fn fill_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        let name = after[..end].trim();
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => out.push_str(value),
+            None => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Point relative links at `.md` files to the `.html` pages generated from them, keeping any
+/// `#fragment`
+pub fn rewrite_links(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("href=\"") {
+        let value_start = start + "href=\"".len();
+        out.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+        let end = rest.find('"').unwrap_or(rest.len());
+        let href = &rest[..end];
+        let (path, fragment) = match href.find('#') {
+            Some(hash) => href.split_at(hash),
+            None => (href, ""),
+        };
+        // Links with a scheme, like https: or mailto:, point off-site
+        let external = path
+            .find(':')
+            .is_some_and(|colon| !path[..colon].contains('/'));
+        match path.strip_suffix(".md") {
+            Some(stem) if !external && !stem.is_empty() => {
+                out.push_str(stem);
+                out.push_str(".html");
+                out.push_str(fragment);
+            }
+            _ => out.push_str(href),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_front_matter_and_rewrites_links() {
+        let (front_matter, body) = split_front_matter("---\ntitle: Setup\norder: 2\n---\n# Hi\n");
+        assert_eq!(front_matter, Some("title: Setup\norder: 2\n"));
+        assert_eq!(body, "\n\n\n\n# Hi\n");
+        assert_eq!(split_front_matter("---\nno end\n").0, None);
+
+        assert_eq!(
+            rewrite_links(
+                r#"<a href="guide/setup.md#install">x</a> <a href="https://x.org/a.md">y</a>"#
+            ),
+            r#"<a href="guide/setup.html#install">x</a> <a href="https://x.org/a.md">y</a>"#
+        );
+        assert_eq!(
+            fill_template(
+                "{{ title }}|{{nav}}|{{other}}",
+                &[("title", "{{nav}}"), ("nav", "N")]
+            ),
+            "{{nav}}|N|{{other}}"
+        );
+    }
+
+    #[test]
+    fn builds_pages_nav_and_index() -> io::Result<()> {
+        let root = std::env::temp_dir().join("flux_site_test");
+        let _ = fs::remove_dir_all(&root);
+        let docs = root.join("docs");
+        fs::create_dir_all(docs.join("guide"))?;
+        fs::write(docs.join("about.md"), "# About us\n")?;
+        fs::write(
+            docs.join("guide/setup.md"),
+            "---\ntitle: Setup\norder: 2\n---\nSee [install](install.md#top)\n",
+        )?;
+        fs::write(
+            docs.join("guide/install.md"),
+            "---\norder: 1\n---\n# Install\n",
+        )?;
+        fs::write(docs.join("guide/logo.png"), [0u8, 1])?;
+
+        let options = SiteOptions {
+            title: "Docs".to_string(),
+            layout: "<title>{{title}}</title>{{root}}\n{{css}}\n{{nav}}\n{{content}}".to_string(),
+            css: vec!["site.css".to_string()],
+            options: ConvertOptions::default(),
+        };
+        let out = root.join("site");
+        let summary = build(&docs, &out, &options)?;
+        assert_eq!((summary.converted, summary.copied), (3, 1));
+        assert_eq!(fs::read(out.join("guide/logo.png"))?, [0u8, 1]);
+
+        let setup = fs::read_to_string(out.join("guide/setup.html"))?;
+        assert!(setup.starts_with(
+            "<title>Setup</title>../\n<link rel=\"stylesheet\" href=\"../site.css\">\n"
+        ));
+        assert!(setup.contains(r#"<a href="install.html#top">install</a>"#));
+        assert!(setup.contains(r#"<a href="../guide/setup.html" aria-current="page">Setup</a>"#));
+        // Ordered pages come first, whatever their titles
+        let install = setup.find("../guide/install.html").unwrap();
+        assert!(install < setup.find("../guide/setup.html").unwrap());
+
+        let index = fs::read_to_string(out.join("index.html"))?;
+        assert!(index.contains("<h1>Docs</h1>"));
+        assert!(index.contains(r#"<a href="about.html">About us</a>"#));
+        fs::remove_dir_all(&root)
+    }
+}
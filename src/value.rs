@@ -0,0 +1,304 @@
+// The data model shared by the config formats. Every config parser produces a `Value` and
+// every serializer consumes one, so any format converts to any other.
+//
+// Not every value fits every format. `check` reports what writing a value would lose before
+// anything is written:
+//
+// | Value                      | JSON                  | YAML              | TOML                       |
+// |----------------------------|-----------------------|-------------------|----------------------------|
+// | null map value             | kept                  | kept              | warning, key dropped       |
+// | null array item or root    | kept                  | kept              | error                      |
+// | datetime                   | warning, string       | warning, string   | kept                       |
+// | NaN or infinity            | warning, null         | kept              | kept                       |
+// | non-string scalar key      | warning, string key   | kept              | warning, string key        |
+// | array or map key           | error                 | kept              | error                      |
+// | root that is not a map     | kept                  | kept              | error                      |
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Datetime(Datetime),
+    Array(Vec<Value>),
+    // Entries keep their document order. Keys are usually strings, but YAML allows any value
+    Map(Vec<(Value, Value)>),
+}
+
+/// An offset date-time, local date-time, local date or local time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Datetime {
+    pub date: Option<Date>,
+    pub time: Option<Time>,
+    pub offset: Option<Offset>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Offset {
+    Z,
+    Minutes(i16),
+}
+
+impl fmt::Display for Datetime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(date) = self.date {
+            write!(f, "{:04}-{:02}-{:02}", date.year, date.month, date.day)?;
+            if self.time.is_some() {
+                write!(f, "T")?;
+            }
+        }
+        if let Some(time) = self.time {
+            write!(f, "{:02}:{:02}:{:02}", time.hour, time.minute, time.second)?;
+            if time.nanosecond > 0 {
+                let fraction = format!("{:09}", time.nanosecond);
+                write!(f, ".{}", fraction.trim_end_matches('0'))?;
+            }
+        }
+        match self.offset {
+            Some(Offset::Z) => write!(f, "Z"),
+            Some(Offset::Minutes(minutes)) => {
+                let sign = if minutes < 0 { '-' } else { '+' };
+                let minutes = minutes.unsigned_abs();
+                write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// How deeply the config parsers let arrays and maps nest, so hostile input gets an error
+/// rather than exhausting the stack
+pub const MAX_DEPTH: usize = 512;
+
+/// The string a scalar map key becomes in formats whose keys are strings. Arrays and maps
+/// have no such form
+pub fn key_to_string(key: &Value) -> Option<String> {
+    match key {
+        Value::Null => Some("null".to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::String(s) => Some(s.clone()),
+        Value::Datetime(datetime) => Some(datetime.to_string()),
+        Value::Array(_) | Value::Map(_) => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Toml => "TOML",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something a format cannot represent exactly, located by its path in the document
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loss {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Loss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "top level: {}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Parent {
+    Root,
+    Array,
+    Map,
+}
+
+/// Check whether `value` can be written as `format`. Lossy but well-defined steps come back as
+/// warnings, while values the format cannot hold at all are an error
+pub fn check(value: &Value, format: ConfigFormat) -> Result<Vec<Loss>, Loss> {
+    if format == ConfigFormat::Toml && !matches!(value, Value::Map(_)) {
+        return Err(Loss {
+            path: String::new(),
+            message: "a TOML document must be a table".to_string(),
+        });
+    }
+    let mut losses = Vec::new();
+    check_value(value, format, "", Parent::Root, &mut losses)?;
+    Ok(losses)
+}
+
+fn check_value(
+    value: &Value,
+    format: ConfigFormat,
+    path: &str,
+    parent: Parent,
+    losses: &mut Vec<Loss>,
+) -> Result<(), Loss> {
+    let loss = |message: String| Loss {
+        path: path.to_string(),
+        message,
+    };
+    match value {
+        Value::Null if format == ConfigFormat::Toml => {
+            if parent != Parent::Map {
+                return Err(loss(
+                    "TOML has no null, and an array cannot skip it".to_string(),
+                ));
+            }
+            losses.push(loss("TOML has no null, so the key is dropped".to_string()));
+        }
+        Value::Datetime(datetime) if format != ConfigFormat::Toml => losses.push(loss(format!(
+            "{} has no datetime type, so it is written as the string \"{}\"",
+            format, datetime
+        ))),
+        Value::Float(f) if format == ConfigFormat::Json && !f.is_finite() => losses.push(loss(
+            "JSON has no NaN or infinity, so it is written as null".to_string(),
+        )),
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let item_path = format!("{}[{}]", path, i);
+                check_value(item, format, &item_path, Parent::Array, losses)?;
+            }
+        }
+        Value::Map(entries) => {
+            for (key, entry) in entries {
+                let key_string = match (key, format) {
+                    (_, ConfigFormat::Yaml) | (Value::String(_), _) => {
+                        key_to_string(key).unwrap_or_else(|| "?".to_string())
+                    }
+                    _ => match key_to_string(key) {
+                        Some(key_string) => {
+                            losses.push(loss(format!(
+                                "{} keys are strings, so the key {} becomes \"{}\"",
+                                format, key_string, key_string
+                            )));
+                            key_string
+                        }
+                        None => {
+                            return Err(loss(format!(
+                                "{} keys must be strings, not arrays or maps",
+                                format
+                            )))
+                        }
+                    },
+                };
+                let entry_path = if path.is_empty() {
+                    key_string
+                } else {
+                    format!("{}.{}", path, key_string)
+                };
+                check_value(entry, format, &entry_path, Parent::Map, losses)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (Value::String(k.to_string()), v))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn reports_lossy_values_by_path() {
+        let datetime = Datetime {
+            date: Some(Date {
+                year: 2024,
+                month: 1,
+                day: 2,
+            }),
+            time: None,
+            offset: None,
+        };
+        let value = map(vec![
+            ("when", Value::Datetime(datetime)),
+            (
+                "servers",
+                Value::Array(vec![map(vec![("port", Value::Null)])]),
+            ),
+            (
+                "ids",
+                Value::Map(vec![(Value::Integer(1), Value::Float(f64::NAN))]),
+            ),
+        ]);
+        let messages = |format| {
+            check(&value, format)
+                .unwrap()
+                .iter()
+                .map(Loss::to_string)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            messages(ConfigFormat::Json),
+            [
+                "when: JSON has no datetime type, so it is written as the string \"2024-01-02\"",
+                "ids: JSON keys are strings, so the key 1 becomes \"1\"",
+                "ids.1: JSON has no NaN or infinity, so it is written as null",
+            ]
+        );
+        assert_eq!(
+            messages(ConfigFormat::Toml),
+            [
+                "servers[0].port: TOML has no null, so the key is dropped",
+                "ids: TOML keys are strings, so the key 1 becomes \"1\"",
+            ]
+        );
+        assert_eq!(messages(ConfigFormat::Yaml).len(), 1);
+    }
+
+    #[test]
+    fn rejects_values_a_format_cannot_hold() {
+        let nested_key = Value::Map(vec![(Value::Array(vec![]), Value::Null)]);
+        assert!(check(&nested_key, ConfigFormat::Yaml).is_ok());
+        assert!(check(&nested_key, ConfigFormat::Json).is_err());
+        let null_item = map(vec![("a", Value::Array(vec![Value::Null]))]);
+        assert_eq!(
+            check(&null_item, ConfigFormat::Toml)
+                .unwrap_err()
+                .to_string(),
+            "a[0]: TOML has no null, and an array cannot skip it"
+        );
+        assert!(check(&Value::Integer(1), ConfigFormat::Toml).is_err());
+    }
+}
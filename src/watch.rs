@@ -0,0 +1,251 @@
+// Watch mode. Sources are polled for changes by comparing modification times and sizes, which
+// works everywhere without platform file-event APIs. A changed file is only re-converted once
+// it has stopped changing for the debounce period, so an editor's burst of saves converts once
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::batch::{self, Outcome};
+use crate::convert::{ConvertOptions, FileType, Registry};
+use crate::diag;
+
+/// Where a watched input's output goes
+#[derive(Clone, Debug)]
+pub enum Destination {
+    /// A single file converted to exactly this path
+    File(PathBuf),
+    /// A directory, glob or file mirrored into this directory
+    Dir(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub input: String,
+    pub destination: Destination,
+}
+
+/// The result of handling one changed file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub input: PathBuf,
+    pub outcome: Outcome,
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Converted(output) => {
+                write!(f, "{} -> {}", self.input.display(), output.display())
+            }
+            Outcome::Copied(output) => {
+                write!(f, "{} copied to {}", self.input.display(), output.display())
+            }
+            Outcome::Failed(e) => write!(f, "{}: {}", self.input.display(), e),
+        }
+    }
+}
+
+// What a file looked like when last polled
+type Stamp = (Option<SystemTime>, u64);
+
+struct WatchedFile {
+    relative: PathBuf,
+    source: usize,
+    stamp: Stamp,
+}
+
+pub struct Watcher<'a> {
+    registry: &'a Registry,
+    sources: Vec<Source>,
+    to: FileType,
+    options: &'a ConvertOptions,
+    debounce: Duration,
+    known: HashMap<PathBuf, Stamp>,
+    // Changed files and when they last changed
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(
+        registry: &'a Registry,
+        sources: Vec<Source>,
+        to: FileType,
+        options: &'a ConvertOptions,
+        debounce: Duration,
+    ) -> Self {
+        Watcher {
+            registry,
+            sources,
+            to,
+            options,
+            debounce,
+            known: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Convert every watched file once
+    pub fn start(&mut self) -> Vec<Report> {
+        let files = self.scan();
+        let mut paths: Vec<&PathBuf> = files.keys().collect();
+        paths.sort();
+        let reports = paths
+            .into_iter()
+            .map(|path| self.process(path, &files[path]))
+            .collect();
+        self.known = files.into_iter().map(|(p, f)| (p, f.stamp)).collect();
+        reports
+    }
+
+    /// Note which files changed since the last poll, then convert those that have been quiet
+    /// for the debounce period
+    pub fn poll(&mut self, now: Instant) -> Vec<Report> {
+        let files = self.scan();
+        for (path, file) in &files {
+            if self.known.get(path) != Some(&file.stamp) {
+                self.pending.insert(path.clone(), now);
+            }
+        }
+        self.pending.retain(|path, _| files.contains_key(path));
+
+        let mut ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, changed)| now.duration_since(**changed) >= self.debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        ready.sort();
+        let mut reports = Vec::new();
+        for path in ready {
+            self.pending.remove(&path);
+            reports.push(self.process(&path, &files[&path]));
+        }
+        self.known = files.into_iter().map(|(p, f)| (p, f.stamp)).collect();
+        reports
+    }
+
+    fn scan(&self) -> HashMap<PathBuf, WatchedFile> {
+        let mut files = HashMap::new();
+        for (index, source) in self.sources.iter().enumerate() {
+            // A source that is briefly missing, say mid-save, just has no files this round
+            let Ok((found, _)) = batch::collect_files(&source.input) else {
+                continue;
+            };
+            for (path, relative) in found {
+                if let Destination::Dir(out_dir) = &source.destination {
+                    if batch::in_out_dir(&path, out_dir) {
+                        continue;
+                    }
+                }
+                if let Ok(metadata) = fs::metadata(&path) {
+                    let stamp = (metadata.modified().ok(), metadata.len());
+                    let file = WatchedFile {
+                        relative,
+                        source: index,
+                        stamp,
+                    };
+                    files.insert(path, file);
+                }
+            }
+        }
+        files
+    }
+
+    fn process(&self, path: &Path, file: &WatchedFile) -> Report {
+        let mut warnings = Vec::new();
+        let outcome = match &self.sources[file.source].destination {
+            Destination::Dir(out_dir) => batch::process_file(
+                self.registry,
+                path,
+                &file.relative,
+                out_dir,
+                self.to,
+                self.options,
+                &mut warnings,
+            ),
+            Destination::File(output) => match FileType::from_file_name(&path.to_string_lossy()) {
+                Ok(from) => {
+                    batch::convert_file(self.registry, path, output, from, self.to, self.options)
+                        .and_then(|file_warnings| {
+                            let checked = self.options.check_warnings(&file_warnings);
+                            warnings = file_warnings;
+                            checked.map(|_| Outcome::Converted(output.clone()))
+                        })
+                        .unwrap_or_else(Outcome::Failed)
+                }
+                Err(e) => Outcome::Failed(e.to_string()),
+            },
+        };
+        Report {
+            input: path.to_path_buf(),
+            outcome,
+            warnings,
+        }
+    }
+}
+
+fn print(report: &Report) {
+    for warning in &report.warnings {
+        diag::warning!("{}: {}", report.input.display(), warning);
+    }
+    match report.outcome {
+        Outcome::Failed(_) => diag::error!("{}", report),
+        _ => diag::info!("{}", report),
+    }
+}
+
+/// Poll forever, printing a line for every file handled
+pub fn run(watcher: &mut Watcher, interval: Duration) -> ! {
+    watcher.start().iter().for_each(print);
+    diag::info!("watching for changes (press Ctrl-C to stop)");
+    loop {
+        thread::sleep(interval);
+        watcher.poll(Instant::now()).iter().for_each(print);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconverts_changed_files_after_the_debounce() -> std::io::Result<()> {
+        let root = std::env::temp_dir().join("flux_watch_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs"))?;
+        fs::write(root.join("docs/a.md"), "# A\n")?;
+        fs::write(root.join("docs/b.md"), "# B\n")?;
+
+        let registry = Registry::with_builtins();
+        let options = ConvertOptions::default();
+        let sources = vec![Source {
+            input: root.join("docs").to_string_lossy().into_owned(),
+            destination: Destination::Dir(root.join("out")),
+        }];
+        let debounce = Duration::from_millis(100);
+        let mut watcher = Watcher::new(&registry, sources, FileType::HTML, &options, debounce);
+        assert_eq!(watcher.start().len(), 2);
+
+        let start = Instant::now();
+        assert!(watcher.poll(start).is_empty());
+        fs::write(root.join("docs/a.md"), "# A again\n")?;
+        // Still inside the debounce window, and a second save restarts it
+        assert!(watcher.poll(start).is_empty());
+        fs::write(root.join("docs/a.md"), "# A once more\n")?;
+        assert!(watcher.poll(start + debounce / 2).is_empty());
+        let reports = watcher.poll(start + debounce * 2);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(
+            reports[0].outcome,
+            Outcome::Converted(root.join("out/a.html"))
+        );
+        assert!(fs::read_to_string(root.join("out/a.html"))?.contains("A once more"));
+        assert!(watcher.poll(start + debounce * 4).is_empty());
+        fs::remove_dir_all(&root)
+    }
+}